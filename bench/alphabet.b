@@ -0,0 +1,6 @@
+Prints the alphabet on 50000 lines
+Mostly output: the loops around each character are short
+
+>>>>>+++++++++++++++[<<<<<++++++++++++++++>>>>>-]<<<<<++++++++++>>>>++++++++++<<
+<<[>[-]>>>>[-]++++++++++++++[<<<<++++++++++++++>>>>-]<<<<++++[>[-]>>>[-]++++++++
+[<<<++++++++>>>-]<<<+>[-]>>[-]+++++[<<+++++>>-]<<+[<.+>-]>.<<<-]<-]
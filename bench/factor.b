@@ -0,0 +1,18 @@
+Factors each number read from input by trial division
+One number per line; prints the number and its prime factors
+Needs cells of at least 32 bits
+
+,[>[-]<----------[-------------------------------------->[->>>>++++++++++<<<<]>>
+>>[-<<<<+>>>>]<<<<<[->+<],----------]>[->>>>>>>>>>>>>>>>>>>>>>>>>>>>>>+<<<<<<<<<
+<<<<<<<<<<<<<<<<<+<<<<]>>>>[-<<<<+>>>>]>>>>>>>>>>>>>>>>>>>>>>>>>>[>++++++++++<[-
+>-[>+>>]>[+[-<+>]>+>>]<<<<<]>[-]>[-<<<+>>>]<<<+>>>>[->>>+<<<]>>>]<<<<<<<[+++++++
+++++++++++++++++++++++++++++++++++++++++.[-]<<<<<<]<<<<<<<<<<<<<<<<<[-]<[-]+++++
+++[>++++++++<-]>++.[-]<<<<<[-]++>[-]<<[->>+>>+<<<<]>>>>[-<<<<+>>>>]<<-[[-]<<[->>
+>>>>>>>+<<<<<+<<<<]>>>>[-<<<<+>>>>]<<<[->>>>>>>>>+<<<<<<+<<<]>>>[-<<<+>>>]>>>>>[
+->-[>+>>]>[+[-<+>]>+>>]<<<<<]>[-]<<<<<<<[-]+>>>>>>>>[<<<<<<<<<<+>>>>>>>>>>>[-]<[
+-]<<<<<<<<[-]>>>>>>>>]<<<<<<<<[>>>[-]<[-]+++++[>++++++<-]>++.[-]<<<<<[->>>>>>>>>
+>>>>>>>>>>>>>>>>>>>>+<<<<<<<<<<<<<<<<<<<<<<<<<<+<<<]>>>[-<<<+>>>]>>>>>>>>>>>>>>>
+>>>>>>>>>>>[>++++++++++<[->-[>+>>]>[+[-<+>]>+>>]<<<<<]>[-]>[-<<<+>>>]<<<+>>>>[->
+>>+<<<]>>>]<<<<<<<[+++++++++++++++++++++++++++++++++++++++++++++++.[-]<<<<<<]<<<
+<<<<<<<<<<<<<<<<<<<<[-]>>>>>>>>>>>>[-<<<<<<<<<<<<+>>>>>>>>>>>>]<<<<<<<<<[-]]<[-]
+<<[->>+>>+<<<<]>>>>[-<<<<+>>>>]<<-]>>>>[-]++++++++++.[-]<<<<<<<,]
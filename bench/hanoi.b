@@ -0,0 +1,80 @@
+Towers of Hanoi for 18 disks
+Prints each of the 262143 moves as the disk number and the pegs it moves between
+
+The moves come from a binary counter kept on the tape: the lowest zero bit of
+each increment is the disk that moves and every disk cycles through the pegs
+in a fixed direction
+
+>+>>>>>>>>>>>>+>>>>>++++++++[<<<++++++++>>>-]<<<+>>>++++++++[<<++++++++>>-]<<++>
+>++++++++[<++++++++>-]<+++>+++++[>++++++<-]>++<+++++++[>>+++++++<<-]>><<+++++++[
+>>>++++++++<<<-]>>>++<<<+++++[>>>>++++++<<<<-]>>>>++<<<<++++++[>>>>>+++++++<<<<<
+-]>>>>>+++<<<<<+++++++[>>>>>>++++++++<<<<<<-]>>>>>>++++++>++++++++++>+>>>>>+++++
++++[<<<++++++++>>>-]<<<+>>>++++++++[<<++++++++>>-]<<+++>>++++++++[<++++++++>-]<+
++>+++++[>++++++<-]>++<+++++++[>>+++++++<<-]>>+<<+++++++[>>>++++++++<<<-]>>>++<<<
++++++[>>>>++++++<<<<-]>>>>++<<<<++++++[>>>>>+++++++<<<<<-]>>>>>+++<<<<<+++++++[>
+>>>>>++++++++<<<<<<-]>>>>>>++++++>++++++++++>+>>>>>++++++++[<<<++++++++>>>-]<<<+
+>>>++++++++[<<++++++++>>-]<<++>>++++++++[<++++++++>-]<+++>+++++[>++++++<-]>++<++
++++++[>>+++++++<<-]>>++<<+++++++[>>>++++++++<<<-]>>>++<<<+++++[>>>>++++++<<<<-]>
+>>>++<<<<++++++[>>>>>+++++++<<<<<-]>>>>>+++<<<<<+++++++[>>>>>>++++++++<<<<<<-]>>
+>>>>++++++>++++++++++>+>>>>>++++++++[<<<++++++++>>>-]<<<+>>>++++++++[<<++++++++>
+>-]<<+++>>++++++++[<++++++++>-]<++>+++++[>++++++<-]>++<+++++++[>>+++++++<<-]>>++
++<<+++++++[>>>++++++++<<<-]>>>++<<<+++++[>>>>++++++<<<<-]>>>>++<<<<++++++[>>>>>+
+++++++<<<<<-]>>>>>+++<<<<<+++++++[>>>>>>++++++++<<<<<<-]>>>>>>++++++>++++++++++>
++>>>>>++++++++[<<<++++++++>>>-]<<<+>>>++++++++[<<++++++++>>-]<<++>>++++++++[<+++
++++++>-]<+++>+++++[>++++++<-]>++<+++++++[>>+++++++<<-]>>++++<<+++++++[>>>+++++++
++<<<-]>>>++<<<+++++[>>>>++++++<<<<-]>>>>++<<<<++++++[>>>>>+++++++<<<<<-]>>>>>+++
+<<<<<+++++++[>>>>>>++++++++<<<<<<-]>>>>>>++++++>++++++++++>+>>>>>++++++++[<<<+++
++++++>>>-]<<<+>>>++++++++[<<++++++++>>-]<<+++>>++++++++[<++++++++>-]<++>+++++[>+
++++++<-]>++<+++++++[>>+++++++<<-]>>+++++<<+++++++[>>>++++++++<<<-]>>>++<<<+++++[
+>>>>++++++<<<<-]>>>>++<<<<++++++[>>>>>+++++++<<<<<-]>>>>>+++<<<<<+++++++[>>>>>>+
++++++++<<<<<<-]>>>>>>++++++>++++++++++>+>>>>>++++++++[<<<++++++++>>>-]<<<+>>>+++
++++++[<<++++++++>>-]<<++>>++++++++[<++++++++>-]<+++>+++++[>++++++<-]>++<+++++++[
+>>+++++++<<-]>>++++++<<+++++++[>>>++++++++<<<-]>>>++<<<+++++[>>>>++++++<<<<-]>>>
+>++<<<<++++++[>>>>>+++++++<<<<<-]>>>>>+++<<<<<+++++++[>>>>>>++++++++<<<<<<-]>>>>
+>>++++++>++++++++++>+>>>>>++++++++[<<<++++++++>>>-]<<<+>>>++++++++[<<++++++++>>-
+]<<+++>>++++++++[<++++++++>-]<++>+++++[>++++++<-]>++<+++++++[>>++++++++<<-]>><<+
+++++++[>>>++++++++<<<-]>>>++<<<+++++[>>>>++++++<<<<-]>>>>++<<<<++++++[>>>>>+++++
+++<<<<<-]>>>>>+++<<<<<+++++++[>>>>>>++++++++<<<<<<-]>>>>>>++++++>++++++++++>+>>>
+>>++++++++[<<<++++++++>>>-]<<<+>>>++++++++[<<++++++++>>-]<<++>>++++++++[<+++++++
++>-]<+++>+++++[>++++++<-]>++<+++++++[>>++++++++<<-]>>+<<+++++++[>>>++++++++<<<-]
+>>>++<<<+++++[>>>>++++++<<<<-]>>>>++<<<<++++++[>>>>>+++++++<<<<<-]>>>>>+++<<<<<+
+++++++[>>>>>>++++++++<<<<<<-]>>>>>>++++++>++++++++++>+>>>>>++++++++[<<<++++++++>
+>>-]<<<+>>>++++++++[<<++++++++>>-]<<+++>>++++++++[<++++++++>-]<++>+++++++[>+++++
+++<-]><++++++[>>++++++++<<-]>><<+++++++[>>>++++++++<<<-]>>>++<<<+++++[>>>>++++++
+<<<<-]>>>>++<<<<++++++[>>>>>+++++++<<<<<-]>>>>>+++<<<<<+++++++[>>>>>>++++++++<<<
+<<<-]>>>>>>++++++>++++++++++>+>>>>>++++++++[<<<++++++++>>>-]<<<+>>>++++++++[<<++
+++++++>>-]<<++>>++++++++[<++++++++>-]<+++>+++++++[>+++++++<-]><+++++++[>>+++++++
+<<-]>><<+++++++[>>>++++++++<<<-]>>>++<<<+++++[>>>>++++++<<<<-]>>>>++<<<<++++++[>
+>>>>+++++++<<<<<-]>>>>>+++<<<<<+++++++[>>>>>>++++++++<<<<<<-]>>>>>>++++++>++++++
+++++>+>>>>>++++++++[<<<++++++++>>>-]<<<+>>>++++++++[<<++++++++>>-]<<+++>>+++++++
++[<++++++++>-]<++>+++++++[>+++++++<-]><+++++++[>>+++++++<<-]>>+<<+++++++[>>>++++
+++++<<<-]>>>++<<<+++++[>>>>++++++<<<<-]>>>>++<<<<++++++[>>>>>+++++++<<<<<-]>>>>>
++++<<<<<+++++++[>>>>>>++++++++<<<<<<-]>>>>>>++++++>++++++++++>+>>>>>++++++++[<<<
+++++++++>>>-]<<<+>>>++++++++[<<++++++++>>-]<<++>>++++++++[<++++++++>-]<+++>+++++
+++[>+++++++<-]><+++++++[>>+++++++<<-]>>++<<+++++++[>>>++++++++<<<-]>>>++<<<+++++
+[>>>>++++++<<<<-]>>>>++<<<<++++++[>>>>>+++++++<<<<<-]>>>>>+++<<<<<+++++++[>>>>>>
+++++++++<<<<<<-]>>>>>>++++++>++++++++++>+>>>>>++++++++[<<<++++++++>>>-]<<<+>>>++
+++++++[<<++++++++>>-]<<+++>>++++++++[<++++++++>-]<++>+++++++[>+++++++<-]><++++++
++[>>+++++++<<-]>>+++<<+++++++[>>>++++++++<<<-]>>>++<<<+++++[>>>>++++++<<<<-]>>>>
+++<<<<++++++[>>>>>+++++++<<<<<-]>>>>>+++<<<<<+++++++[>>>>>>++++++++<<<<<<-]>>>>>
+>++++++>++++++++++>+>>>>>++++++++[<<<++++++++>>>-]<<<+>>>++++++++[<<++++++++>>-]
+<<++>>++++++++[<++++++++>-]<+++>+++++++[>+++++++<-]><+++++++[>>+++++++<<-]>>++++
+<<+++++++[>>>++++++++<<<-]>>>++<<<+++++[>>>>++++++<<<<-]>>>>++<<<<++++++[>>>>>++
++++++<<<<<-]>>>>>+++<<<<<+++++++[>>>>>>++++++++<<<<<<-]>>>>>>++++++>++++++++++>+
+>>>>>++++++++[<<<++++++++>>>-]<<<+>>>++++++++[<<++++++++>>-]<<+++>>++++++++[<+++
++++++>-]<++>+++++++[>+++++++<-]><+++++++[>>+++++++<<-]>>+++++<<+++++++[>>>++++++
+++<<<-]>>>++<<<+++++[>>>>++++++<<<<-]>>>>++<<<<++++++[>>>>>+++++++<<<<<-]>>>>>++
++<<<<<+++++++[>>>>>>++++++++<<<<<<-]>>>>>>++++++>++++++++++>+>>>>>++++++++[<<<++
+++++++>>>-]<<<+>>>++++++++[<<++++++++>>-]<<++>>++++++++[<++++++++>-]<+++>+++++++
+[>+++++++<-]><+++++++[>>+++++++<<-]>>++++++<<+++++++[>>>++++++++<<<-]>>>++<<<+++
+++[>>>>++++++<<<<-]>>>>++<<<<++++++[>>>>>+++++++<<<<<-]>>>>>+++<<<<<+++++++[>>>>
+>>++++++++<<<<<<-]>>>>>>++++++>++++++++++>+>>>>>++++++++[<<<++++++++>>>-]<<<+>>>
+++++++++[<<++++++++>>-]<<+++>>++++++++[<++++++++>-]<++>+++++++[>+++++++<-]><++++
++++[>>++++++++<<-]>><<+++++++[>>>++++++++<<<-]>>>++<<<+++++[>>>>++++++<<<<-]>>>>
+++<<<<++++++[>>>>>+++++++<<<<<-]>>>>>+++<<<<<+++++++[>>>>>>++++++++<<<<<<-]>>>>>
+>++++++>++++++++++<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<
+<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<
+<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<<
+<<<<<<<<<<<<<<<<<<<<<<<[>>>>>>>>>>>>>[->>>>>>>>>>>>>]+>[>>>>.>.>.>.<<<<<<<.>>>>>
+>>.>.>.<<.<<<<<<.>>>>>>>>>.<<<<<<<<<<[->>>+<<<]>[-<+>]>[-<+>]>[-<+>]<<<<<[<<<<<<
+<<<<<<<]]>]
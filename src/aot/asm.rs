@@ -0,0 +1,232 @@
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::byteorder::{ByteOrder, LittleEndian};
+use super::{Code, Codegen, Target};
+
+use bf::Program;
+use emitter::text::{self, Syntax};
+use emitter::x64::Operand;
+use exec::Config;
+
+
+//Temporary files are per call, so callers can assemble from several threads
+static NEXT_FILE: AtomicUsize = AtomicUsize::new(0);
+
+
+//Local label of an op, after where it starts in the source
+fn op_label(prog: &Program, ip: usize) -> String {
+    if ip == prog.ops.len() {
+        return ".Lend".to_string();
+    }
+    let (line, col) = prog.line_col(ip);
+    format!(".L{}_{}", line, col)
+}
+
+//End of the i-th instruction
+fn instruction_end(code: &Code, i: usize) -> usize {
+    match code.instructions.get(i + 1) {
+        Some(next) => next.0,
+        None => code.bytes.len(),
+    }
+}
+
+//The instructions of the generated code with labels for the ops and for
+//every branch target, as (offset, name, op) until sorted into place
+fn listing(prog: &Program, code: &Code, syntax: Syntax) -> Result<String, &'static str> {
+    let mut labels: Vec<(usize, String, Option<usize>)> = vec![];
+    for ip in 0..prog.ops.len() + 1 {
+        labels.push((code.labels[ip] as usize, op_label(prog, ip), Some(ip)));
+    }
+
+    let mut targets: Vec<Option<String>> = vec![];
+    for (i, &(_, _, oprnd)) in code.instructions.iter().enumerate() {
+        if let Operand::Rel32(_) = oprnd {
+            let end = instruction_end(code, i);
+            if let Some(&(_, sym)) = code.relocs.iter().find(|r| r.0 == end - 4) {
+                targets.push(Some(format!("{}@PLT", sym)));
+                continue;
+            }
+            let target = (end as isize + LittleEndian::read_i32(&code.bytes[end - 4..end]) as isize) as usize;
+            let name = match labels.iter().find(|l| l.0 == target) {
+                Some(l) => l.1.clone(),
+                None => {
+                    //a branch inside an op, named after it
+                    let owner = (0..prog.ops.len()).rev().find(|&ip| code.labels[ip] as usize <= target).unwrap_or(0);
+                    let prefix = format!("{}.", op_label(prog, owner));
+                    let count = labels.iter().filter(|l| l.2.is_none() && l.1.starts_with(&prefix)).count();
+                    let name = format!("{}{}", prefix, count + 1);
+                    labels.push((target, name.clone(), None));
+                    name
+                },
+            };
+            targets.push(Some(name));
+        }else{
+            targets.push(None);
+        }
+    }
+    //stable, so the op labels of ops without code stay in program order
+    labels.sort_by_key(|l| l.0);
+
+    let mut out = String::new();
+    let mut next = 0;
+    for (i, &(at, op, oprnd)) in code.instructions.iter().enumerate() {
+        while next < labels.len() && labels[next].0 <= at {
+            let label = format!("{}:", labels[next].1);
+            match labels[next].2 {
+                Some(ip) if ip < prog.ops.len() => out.push_str(&format!("{:<24}# {}\n", label, prog.ops[ip])),
+                _ => out.push_str(&format!("{}\n", label)),
+            }
+            next += 1;
+        }
+        let line = text::format(op, oprnd, targets[i].as_ref().map(|t| &**t), syntax)?;
+        out.push_str(&format!("    {}\n", line));
+    }
+    Ok(out)
+}
+
+//GNU as source of the function compile_object builds, with a label for every
+//op named after its line and column in the BF source
+pub fn compile_asm(prog: &Program, config: &Config, name: &str, syntax: Syntax) -> Result<String, &'static str> {
+    if name.is_empty() || name.contains(|c: char| !(c.is_alphanumeric() || c == '_')) {
+        return Err("Invalid function name");
+    }
+    let code = Codegen::new(&prog.ops, config, Target::Function)?.compile()?;
+
+    let mut out = String::new();
+    out.push_str(&format!("    {}\n", syntax.directive()));
+    out.push_str("    .text\n");
+    out.push_str(&format!("    .globl {}\n", name));
+    out.push_str(&format!("    .type {}, @function\n", name));
+    out.push_str(&format!("{}:\n", name));
+    out.push_str(&listing(prog, &code, syntax)?);
+    out.push_str(&format!("    .size {}, .-{}\n", name, name));
+    out.push_str("    .section .note.GNU-stack,\"\",@progbits\n");
+    Ok(out)
+}
+
+pub fn write_asm<P: AsRef<Path>>(prog: &Program, config: &Config, name: &str, syntax: Syntax, path: P) -> io::Result<()> {
+    let src = match compile_asm(prog, config, name, syntax) {
+        Ok(src) => src,
+        Err(err) => return Err(io::Error::new(io::ErrorKind::Other, err)),
+    };
+    let mut f = File::create(path)?;
+    f.write_all(src.as_bytes())
+}
+
+//Contents of the .text section of an ELF64 object
+fn text_section(obj: &[u8]) -> Option<&[u8]> {
+    if obj.len() < 64 || &obj[..4] != b"\x7fELF" {
+        return None;
+    }
+    let shoff = LittleEndian::read_u64(&obj[0x28..]) as usize;
+    let shentsize = LittleEndian::read_u16(&obj[0x3a..]) as usize;
+    let shnum = LittleEndian::read_u16(&obj[0x3c..]) as usize;
+    let shstrndx = LittleEndian::read_u16(&obj[0x3e..]) as usize;
+
+    let header = |i: usize| obj.get(shoff + i * shentsize..shoff + (i + 1) * shentsize);
+    let names = header(shstrndx)?;
+    let names_offset = LittleEndian::read_u64(&names[0x18..]) as usize;
+    for i in 0..shnum {
+        let sh = header(i)?;
+        let name = names_offset + LittleEndian::read_u32(sh) as usize;
+        if obj.get(name..name + 6) == Some(b".text\0") {
+            let offset = LittleEndian::read_u64(&sh[0x18..]) as usize;
+            let size = LittleEndian::read_u64(&sh[0x20..]) as usize;
+            return obj.get(offset..offset + size);
+        }
+    }
+    None
+}
+
+//The .text bytes GNU as source assembles to, with the system assembler or
+//the one in $AS
+pub fn assemble(src_text: &str) -> Result<Vec<u8>, String> {
+    let dir = env::temp_dir();
+    let stem = format!("bf_asm_{}_{}", ::std::process::id(), NEXT_FILE.fetch_add(1, Ordering::Relaxed));
+    let src = dir.join(format!("{}.s", stem));
+    let obj = dir.join(format!("{}.o", stem));
+    File::create(&src).and_then(|mut f| f.write_all(src_text.as_bytes()))
+        .map_err(|err| format!("Could not write {}: {}", src.display(), err))?;
+
+    let assembler = env::var("AS").unwrap_or("as".to_string());
+    let status = Command::new(&assembler).arg("-o").arg(&obj).arg(&src).status()
+        .map_err(|err| format!("Could not run {}: {}", assembler, err))?;
+    if !status.success() {
+        return Err(format!("{} failed on {}", assembler, src.display()));
+    }
+    let mut bytes = vec![];
+    File::open(&obj).and_then(|mut f| f.read_to_end(&mut bytes))
+        .map_err(|err| format!("Could not read {}: {}", obj.display(), err))?;
+    let _ = fs::remove_file(&src);
+    let _ = fs::remove_file(&obj);
+
+    match text_section(&bytes) {
+        Some(text) => Ok(text.to_vec()),
+        None => Err(format!("No .text section in the {} output", assembler)),
+    }
+}
+
+//Assembles the compile_asm output and checks it gives the bytes the Emitter
+//wrote
+pub fn check_asm(prog: &Program, config: &Config, syntax: Syntax) -> Result<(), String> {
+    let code = Codegen::new(&prog.ops, config, Target::Function)?.compile()?;
+    let assembled = assemble(&compile_asm(prog, config, "bf_main", syntax)?)?;
+    if assembled == code.bytes {
+        return Ok(());
+    }
+
+    let at = assembled.iter().zip(code.bytes.iter()).take_while(|&(a, b)| a == b).count();
+    match code.instructions.iter().rposition(|i| i.0 <= at) {
+        Some(i) if at < code.bytes.len() => {
+            let (start, op, oprnd) = code.instructions[i];
+            let end = instruction_end(&code, i);
+            Err(format!("Byte {} differs, in {:?} {:?}: emitted {:02x?}, assembled {:02x?}", at, op, oprnd,
+                        &code.bytes[start..end], &assembled[start.min(at)..end.min(assembled.len())]))
+        },
+        _ => Err(format!("Assembled {} bytes, emitted {}", assembled.len(), code.bytes.len())),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::File;
+    use std::io::Read;
+    use std::process::{Command, Stdio};
+    use bf::Program;
+    use emitter::text::Syntax;
+    use exec::{CellWidth, Config};
+
+    fn have_assembler() -> bool {
+        let assembler = env::var("AS").unwrap_or("as".to_string());
+        Command::new(assembler).arg("--version").stdout(Stdio::null()).status().is_ok()
+    }
+
+    #[test]
+    fn assembles_to_the_emitted_code() {
+        if !have_assembler() {
+            return;
+        }
+        let mut mandelbrot = String::new();
+        File::open(concat!(env!("CARGO_MANIFEST_DIR"), "/mandelbrot.bf.txt")).unwrap().read_to_string(&mut mandelbrot).unwrap();
+        let sources = ["++++++++[>++++[>++>+++<<-]>+<<-]>>.>+.", ",[.,]", "+[[->>+<<]>]<<<-", &mandelbrot];
+        let mut narrow = Config::new();
+        narrow.cell_width = CellWidth::U8;
+        for source in sources.iter() {
+            let prog = Program::parse(source).unwrap();
+            for config in &[Config::new(), narrow.clone()] {
+                for &syntax in &[Syntax::Intel, Syntax::Att] {
+                    if let Err(err) = super::check_asm(&prog, config, syntax) {
+                        panic!("{:?} listing of {:.20}: {}", syntax, source, err);
+                    }
+                }
+            }
+        }
+    }
+}
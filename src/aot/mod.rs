@@ -0,0 +1,369 @@
+extern crate byteorder;
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+use self::byteorder::{LittleEndian, WriteBytesExt};
+
+use CodeBuff;
+use bf::{Opcode, Program};
+use emitter::{x64, Emitter};
+use emitter::x64::{Reg64, Operand};
+use exec::{Config, EofPolicy};
+
+mod object;
+mod asm;
+pub use self::object::{compile_object, write_object};
+pub use self::asm::{compile_asm, write_asm, check_asm, assemble};
+
+
+//Same register for the tape pointer as the JIT
+const TAPE: Reg64 = Reg64::Rbx;
+//Survives syscalls, holds a cell's old value across a read
+const SAVED: Reg64 = Reg64::R12;
+//The I/O callbacks of an object file function, null for getchar/putchar
+const READ_CB: Reg64 = Reg64::R13;
+const WRITE_CB: Reg64 = Reg64::R14;
+
+//Worst case number of code bytes per op, a read with its EOF handling being the largest
+const BYTES_PER_OP: usize = 64;
+
+const SYS_READ: u32 = 0;
+const SYS_WRITE: u32 = 1;
+const SYS_EXIT: u32 = 60;
+
+//Where the executable gets mapped, the usual non-PIE base
+const BASE_ADDR: u64 = 0x400000;
+const PAGE_SIZE: u64 = 0x1000;
+
+const EHDR_SIZE: u64 = 64;
+const PHDR_SIZE: u64 = 56;
+
+
+#[derive(Eq, PartialEq, Debug, Copy, Clone)]
+enum Target {
+    //_start of a static executable doing raw syscalls, with the tape at a fixed address
+    Executable{tape_addr: u64},
+    //a System V function taking the tape and the I/O callbacks, see object.rs
+    Function,
+}
+
+//Generated code plus the external functions it calls, as (offset of the
+//rel32 field, symbol name), and what was emitted where for listings
+struct Code {
+    bytes: Vec<u8>,
+    relocs: Vec<(usize, &'static str)>,
+    instructions: Vec<(usize, x64::Opcode, Operand)>,
+    labels: Vec<isize>,
+}
+
+//Generates the machine code of a program for linking or running outside of
+//this process. Cells are 32 bit wide and masked like in the JIT, so all cell
+//widths behave the same as in the other engines.
+struct Codegen<'a> {
+    e: Emitter,
+    cb: CodeBuff,
+    ops: &'a [Opcode],
+    config: &'a Config,
+    target: Target,
+    //code offset of every op and of the end of the program
+    labels: Vec<isize>,
+    //rel32 fields (by end position) waiting for the label of an op
+    fixups: Vec<(isize, usize)>,
+    relocs: Vec<(usize, &'static str)>,
+    instructions: Vec<(usize, x64::Opcode, Operand)>,
+}
+
+impl<'a> Codegen<'a> {
+    fn new(ops: &'a [Opcode], config: &'a Config, target: Target) -> Result<Codegen<'a>, &'static str> {
+        let size = ops.len() * BYTES_PER_OP + 256;
+        let page_size = CodeBuff::get_page_size() as usize;
+        let cb = match CodeBuff::new(((size + page_size - 1) / page_size) as u32) {
+            Ok(cb) => cb,
+            Err(_) => return Err("Code buffer creation failed"),
+        };
+
+        Ok(Codegen {
+            e: Emitter::new(),
+            cb: cb,
+            ops: ops,
+            config: config,
+            target: target,
+            labels: vec![0; ops.len() + 1],
+            fixups: vec![],
+            relocs: vec![],
+            instructions: vec![],
+        })
+    }
+
+    fn emit(&mut self, op: x64::Opcode, oprnd: Operand) -> Result<(), &'static str> {
+        self.instructions.push((self.cb.position() as usize, op, oprnd));
+        if self.e.emit(op, oprnd, &mut self.cb) < 0 {
+            Err("Instruction encoding failed")
+        }else{
+            Ok(())
+        }
+    }
+
+    fn patch(&mut self, at: isize, target: isize) {
+        let pos = self.cb.position();
+        self.cb.set_position(at - 4);
+        self.cb.write_u32((target - at) as i32 as u32);
+        self.cb.set_position(pos);
+    }
+
+    //Forward branch inside an op, returns what to patch once the target is emitted
+    fn branch(&mut self, op: x64::Opcode) -> Result<isize, &'static str> {
+        self.emit(op, Operand::Rel32(0))?;
+        Ok(self.cb.position())
+    }
+
+    //Calls the callback in `cb`, or the libc function when it is null
+    fn call_io(&mut self, cb: Reg64, default: &'static str) -> Result<(), &'static str> {
+        use emitter::x64::Opcode::*;
+        self.emit(Test, Operand::Reg64Reg64{d: cb, s: cb})?;
+        let to_default = self.branch(Jcc(x64::JE))?;
+        self.emit(Call, Operand::Register(x64::Register::Reg64(cb)))?;
+        let to_done = self.branch(Jmp)?;
+        let pos = self.cb.position();
+        self.patch(to_default, pos);
+        self.emit(Call, Operand::Rel32(0))?;
+        let at = self.cb.position() as usize;
+        self.relocs.push((at - 4, default));
+        let pos = self.cb.position();
+        self.patch(to_done, pos);
+        Ok(())
+    }
+
+    fn syscall(&mut self, nr: u32, fd: u32) -> Result<(), &'static str> {
+        use emitter::x64::Opcode::*;
+        self.emit(Mov, Operand::Reg64Imm32{r: Reg64::Rax, i: nr})?;
+        self.emit(Mov, Operand::Reg64Imm32{r: Reg64::Rdi, i: fd})?;
+        self.emit(Mov, Operand::Reg64Reg64{d: Reg64::Rsi, s: TAPE})?;
+        self.emit(Mov, Operand::Reg64Imm32{r: Reg64::Rdx, i: 1})?;
+        self.emit(Syscall, Operand::None)
+    }
+
+    fn compile_op(&mut self, ip: usize) -> Result<(), &'static str> {
+        use emitter::x64::Opcode::*;
+        let mask = self.config.cell_width.mask();
+        match self.ops[ip] {
+            Opcode::Ptr(x) => {
+                self.emit(Add, Operand::Reg64Imm32{r: TAPE, i: (x * 4) as u32})?;
+            },
+            Opcode::Byte(x) => {
+                self.emit(Add, Operand::DwordPtrImm32{d: TAPE, o: 0, i: x as u32})?;
+                if mask != !0 {
+                    self.emit(And, Operand::DwordPtrImm32{d: TAPE, o: 0, i: mask})?;
+                }
+            },
+            Opcode::LoopEnter(x) => {
+                self.emit(Cmp, Operand::DwordPtrImm32{d: TAPE, o: 0, i: 0})?;
+                self.emit(Jcc(x64::JE), Operand::Rel32(0))?;
+                let at = self.cb.position();
+                self.fixups.push((at, x + 1));
+            },
+            Opcode::LoopExit(x) => {
+                self.emit(Cmp, Operand::DwordPtrImm32{d: TAPE, o: 0, i: 0})?;
+                self.emit(Jcc(x64::JNE), Operand::Rel32(0))?;
+                let at = self.cb.position();
+                self.fixups.push((at, x + 1));
+            },
+            Opcode::Out if self.target == Target::Function => {
+                self.emit(Mov, Operand::Reg32Mem32{d: Reg64::Rdi, s: TAPE, o: 0})?;
+                self.call_io(WRITE_CB, "putchar")?;
+            },
+            Opcode::In if self.target == Target::Function => {
+                self.call_io(READ_CB, "getchar")?;
+                self.emit(Movsxd, Operand::Reg64Reg64{d: Reg64::Rax, s: Reg64::Rax})?;
+                self.emit(Cmp, Operand::Reg64Imm32{r: Reg64::Rax, i: 0})?;
+                let to_eof = self.branch(Jcc(x64::Jmp::JL))?;
+                self.emit(Mov, Operand::Mem32Reg32{d: TAPE, o: 0, s: Reg64::Rax})?;
+                let to_done = self.branch(Jmp)?;
+                let pos = self.cb.position();
+                self.patch(to_eof, pos);
+                match self.config.eof {
+                    EofPolicy::Zero      => self.emit(Mov, Operand::DwordPtrImm32{d: TAPE, o: 0, i: 0})?,
+                    EofPolicy::MinusOne  => self.emit(Mov, Operand::DwordPtrImm32{d: TAPE, o: 0, i: mask})?,
+                    EofPolicy::Unchanged => {},
+                }
+                let pos = self.cb.position();
+                self.patch(to_done, pos);
+            },
+            Opcode::Out => {
+                //cells are little endian, so the low byte is the one at the pointer
+                self.syscall(SYS_WRITE, 1)?;
+            },
+            Opcode::In => {
+                //the read only fills the low byte, anything but a full read is EOF
+                self.emit(Mov, Operand::Reg64Mem64{d: SAVED, s: TAPE, o: 0})?;
+                self.emit(Mov, Operand::DwordPtrImm32{d: TAPE, o: 0, i: 0})?;
+                self.syscall(SYS_READ, 0)?;
+                self.emit(Cmp, Operand::Reg64Imm32{r: Reg64::Rax, i: 1})?;
+                self.emit(Jcc(x64::JE), Operand::Rel32(0))?;
+                let at = self.cb.position();
+                match self.config.eof {
+                    EofPolicy::Zero      => {},
+                    EofPolicy::MinusOne  => self.emit(Mov, Operand::DwordPtrImm32{d: TAPE, o: 0, i: mask})?,
+                    EofPolicy::Unchanged => self.emit(Mov, Operand::Mem64Reg64{d: TAPE, o: 0, s: SAVED})?,
+                }
+                let done = self.cb.position();
+                self.patch(at, done);
+            },
+            //there is no stderr formatting without a runtime, executables skip it
+            Opcode::Debug => {},
+        }
+        Ok(())
+    }
+
+    fn prologue(&mut self) -> Result<(), &'static str> {
+        use emitter::x64::Opcode::*;
+        match self.target {
+            Target::Executable{tape_addr} => self.emit(Mov, Operand::Reg64Imm64{r: TAPE, i: tape_addr}),
+            Target::Function => {
+                for &r in &[TAPE, SAVED, READ_CB, WRITE_CB] {
+                    self.emit(Push, Operand::Register(x64::Register::Reg64(r)))?;
+                }
+                //keeps the stack 16 byte aligned for the calls
+                self.emit(Sub, Operand::Reg64Imm32{r: Reg64::Rsp, i: 8})?;
+                self.emit(Mov, Operand::Reg64Reg64{d: TAPE, s: Emitter::ArgReg(0)})?;
+                self.emit(Mov, Operand::Reg64Reg64{d: READ_CB, s: Emitter::ArgReg(1)})?;
+                self.emit(Mov, Operand::Reg64Reg64{d: WRITE_CB, s: Emitter::ArgReg(2)})
+            },
+        }
+    }
+
+    fn epilogue(&mut self) -> Result<(), &'static str> {
+        use emitter::x64::Opcode::*;
+        match self.target {
+            Target::Executable{..} => {
+                self.emit(Mov, Operand::Reg64Imm32{r: Reg64::Rax, i: SYS_EXIT})?;
+                self.emit(Mov, Operand::Reg64Imm32{r: Reg64::Rdi, i: 0})?;
+                self.emit(Syscall, Operand::None)
+            },
+            Target::Function => {
+                //returns where the pointer ended up
+                self.emit(Mov, Operand::Reg64Reg64{d: Reg64::Rax, s: TAPE})?;
+                self.emit(Add, Operand::Reg64Imm32{r: Reg64::Rsp, i: 8})?;
+                for &r in &[WRITE_CB, READ_CB, SAVED, TAPE] {
+                    self.emit(Pop, Operand::Register(x64::Register::Reg64(r)))?;
+                }
+                self.emit(Ret, Operand::None)
+            },
+        }
+    }
+
+    fn compile(mut self) -> Result<Code, &'static str> {
+        self.prologue()?;
+
+        for ip in 0..self.ops.len() {
+            self.labels[ip] = self.cb.position();
+            self.compile_op(ip)?;
+        }
+        let end = self.ops.len();
+        self.labels[end] = self.cb.position();
+        self.epilogue()?;
+
+        for (at, ip) in self.fixups.clone() {
+            let target = self.labels[ip];
+            self.patch(at, target);
+        }
+
+        if self.cb.position() > self.cb.get_size() as isize {
+            return Err("Ran out of code buffer room");
+        }
+        Ok(Code {
+            bytes: (0..self.cb.position() as usize).map(|i| self.cb[i]).collect(),
+            relocs: self.relocs,
+            instructions: self.instructions,
+            labels: self.labels,
+        })
+    }
+}
+
+
+fn align_up(x: u64, align: u64) -> u64 {
+    (x + align - 1) / align * align
+}
+
+fn write_phdr(w: &mut Vec<u8>, flags: u32, offset: u64, vaddr: u64, filesz: u64, memsz: u64) {
+    w.write_u32::<LittleEndian>(1).unwrap(); //PT_LOAD
+    w.write_u32::<LittleEndian>(flags).unwrap();
+    w.write_u64::<LittleEndian>(offset).unwrap();
+    w.write_u64::<LittleEndian>(vaddr).unwrap();
+    w.write_u64::<LittleEndian>(vaddr).unwrap();
+    w.write_u64::<LittleEndian>(filesz).unwrap();
+    w.write_u64::<LittleEndian>(memsz).unwrap();
+    w.write_u64::<LittleEndian>(PAGE_SIZE).unwrap();
+}
+
+//A static x86-64 Linux executable: one read/execute segment holding the
+//headers and the code, and a zero filled one for the tape
+pub fn compile_elf(prog: &Program, config: &Config) -> Result<Vec<u8>, &'static str> {
+    let code_offset = EHDR_SIZE + 2 * PHDR_SIZE;
+    //the code length isn't known before compiling, so size it for the worst case
+    let max_len = code_offset + (prog.ops.len() * BYTES_PER_OP + 256) as u64;
+    let tape_addr = align_up(BASE_ADDR + max_len, PAGE_SIZE) + PAGE_SIZE;
+    //the read handling loads and stores 8 bytes at the last cell
+    let tape_size = config.tape_size as u64 * 4 + 8;
+
+    let code = Codegen::new(&prog.ops, config, Target::Executable{tape_addr: tape_addr})?.compile()?.bytes;
+    let file_len = code_offset + code.len() as u64;
+
+    let mut elf = Vec::<u8>::with_capacity(file_len as usize);
+    elf.extend_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+    elf.write_u16::<LittleEndian>(2).unwrap();    //ET_EXEC
+    elf.write_u16::<LittleEndian>(0x3e).unwrap(); //EM_X86_64
+    elf.write_u32::<LittleEndian>(1).unwrap();
+    elf.write_u64::<LittleEndian>(BASE_ADDR + code_offset).unwrap();
+    elf.write_u64::<LittleEndian>(EHDR_SIZE).unwrap();
+    elf.write_u64::<LittleEndian>(0).unwrap();
+    elf.write_u32::<LittleEndian>(0).unwrap();
+    elf.write_u16::<LittleEndian>(EHDR_SIZE as u16).unwrap();
+    elf.write_u16::<LittleEndian>(PHDR_SIZE as u16).unwrap();
+    elf.write_u16::<LittleEndian>(2).unwrap();
+    elf.write_u16::<LittleEndian>(64).unwrap();
+    elf.write_u16::<LittleEndian>(0).unwrap();
+    elf.write_u16::<LittleEndian>(0).unwrap();
+
+    write_phdr(&mut elf, 5, 0, BASE_ADDR, file_len, file_len);
+    write_phdr(&mut elf, 6, 0, tape_addr, 0, tape_size);
+    elf.extend_from_slice(&code);
+    Ok(elf)
+}
+
+pub fn write_elf<P: AsRef<Path>>(prog: &Program, config: &Config, path: P) -> io::Result<()> {
+    let elf = match compile_elf(prog, config) {
+        Ok(elf) => elf,
+        Err(err) => return Err(io::Error::new(io::ErrorKind::Other, err)),
+    };
+    let mut f = File::create(&path)?;
+    f.write_all(&elf)?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        f.set_permissions(PermissionsExt::from_mode(0o755))?;
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::process::Command;
+    use bf::Program;
+    use exec::Config;
+
+    //Loops patch their jumps at odd offsets, which debug builds check
+    #[test]
+    fn compiles_loops() {
+        let prog = Program::parse("++++++++[>++++[>++>+++<<-]>+<<-]>>.>+.").unwrap();
+        let path = ::std::env::temp_dir().join(format!("bf_jit_aot_{}", ::std::process::id()));
+        super::write_elf(&prog, &Config::new(), &path).unwrap();
+        let out = Command::new(&path).output();
+        let _ = ::std::fs::remove_file(&path);
+        assert_eq!(out.unwrap().stdout, b"Ha");
+    }
+}
@@ -0,0 +1,230 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+use super::byteorder::{LittleEndian, WriteBytesExt};
+use super::{Codegen, Target};
+
+use bf::Program;
+use exec::Config;
+
+
+//Section indices, in the order the section headers are written
+const SHN_TEXT: u32 = 1;
+const SHN_SYMTAB: u32 = 3;
+const SHN_STRTAB: u32 = 4;
+const SHN_SHSTRTAB: u32 = 6;
+const SECTION_COUNT: u32 = 7;
+
+const SHT_PROGBITS: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const SHT_STRTAB: u32 = 3;
+const SHT_RELA: u32 = 4;
+
+const SHF_ALLOC: u64 = 0x2;
+const SHF_EXECINSTR: u64 = 0x4;
+const SHF_INFO_LINK: u64 = 0x40;
+
+const R_X86_64_PLT32: u64 = 4;
+
+const SYM_SIZE: u64 = 24;
+const RELA_SIZE: u64 = 24;
+const SHDR_SIZE: u64 = 64;
+
+
+struct Section {
+    name: u32,
+    kind: u32,
+    flags: u64,
+    offset: u64,
+    size: u64,
+    link: u32,
+    info: u32,
+    align: u64,
+    entsize: u64,
+}
+
+//Appends a name to a string table and returns its offset
+fn add_str(table: &mut Vec<u8>, s: &str) -> u32 {
+    let at = table.len() as u32;
+    table.extend_from_slice(s.as_bytes());
+    table.push(0);
+    at
+}
+
+fn pad_to(buf: &mut Vec<u8>, align: usize) {
+    while buf.len() % align != 0 {
+        buf.push(0);
+    }
+}
+
+fn write_sym(w: &mut Vec<u8>, name: u32, info: u8, shndx: u16, value: u64, size: u64) {
+    w.write_u32::<LittleEndian>(name).unwrap();
+    w.write_u8(info).unwrap();
+    w.write_u8(0).unwrap();
+    w.write_u16::<LittleEndian>(shndx).unwrap();
+    w.write_u64::<LittleEndian>(value).unwrap();
+    w.write_u64::<LittleEndian>(size).unwrap();
+}
+
+//An ELF64 relocatable object defining one global function, callable from C as
+//
+//    uint32_t *NAME(uint32_t *tape, int (*read)(void), int (*write)(int));
+//
+//`read` returns a byte or a negative value at EOF and `write` gets the cell,
+//like getchar and putchar, which are called instead when a callback is null.
+//The tape needs config.tape_size 32 bit cells, the function returns where the
+//pointer ended up.
+pub fn compile_object(prog: &Program, config: &Config, name: &str) -> Result<Vec<u8>, &'static str> {
+    if name.is_empty() || name.contains('\0') {
+        return Err("Invalid function name");
+    }
+    let code = Codegen::new(&prog.ops, config, Target::Function)?.compile()?;
+
+    let mut strtab = vec![0u8];
+    let func_name = add_str(&mut strtab, name);
+
+    //local section symbol, the function, then the libc functions it calls
+    let mut symtab = vec![0u8; SYM_SIZE as usize];
+    write_sym(&mut symtab, 0, 0x03, SHN_TEXT as u16, 0, 0);
+    write_sym(&mut symtab, func_name, 0x12, SHN_TEXT as u16, 0, code.bytes.len() as u64);
+    let first_global = 2;
+    let mut externs: Vec<&'static str> = vec![];
+    for &(_, sym) in &code.relocs {
+        if !externs.contains(&sym) {
+            externs.push(sym);
+            let at = add_str(&mut strtab, sym);
+            write_sym(&mut symtab, at, 0x10, 0, 0, 0);
+        }
+    }
+
+    let mut rela = vec![];
+    for &(offset, sym) in &code.relocs {
+        let index = 3 + externs.iter().position(|&s| s == sym).unwrap() as u64;
+        rela.write_u64::<LittleEndian>(offset as u64).unwrap();
+        rela.write_u64::<LittleEndian>(index << 32 | R_X86_64_PLT32).unwrap();
+        rela.write_i64::<LittleEndian>(-4).unwrap();
+    }
+
+    let mut shstrtab = vec![0u8];
+    let mut sections = vec![];
+    let mut obj = vec![0u8; 64];
+
+    let text_offset = obj.len() as u64;
+    obj.extend_from_slice(&code.bytes);
+    sections.push(Section {name: add_str(&mut shstrtab, ".text"), kind: SHT_PROGBITS, flags: SHF_ALLOC | SHF_EXECINSTR,
+                           offset: text_offset, size: code.bytes.len() as u64, link: 0, info: 0, align: 16, entsize: 0});
+
+    pad_to(&mut obj, 8);
+    sections.push(Section {name: add_str(&mut shstrtab, ".rela.text"), kind: SHT_RELA, flags: SHF_INFO_LINK,
+                           offset: obj.len() as u64, size: rela.len() as u64, link: SHN_SYMTAB, info: SHN_TEXT, align: 8, entsize: RELA_SIZE});
+    obj.extend_from_slice(&rela);
+
+    pad_to(&mut obj, 8);
+    sections.push(Section {name: add_str(&mut shstrtab, ".symtab"), kind: SHT_SYMTAB, flags: 0,
+                           offset: obj.len() as u64, size: symtab.len() as u64, link: SHN_STRTAB, info: first_global, align: 8, entsize: SYM_SIZE});
+    obj.extend_from_slice(&symtab);
+
+    sections.push(Section {name: add_str(&mut shstrtab, ".strtab"), kind: SHT_STRTAB, flags: 0,
+                           offset: obj.len() as u64, size: strtab.len() as u64, link: 0, info: 0, align: 1, entsize: 0});
+    obj.extend_from_slice(&strtab);
+
+    //the code doesn't need an executable stack, say so or the linker assumes it does
+    sections.push(Section {name: add_str(&mut shstrtab, ".note.GNU-stack"), kind: SHT_PROGBITS, flags: 0,
+                           offset: obj.len() as u64, size: 0, link: 0, info: 0, align: 1, entsize: 0});
+
+    //its own name has to be in it before its size is known
+    let name = add_str(&mut shstrtab, ".shstrtab");
+    sections.push(Section {name: name, kind: SHT_STRTAB, flags: 0,
+                           offset: obj.len() as u64, size: shstrtab.len() as u64, link: 0, info: 0, align: 1, entsize: 0});
+    obj.extend_from_slice(&shstrtab);
+
+    pad_to(&mut obj, 8);
+    let shoff = obj.len() as u64;
+    obj.extend_from_slice(&[0u8; SHDR_SIZE as usize]);
+    for s in &sections {
+        obj.write_u32::<LittleEndian>(s.name).unwrap();
+        obj.write_u32::<LittleEndian>(s.kind).unwrap();
+        obj.write_u64::<LittleEndian>(s.flags).unwrap();
+        obj.write_u64::<LittleEndian>(0).unwrap();
+        obj.write_u64::<LittleEndian>(s.offset).unwrap();
+        obj.write_u64::<LittleEndian>(s.size).unwrap();
+        obj.write_u32::<LittleEndian>(s.link).unwrap();
+        obj.write_u32::<LittleEndian>(s.info).unwrap();
+        obj.write_u64::<LittleEndian>(s.align).unwrap();
+        obj.write_u64::<LittleEndian>(s.entsize).unwrap();
+    }
+
+    let mut ehdr = vec![0x7f, b'E', b'L', b'F', 2, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    ehdr.write_u16::<LittleEndian>(1).unwrap();    //ET_REL
+    ehdr.write_u16::<LittleEndian>(0x3e).unwrap(); //EM_X86_64
+    ehdr.write_u32::<LittleEndian>(1).unwrap();
+    ehdr.write_u64::<LittleEndian>(0).unwrap();
+    ehdr.write_u64::<LittleEndian>(0).unwrap();
+    ehdr.write_u64::<LittleEndian>(shoff).unwrap();
+    ehdr.write_u32::<LittleEndian>(0).unwrap();
+    ehdr.write_u16::<LittleEndian>(64).unwrap();
+    ehdr.write_u16::<LittleEndian>(0).unwrap();
+    ehdr.write_u16::<LittleEndian>(0).unwrap();
+    ehdr.write_u16::<LittleEndian>(SHDR_SIZE as u16).unwrap();
+    ehdr.write_u16::<LittleEndian>(SECTION_COUNT as u16).unwrap();
+    ehdr.write_u16::<LittleEndian>(SHN_SHSTRTAB as u16).unwrap();
+    obj[..64].copy_from_slice(&ehdr);
+    Ok(obj)
+}
+
+pub fn write_object<P: AsRef<Path>>(prog: &Program, config: &Config, name: &str, path: P) -> io::Result<()> {
+    let obj = match compile_object(prog, config, name) {
+        Ok(obj) => obj,
+        Err(err) => return Err(io::Error::new(io::ErrorKind::Other, err)),
+    };
+    let mut f = File::create(path)?;
+    f.write_all(&obj)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+    use bf::Program;
+    use exec::{self, Config};
+
+    const DRIVER: &'static str = "\
+#include <stdint.h>
+uint32_t *bf_main(uint32_t *tape, int (*read)(void), int (*write)(int));
+static uint32_t tape[30000];
+int main(void) { bf_main(tape, 0, 0); return 0; }
+";
+
+    //Links the object into a C program with getchar and putchar for I/O
+    #[test]
+    fn links_with_c() {
+        if Command::new("cc").arg("--version").stdout(Stdio::null()).status().is_err() {
+            return;
+        }
+        let prog = Program::parse("++++++++[>++++[>++>+++<<-]>+<<-]>>.>+.,[.,]").unwrap();
+        let input = b"xyz";
+        let stem = ::std::env::temp_dir().join(format!("bf_jit_object_{}", ::std::process::id()));
+        let (obj, driver, exe) = (stem.with_extension("o"), stem.with_extension("c"), stem.with_extension("out"));
+        super::write_object(&prog, &Config::new(), "bf_main", &obj).unwrap();
+        File::create(&driver).unwrap().write_all(DRIVER.as_bytes()).unwrap();
+
+        let linked = Command::new("cc").arg("-o").arg(&exe).arg(&driver).arg(&obj).status().unwrap();
+        let out = if linked.success() {
+            let mut child = Command::new(&exe).stdin(Stdio::piped()).stdout(Stdio::piped()).spawn().unwrap();
+            child.stdin.take().unwrap().write_all(input).unwrap();
+            Some(child.wait_with_output().unwrap())
+        }else{
+            None
+        };
+        for path in &[&obj, &driver, &exe] {
+            let _ = fs::remove_file(path);
+        }
+        assert!(linked.success());
+        let expected = exec::run(&prog, input, &Config::new()).output;
+        assert_eq!(expected, b"Haxyz".to_vec());
+        assert_eq!(out.unwrap().stdout, expected);
+    }
+}
@@ -0,0 +1,416 @@
+use std::env;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use aot;
+use bf::Program;
+use cgen;
+use exec::{self, Config, EngineKind};
+
+
+//Times the standard programs on every engine and native backend. Results go
+//to a tab separated file, one line per program and variant, so the files of
+//two commits can be compared.
+
+pub struct Bench {
+    pub name: String,
+    pub source: String,
+    pub input: Vec<u8>,
+}
+
+impl Bench {
+    pub fn new(name: &str, source: &str, input: &[u8]) -> Bench {
+        Bench {name: name.to_string(), source: source.to_string(), input: input.to_vec()}
+    }
+
+    //The source at `path`, with the contents of `input_path` as input if given
+    pub fn load<P: AsRef<Path>>(name: &str, path: P, input_path: Option<P>) -> io::Result<Bench> {
+        let mut source = String::new();
+        File::open(path)?.read_to_string(&mut source)?;
+        let mut input = vec![];
+        if let Some(p) = input_path {
+            File::open(p)?.read_to_end(&mut input)?;
+        }
+        Ok(Bench {name: name.to_string(), source: source, input: input})
+    }
+}
+
+//The standard set, paths relative to the repository root: a compute bound
+//program, a loop heavy one, one with lots of output and one doing I/O only
+pub fn standard() -> io::Result<Vec<Bench>> {
+    //text without zero bytes, a zero would end the copy loop early
+    let text: Vec<u8> = (0..1 << 22).map(|i: u32| b'a' + (i.wrapping_mul(2_654_435_761) >> 27) as u8 % 26).collect();
+    Ok(vec![
+        Bench::load("mandelbrot", "mandelbrot.bf.txt", None)?,
+        Bench::load("hanoi", "bench/hanoi.b", None)?,
+        Bench::load("factor", "bench/factor.b", Some("bench/factor.in"))?,
+        Bench::load("alphabet", "bench/alphabet.b", None)?,
+        Bench::new("cat", ",[.,]", &text),
+    ])
+}
+
+
+#[derive(Eq, PartialEq, Debug, Copy, Clone)]
+pub enum Variant {
+    Engine(EngineKind),
+    //the C backend built at an optimization level, 0 to 3
+    C(u8),
+    //the static executable from the AOT compiler
+    Elf,
+}
+
+impl Variant {
+    pub fn parse(s: &str) -> Option<Variant> {
+        match s {
+            "naive"     => Some(Variant::Engine(EngineKind::Naive)),
+            "optimized" => Some(Variant::Engine(EngineKind::Optimized)),
+            "jit"       => Some(Variant::Engine(EngineKind::Jit)),
+            "tiered"    => Some(Variant::Engine(EngineKind::Tiered)),
+            "bytecode"  => Some(Variant::Engine(EngineKind::Bytecode)),
+            "threaded"  => Some(Variant::Engine(EngineKind::Threaded)),
+            "elf"       => Some(Variant::Elf),
+            "c-O0" => Some(Variant::C(0)),
+            "c-O1" => Some(Variant::C(1)),
+            "c-O2" => Some(Variant::C(2)),
+            "c-O3" => Some(Variant::C(3)),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Variant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Variant::Engine(EngineKind::Naive)     => write!(f, "naive"),
+            Variant::Engine(EngineKind::Optimized) => write!(f, "optimized"),
+            Variant::Engine(EngineKind::Jit)       => write!(f, "jit"),
+            Variant::Engine(EngineKind::Tiered)    => write!(f, "tiered"),
+            Variant::Engine(EngineKind::Bytecode)  => write!(f, "bytecode"),
+            Variant::Engine(EngineKind::Threaded)  => write!(f, "threaded"),
+            Variant::C(level)                      => write!(f, "c-O{}", level),
+            Variant::Elf                           => write!(f, "elf"),
+        }
+    }
+}
+
+pub fn variants() -> Vec<Variant> {
+    vec![Variant::Engine(EngineKind::Naive), Variant::Engine(EngineKind::Optimized), Variant::Engine(EngineKind::Bytecode),
+         Variant::Engine(EngineKind::Threaded), Variant::Engine(EngineKind::Jit), Variant::Engine(EngineKind::Tiered), Variant::C(0), Variant::C(2), Variant::Elf]
+}
+
+
+pub struct Options {
+    //untimed runs before the timed ones
+    pub warmup: usize,
+    pub runs: usize,
+    pub variants: Vec<Variant>,
+    //the engine is set per variant
+    pub config: Config,
+}
+
+impl Options {
+    pub fn new() -> Options {
+        Options {warmup: 1, runs: 5, variants: variants(), config: Config::new()}
+    }
+}
+
+
+#[derive(Debug, Clone)]
+pub struct Measurement {
+    pub bench: String,
+    pub variant: Variant,
+    pub times: Vec<Duration>,
+    //ops the engine counted, native code doesn't count
+    pub steps: Option<u64>,
+    pub error: Option<String>,
+}
+
+impl Measurement {
+    pub fn median(&self) -> Option<Duration> {
+        let mut times = self.times.clone();
+        times.sort();
+        match times.len() {
+            0 => None,
+            n if n % 2 == 1 => Some(times[n / 2]),
+            n => Some((times[n / 2 - 1] + times[n / 2]) / 2),
+        }
+    }
+}
+
+
+//A compiled program in a temporary file, deleted when dropped
+struct Executable {
+    path: ::std::path::PathBuf,
+}
+
+impl Drop for Executable {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+fn build(prog: &Program, config: &Config, variant: Variant) -> Result<Option<Executable>, String> {
+    let path = env::temp_dir().join(format!("bf_bench_{}_{}", ::std::process::id(), variant));
+    match variant {
+        Variant::Engine(_) => return Ok(None),
+        Variant::C(level) => cgen::build(prog, config, &format!("-O{}", level), &path)?,
+        Variant::Elf => aot::write_elf(prog, config, &path).map_err(|err| err.to_string())?,
+    }
+    Ok(Some(Executable {path: path}))
+}
+
+//Runs an executable, feeding stdin from another thread so neither side can
+//block on a full pipe
+fn run_native(exe: &Executable, input: &[u8]) -> Result<(Duration, Vec<u8>), String> {
+    let start = Instant::now();
+    let mut child = Command::new(&exe.path).stdin(Stdio::piped()).stdout(Stdio::piped()).spawn()
+        .map_err(|err| format!("Could not run {}: {}", exe.path.display(), err))?;
+    let mut stdin = child.stdin.take().unwrap();
+    let input = input.to_vec();
+    //the program may exit without reading everything
+    let feeder = thread::spawn(move || { let _ = stdin.write_all(&input); });
+    let out = child.wait_with_output().map_err(|err| err.to_string())?;
+    let time = start.elapsed();
+    let _ = feeder.join();
+    if !out.status.success() {
+        return Err(format!("Exited with {}", out.status));
+    }
+    Ok((time, out.stdout))
+}
+
+fn run_once(prog: &Program, bench: &Bench, config: &Config, exe: &Option<Executable>)
+            -> Result<(Duration, Vec<u8>, Option<u64>), String> {
+    match *exe {
+        Some(ref exe) => run_native(exe, &bench.input).map(|(t, out)| (t, out, None)),
+        None => {
+            let start = Instant::now();
+            let r = exec::run(prog, &bench.input, config);
+            let time = start.elapsed();
+            match r.error {
+                Some(err) => Err(err.to_string()),
+                None => Ok((time, r.output, Some(r.steps))),
+            }
+        },
+    }
+}
+
+fn measure(prog: &Program, bench: &Bench, variant: Variant, options: &Options, expected: &mut Option<Vec<u8>>)
+           -> Measurement {
+    let mut m = Measurement {bench: bench.name.clone(), variant: variant, times: vec![], steps: None, error: None};
+    let mut config = options.config.clone();
+    if let Variant::Engine(engine) = variant {
+        config.engine = engine;
+    }
+    let exe = match build(prog, &config, variant) {
+        Ok(exe) => exe,
+        Err(err) => {
+            m.error = Some(err);
+            return m;
+        },
+    };
+
+    for i in 0..options.warmup + options.runs {
+        match run_once(prog, bench, &config, &exe) {
+            Ok((time, output, steps)) => {
+                //the first variant to finish is the reference for the others
+                match *expected {
+                    Some(ref e) if *e != output => {
+                        m.error = Some(format!("Output differs ({} bytes, expected {})", output.len(), e.len()));
+                        return m;
+                    },
+                    Some(_) => {},
+                    None => *expected = Some(output),
+                }
+                m.steps = steps;
+                if i >= options.warmup {
+                    m.times.push(time);
+                }
+            },
+            Err(err) => {
+                m.error = Some(err);
+                return m;
+            },
+        }
+    }
+    m
+}
+
+//Every bench on every variant. Each result is also written to `log` as it
+//comes in, a full run takes minutes.
+pub fn run(benches: &[Bench], options: &Options, log: &mut dyn Write) -> Vec<Measurement> {
+    let mut results = vec![];
+    for bench in benches {
+        let prog = match Program::parse_dialect(&bench.source, options.config.dialect) {
+            Ok(prog) => prog,
+            Err(err) => {
+                for &variant in &options.variants {
+                    results.push(Measurement {bench: bench.name.clone(), variant: variant, times: vec![],
+                                              steps: None, error: Some(err.to_string())});
+                }
+                continue;
+            },
+        };
+        let mut expected = None;
+        for &variant in &options.variants {
+            let m = measure(&prog, bench, variant, options, &mut expected);
+            let _ = write_row(log, &m);
+            results.push(m);
+        }
+    }
+    results
+}
+
+
+fn seconds(d: Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 * 1e-9
+}
+
+fn nanos(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000_000 + d.subsec_nanos() as u64
+}
+
+//Median, fastest and slowest time per program and variant
+pub fn report(results: &[Measurement], w: &mut dyn Write) -> io::Result<()> {
+    writeln!(w, "{:<12} {:<10} {:>10} {:>10} {:>10} {:>14}", "program", "variant", "median", "min", "max", "steps")?;
+    for m in results {
+        let variant = m.variant.to_string();
+        if let Some(ref err) = m.error {
+            writeln!(w, "{:<12} {:<10} {}", m.bench, variant, err)?;
+            continue;
+        }
+        let steps = m.steps.map_or("-".to_string(), |s| s.to_string());
+        match m.median() {
+            Some(median) => writeln!(w, "{:<12} {:<10} {:>9.3}s {:>9.3}s {:>9.3}s {:>14}", m.bench, variant, seconds(median),
+                                     seconds(*m.times.iter().min().unwrap()), seconds(*m.times.iter().max().unwrap()), steps)?,
+            None => writeln!(w, "{:<12} {:<10} {:>10} {:>10} {:>10} {:>14}", m.bench, variant, "-", "-", "-", steps)?,
+        }
+    }
+    Ok(())
+}
+
+
+//program, variant, median ns, steps, error, then every timed run in ns
+fn write_row(w: &mut dyn Write, m: &Measurement) -> io::Result<()> {
+    let times: Vec<String> = m.times.iter().map(|&t| nanos(t).to_string()).collect();
+    let error = m.error.as_ref().map_or(String::new(), |e| e.replace(|c: char| c == '\t' || c == '\n', " "));
+    writeln!(w, "{}\t{}\t{}\t{}\t{}\t{}", m.bench, m.variant, m.median().map_or("-".to_string(), |t| nanos(t).to_string()),
+             m.steps.map_or("-".to_string(), |s| s.to_string()), error, times.join(","))
+}
+
+//The results file. Lines starting with # are comments, the first ones say
+//which commit the numbers are for.
+pub fn write_results<P: AsRef<Path>>(path: P, results: &[Measurement]) -> io::Result<()> {
+    let mut f = File::create(path)?;
+    if let Ok(out) = Command::new("git").args(&["rev-parse", "HEAD"]).output() {
+        if out.status.success() {
+            write!(f, "# commit {}", String::from_utf8_lossy(&out.stdout))?;
+        }
+    }
+    writeln!(f, "# program\tvariant\tmedian_ns\tsteps\terror\truns_ns")?;
+    for m in results {
+        write_row(&mut f, m)?;
+    }
+    Ok(())
+}
+
+pub fn read_results<P: AsRef<Path>>(path: P) -> io::Result<Vec<Measurement>> {
+    let bad = |line: usize| io::Error::new(io::ErrorKind::InvalidData, format!("Bad results line {}", line));
+    let mut results = vec![];
+    for (i, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.starts_with('#') || line.is_empty() {
+            continue;
+        }
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() != 6 {
+            return Err(bad(i + 1));
+        }
+        let variant = Variant::parse(fields[1]).ok_or(bad(i + 1))?;
+        let mut times = vec![];
+        for t in fields[5].split(',').filter(|t| !t.is_empty()) {
+            let ns: u64 = t.parse().map_err(|_| bad(i + 1))?;
+            times.push(Duration::new(ns / 1_000_000_000, (ns % 1_000_000_000) as u32));
+        }
+        results.push(Measurement {
+            bench: fields[0].to_string(),
+            variant: variant,
+            times: times,
+            steps: fields[3].parse().ok(),
+            error: if fields[4].is_empty() { None } else { Some(fields[4].to_string()) },
+        });
+    }
+    Ok(results)
+}
+
+//Medians that got slower by more than this count as regressions
+pub const REGRESSION: f64 = 1.10;
+
+//Each result next to the same program and variant in `old`, returns how many
+//got slower
+pub fn compare(old: &[Measurement], new: &[Measurement], w: &mut dyn Write) -> io::Result<usize> {
+    let mut regressions = 0;
+    for m in new {
+        let before = old.iter().find(|o| o.bench == m.bench && o.variant == m.variant).and_then(|o| o.median());
+        match (before, m.median()) {
+            (Some(before), Some(now)) => {
+                let ratio = seconds(now) / seconds(before);
+                let mark = if ratio > REGRESSION { regressions += 1; "  slower" } else if ratio < 1.0 / REGRESSION { "  faster" } else { "" };
+                writeln!(w, "{:<12} {:<10} {:>9.3}s -> {:>9.3}s {:>+7.1}%{}", m.bench, m.variant.to_string(),
+                         seconds(before), seconds(now), (ratio - 1.0) * 100.0, mark)?;
+            },
+            (None, Some(now)) => writeln!(w, "{:<12} {:<10} {:>10} -> {:>9.3}s", m.bench, m.variant.to_string(), "new", seconds(now))?,
+            (_, None) => writeln!(w, "{:<12} {:<10} no result", m.bench, m.variant.to_string())?,
+        }
+    }
+    Ok(regressions)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::time::Duration;
+    use exec::EngineKind;
+    use super::*;
+
+    fn measurement(bench: &str, variant: Variant, millis: &[u64]) -> Measurement {
+        Measurement {bench: bench.to_string(), variant: variant, times: millis.iter().map(|&ms| Duration::from_millis(ms)).collect(),
+                     steps: None, error: None}
+    }
+
+    #[test]
+    fn takes_the_median_of_the_runs() {
+        let variant = Variant::Engine(EngineKind::Jit);
+        assert_eq!(measurement("a", variant, &[]).median(), None);
+        assert_eq!(measurement("a", variant, &[7]).median(), Some(Duration::from_millis(7)));
+        assert_eq!(measurement("a", variant, &[9, 1, 5]).median(), Some(Duration::from_millis(5)));
+        assert_eq!(measurement("a", variant, &[8, 2, 4, 100]).median(), Some(Duration::from_millis(6)));
+    }
+
+    #[test]
+    fn reads_back_written_results() {
+        let mut ok = measurement("hello", Variant::Engine(EngineKind::Tiered), &[3, 1, 2]);
+        ok.steps = Some(1234);
+        let mut failed = measurement("mandelbrot", Variant::C(2), &[]);
+        failed.error = Some("cc\tfailed\nbadly".to_string());
+        let elf = measurement("hanoi", Variant::Elf, &[10]);
+
+        let path = ::std::env::temp_dir().join(format!("bf_jit_bench_{}.tsv", ::std::process::id()));
+        write_results(&path, &[ok.clone(), failed, elf.clone()]).unwrap();
+        let read = read_results(&path);
+        let _ = fs::remove_file(&path);
+        let read = read.unwrap();
+
+        assert_eq!(read.len(), 3);
+        assert_eq!((&*read[0].bench, read[0].variant, &read[0].times, read[0].steps), ("hello", ok.variant, &ok.times, Some(1234)));
+        assert_eq!(read[0].error, None);
+        assert_eq!((&*read[1].bench, read[1].variant, read[1].times.len(), read[1].steps), ("mandelbrot", Variant::C(2), 0, None));
+        assert_eq!(read[1].error, Some("cc failed badly".to_string()));
+        assert_eq!((&*read[2].bench, read[2].variant, &read[2].times), ("hanoi", Variant::Elf, &elf.times));
+    }
+}
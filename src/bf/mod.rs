@@ -0,0 +1,836 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::io;
+use std::collections::HashMap;
+use std::fmt;
+use exec::{Config, Dialect, Engine, EofPolicy, ExecError, Limiter, Limits};
+use jit::{NativeState, Region};
+use snapshot::{self, Snapshot};
+use profile::Profile;
+type CellType = u32;
+
+#[derive(Eq, PartialEq, Debug, Copy, Clone)]
+pub enum Opcode {
+    Ptr(i32),
+    Byte(i32),
+    LoopEnter(usize),
+    LoopExit(usize),
+    Out,
+    In,
+    Debug,
+}
+
+impl fmt::Display for Opcode {
+fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+        Opcode::Ptr(x)       => write!(f, "P({})", x),
+        Opcode::Byte(x)      => write!(f, "B({})", x),
+        Opcode::LoopEnter(x) => write!(f, "[({})", x),
+        Opcode::LoopExit(x)  => write!(f, "]({})", x),
+        Opcode::Out          => write!(f, "."),
+        Opcode::In           => write!(f, ","),
+        Opcode::Debug        => write!(f, "#"),
+    
+    
+    }
+}
+}
+
+
+//A parsed program: the original source plus the run-length folded ops
+//and the byte offset into the source each op starts at
+#[derive(Debug, Clone)]
+pub struct Program {
+    pub source: String,
+    pub ops: Vec<Opcode>,
+    pub positions: Vec<usize>,
+    pub dialect: Dialect,
+    //Source text after a `!`, when the dialect has input separators
+    pub input: Option<Vec<u8>>,
+}
+
+impl Program {
+    pub fn parse(s: &str) -> Result<Program, &'static str> {
+        Program::parse_dialect(s, Dialect::new())
+    }
+
+    pub fn parse_dialect(s: &str, dialect: Dialect) -> Result<Program, &'static str> {
+        let mut stack = Vec::<usize>::new();
+        let mut input = None;
+
+        let mut prog = Vec::<Opcode>::new();
+        let mut positions = Vec::<usize>::new();
+
+        for (pos, cs) in s.char_indices() {
+            match cs {
+                '+' => {
+                    match prog.last() {
+                        Some(&Opcode::Byte(x)) => {
+                            let nx = x+1;
+                            prog.pop();
+                            prog.push(Opcode::Byte(nx));
+                        },
+
+                        _ => {
+                            prog.push(Opcode::Byte(1))
+                        },
+                    }
+                },
+                '-' => {
+                    match prog.last() {
+                        Some(&Opcode::Byte(x)) => {
+                            let nx = x-1;
+                            prog.pop();
+                            prog.push(Opcode::Byte(nx));
+                        },
+
+
+                        _ => {
+                            prog.push(Opcode::Byte(-1))
+                        },
+                    }
+                },
+                '<' => {
+                    match prog.last() {
+                        Some(&Opcode::Ptr(x)) => {
+                            let nx = x-1;
+                            prog.pop();
+                            prog.push(Opcode::Ptr(nx));
+                        },
+
+                        _ => {
+                            prog.push(Opcode::Ptr(-1))
+                        },
+                    }
+                },
+                '>' => {
+                    match prog.last() {
+                        Some(&Opcode::Ptr(x)) => {
+                            let nx = x+1;
+                            prog.pop();
+                            prog.push(Opcode::Ptr(nx));
+                        },
+
+                        _ => {
+                            prog.push(Opcode::Ptr(1))
+                        },
+                    }
+                },
+                '[' => {
+                    stack.push(prog.len());
+                    prog.push(Opcode::LoopEnter(0));
+
+
+                },
+                ']' => {
+                    match stack.pop() {
+                        Some(x) => {
+                            let i = prog.len();
+                            prog.push(Opcode::LoopExit(x));
+                            prog[x] = Opcode::LoopEnter(i);
+                        },
+                        None    => {
+                                return Err("Unbalanced brackets!");
+                            },
+                    }
+
+                },
+                '.' => {
+                    prog.push(Opcode::Out);
+                },
+                ',' => {
+                    prog.push(Opcode::In);
+                },
+                '#' if dialect.debug_dump => {
+                    prog.push(Opcode::Debug);
+                },
+                '!' if dialect.input_separator => {
+                    input = Some(s[pos + 1..].as_bytes().to_vec());
+                    break;
+                },
+                 _  => {},
+            }
+            if prog.len() > positions.len() {
+                positions.push(pos);
+            }
+        }
+
+        if stack.len() > 0 {
+            return Err("Unbalanced brackets!");
+        }
+
+        Ok(Program {source: s.to_string(), ops: prog, positions: positions, dialect: dialect, input: input})
+    }
+
+    //1-based line and column of an op in the source
+    pub fn line_col(&self, op: usize) -> (usize, usize) {
+        let pos = self.positions[op];
+        let before = &self.source[..pos];
+        let line = before.matches('\n').count() + 1;
+        let col = match before.rfind('\n') {
+            Some(nl) => before[nl + 1..].chars().count() + 1,
+            None => before.chars().count() + 1,
+        };
+        (line, col)
+    }
+}
+
+
+pub fn hash_ops(ops: &[Opcode]) -> u64 {
+    let mut h = snapshot::HASH_INIT;
+    for op in ops {
+        let (tag, x) = match *op {
+            Opcode::Ptr(x)       => (0u8, x as i64),
+            Opcode::Byte(x)      => (1u8, x as i64),
+            Opcode::LoopEnter(x) => (2u8, x as i64),
+            Opcode::LoopExit(x)  => (3u8, x as i64),
+            Opcode::Out          => (4u8, 0),
+            Opcode::In           => (5u8, 0),
+            Opcode::Debug        => (6u8, 0),
+        };
+        let mut bytes = [tag, 0, 0, 0, 0, 0, 0, 0, 0];
+        for i in 0..8 {
+            bytes[i + 1] = (x >> (8 * i)) as u8;
+        }
+        h = snapshot::hash_bytes(h, &bytes);
+    }
+    h
+}
+
+
+//How many cells on each side of the pointer the `#` op shows
+const DUMP_RADIUS: usize = 8;
+
+//Prints what the `#` op shows, the pointer and the cells around it
+pub fn dump_tape(w: &mut dyn Write, tape: &[u32], ptr: usize) -> io::Result<()> {
+    let first = if ptr > DUMP_RADIUS { ptr - DUMP_RADIUS } else { 0 };
+    let last = if ptr + DUMP_RADIUS < tape.len() { ptr + DUMP_RADIUS } else { tape.len() - 1 };
+    write!(w, "#ptr={} cells {}..{}:", ptr, first, last)?;
+    for i in first..last + 1 {
+        if i == ptr {
+            write!(w, " [{}]", tape[i])?;
+        }else{
+            write!(w, " {}", tape[i])?;
+        }
+    }
+    writeln!(w, "")
+}
+
+
+//Cell level I/O shared by all engines, counting bytes so a run can be checkpointed
+#[derive(Debug, Copy, Clone)]
+pub struct CellIo {
+    pub eof: EofPolicy,
+    pub cell_mask: u32,
+    pub input_pos: u64,
+    pub output_pos: u64,
+}
+
+impl CellIo {
+    pub fn new(config: &Config) -> CellIo {
+        CellIo {eof: config.eof, cell_mask: config.cell_width.mask(), input_pos: 0, output_pos: 0}
+    }
+
+    //Reads one cell worth of input, applying the EOF policy when the input runs dry.
+    //An input that would block starves the program instead, the engine then stops
+    //in front of the In op with ExecError::NeedsInput and can be resumed later.
+    pub fn read(&mut self, input: &mut dyn Read, cell: u32) -> Result<u32, ExecError> {
+        let mut c = [0u8;1];
+        loop {
+            match input.read(&mut c) {
+                Ok(0) => {
+                    return Ok(match self.eof {
+                        EofPolicy::Zero      => 0,
+                        EofPolicy::MinusOne  => self.cell_mask,
+                        EofPolicy::Unchanged => cell,
+                    });
+                },
+                Ok(_) => {
+                    self.input_pos += 1;
+                    return Ok(c[0] as u32);
+                },
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {},
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Err(ExecError::NeedsInput),
+                Err(err) => return Err(ExecError::Io(err.to_string())),
+            }
+        }
+    }
+
+    pub fn write(&mut self, output: &mut dyn Write, cell: u32) -> Result<(), ExecError> {
+        match output.write_all(&[cell as u8]).and_then(|_| output.flush()) {
+            Ok(_) => {
+                self.output_pos += 1;
+                Ok(())
+            },
+            Err(err) => Err(ExecError::Io(err.to_string())),
+        }
+    }
+}
+
+
+pub struct OptimizedInterpreter {
+
+    prog: Vec<Opcode>,
+    jmp_table: HashMap<usize, usize>,
+    mem:Vec<u32>,
+    mem_ptr: usize,
+    ip: usize,
+    steps: u64,
+    cell_mask: u32,
+    io: CellIo,
+    limits: Limits,
+    profile: Option<Profile>,
+    dialect: Dialect,
+    embedded_input: Option<Vec<u8>>,
+}
+
+impl OptimizedInterpreter {
+    pub fn new() -> OptimizedInterpreter {
+        OptimizedInterpreter::with_config(&Config::new())
+    }
+
+    pub fn with_config(config: &Config) -> OptimizedInterpreter {
+        
+    
+        OptimizedInterpreter {mem: vec![0u32;config.tape_size], mem_ptr: 0, ip:0, prog: Vec::<Opcode>::new(), jmp_table: HashMap::<usize, usize>::new(),
+                              steps: 0, cell_mask: config.cell_width.mask(), io: CellIo::new(config), limits: config.limits.clone(), profile: None,
+                              dialect: config.dialect, embedded_input: None}
+    }
+
+    //Counts every op executed from now on, see profile::Profile::report
+    pub fn enable_profiling(&mut self) {
+        self.profile = Some(Profile::new(self.prog.len()));
+    }
+
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    pub fn print(&self) {
+        for op in &self.prog {
+            print!("{} ", op); 
+        }
+    
+    }
+    pub fn load(&mut self, f: &mut File) {
+    
+    
+        let mut s = String::new();
+        f.read_to_string(&mut s);
+        
+        match Program::parse_dialect(&s, self.dialect) {
+            Ok(prog) => self.load_program(&prog),
+            Err(err) => println!("{}", err),
+        }
+        
+    }
+
+    pub fn load_program(&mut self, prog: &Program) {
+        self.prog = prog.ops.clone();
+        self.embedded_input = prog.input.clone();
+        self.jmp_table.clear();
+        for (i, op) in self.prog.iter().enumerate() {
+            if let Opcode::LoopExit(x) = *op {
+                self.jmp_table.insert(i, x);
+                self.jmp_table.insert(x, i);
+            }
+        }
+        self.ip = 0;
+        self.steps = 0;
+        if self.profile.is_some() {
+            self.enable_profiling();
+        }
+    }
+
+    //Executes the op at ip, shared by run_io and single stepping
+    #[inline(always)]
+    fn exec_op(&mut self, input: &mut dyn Read, output: &mut dyn Write) -> Result<(), ExecError> {
+        let ip = self.ip;
+        //println!("{} {} {}",self.ip, self.mem_ptr, self.prog[self.ip]);
+        match self.prog[self.ip]{
+            //Some(x) => {
+                
+                //match x {
+                    Opcode::Ptr(x)       => {
+                            let ptr = self.mem_ptr.wrapping_add(x as usize);
+                            if ptr >= self.mem.len() {
+                                return Err(ExecError::PointerOutOfRange{ip: self.ip});
+                            }
+                            self.mem_ptr = ptr;
+                        },
+                    Opcode::Byte(x)      => {self.mem[self.mem_ptr] = self.mem[self.mem_ptr].wrapping_add(x as u32) & self.cell_mask;},
+                    
+                    
+                    Opcode::LoopEnter(x) => {
+                            if self.mem[self.mem_ptr] == 0 {
+                                self.ip = x;
+                            }
+                        },
+                    Opcode::LoopExit(x)  => {
+                            if self.mem[self.mem_ptr] != 0 {
+                                self.ip = x;
+                            }
+                        },
+                    Opcode::Out => {
+                            self.io.write(output, self.mem[self.mem_ptr])?;
+                        },
+                    Opcode::In => {
+                            self.mem[self.mem_ptr] = self.io.read(input, self.mem[self.mem_ptr])?;
+                        },
+                    Opcode::Debug => {
+                            let _ = dump_tape(&mut io::stderr(), &self.mem, self.mem_ptr);
+                        },
+                    
+                    
+                //}
+               
+            
+            //},
+
+            //None => {break},
+        }
+        self.ip += 1;
+        self.steps += 1;
+        if let Some(ref mut p) = self.profile {
+            p.counts[ip] += 1;
+        }
+        Ok(())
+    }
+
+    pub fn step(&mut self, input: &mut dyn Read, output: &mut dyn Write) -> Result<(), ExecError> {
+        if self.ip < self.prog.len() {
+            self.exec_op(input, output)
+        }else{
+            Ok(())
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.ip >= self.prog.len()
+    }
+
+    pub fn set_cell(&mut self, cell: usize, value: u32) {
+        self.mem[cell] = value & self.cell_mask;
+    }
+
+    pub fn io(&self) -> CellIo {
+        self.io
+    }
+
+    //Hands the tape to native code for a region starting at ip, see tiered::Tiered
+    pub fn run_region(&mut self, region: &Region, input: &mut dyn Read, output: &mut dyn Write,
+                      limiter: &Limiter) -> Result<(), ExecError> {
+        let mut state = NativeState {mem_ptr: self.mem_ptr, ip: self.ip, steps: self.steps, io: self.io};
+        let res = region.run(&mut self.mem, &mut state, input, output, limiter);
+        self.mem_ptr = state.mem_ptr;
+        self.ip = state.ip;
+        self.steps = state.steps;
+        self.io = state.io;
+        res
+    }
+
+    //Takes back the last op given the state it started from, an op only ever
+    //writes the cell under the pointer
+    pub fn unstep(&mut self, ip: usize, mem_ptr: usize, cell: u32, io: CellIo) {
+        self.ip = ip;
+        self.mem_ptr = mem_ptr;
+        self.mem[mem_ptr] = cell;
+        self.io = io;
+        self.steps -= 1;
+    }
+
+    //Runs on stdin, or on the input embedded after `!`
+    pub fn run(&mut self) {
+        let res = match self.embedded_input.clone() {
+            Some(input) => self.run_io(&mut &input[..], &mut io::stdout()),
+            None => self.run_io(&mut io::stdin(), &mut io::stdout()),
+        };
+        if let Err(err) = res {
+            println!("Error: {}", err);
+        }
+    }
+
+}
+
+impl Engine for OptimizedInterpreter {
+    fn run_io(&mut self, input: &mut dyn Read, output: &mut dyn Write) -> Result<(), ExecError> {
+        let limiter = self.limits.start();
+        let mut next_poll = self.steps;
+
+        while self.ip < self.prog.len() {
+            if self.steps >= next_poll {
+                next_poll = match limiter.poll(self.steps) {
+                    Ok(n) => n,
+                    Err(int) => return Err(int.to_error(self.ip, self.mem_ptr, &self.mem)),
+                };
+            }
+            self.exec_op(input, output)?;
+        
+        }
+        Ok(())
+    }
+
+    fn tape(&self) -> &[u32] {
+        &self.mem
+    }
+
+    fn mem_ptr(&self) -> usize {
+        self.mem_ptr
+    }
+
+    fn ip(&self) -> usize {
+        self.ip
+    }
+
+    fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    fn program_hash(&self) -> u64 {
+        hash_ops(&self.prog)
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            program_hash: self.program_hash(),
+            tape: self.mem.clone(),
+            mem_ptr: self.mem_ptr,
+            ip: self.ip,
+            steps: self.steps,
+            input_pos: self.io.input_pos,
+            output_pos: self.io.output_pos,
+        }
+    }
+
+    fn restore(&mut self, snap: &Snapshot) -> Result<(), ExecError> {
+        snap.check(self.program_hash(), self.prog.len())?;
+        self.mem = snap.tape.clone();
+        self.mem_ptr = snap.mem_ptr;
+        self.ip = snap.ip;
+        self.steps = snap.steps;
+        self.io.input_pos = snap.input_pos;
+        self.io.output_pos = snap.output_pos;
+        Ok(())
+    }
+}
+
+
+
+
+
+
+
+pub struct Interpreter {
+    mem:Vec<u32>,
+    mem_ptr: usize,
+    ip: usize,
+    prog: Vec<char>,
+    //per command, the op it merges into once parsed, which is the ip errors
+    //report so they read the same as the other engines'
+    op_of: Vec<usize>,
+    jmp_table: HashMap<usize, usize>,
+    steps: u64,
+    cell_mask: u32,
+    io: CellIo,
+    limits: Limits,
+    dialect: Dialect,
+    embedded_input: Option<Vec<u8>>,
+}
+
+impl Interpreter {
+
+    pub fn new() -> Interpreter {
+        Interpreter::with_config(&Config::new())
+    }
+
+    pub fn with_config(config: &Config) -> Interpreter {
+        Interpreter{mem: vec![0u32;config.tape_size], mem_ptr: 0, ip:0, prog:Vec::<char>::new(), op_of: vec![], jmp_table: HashMap::<usize,usize>::new(),
+                    steps: 0, cell_mask: config.cell_width.mask(), io: CellIo::new(config), limits: config.limits.clone(),
+                    dialect: config.dialect, embedded_input: None}
+    }
+    
+    pub fn load(&mut self, f: &mut File) {
+    
+    
+        let mut s = String::new();
+        f.read_to_string(&mut s);
+        
+        let dialect = self.dialect;
+        self.load_str(&s, dialect);
+    }
+
+    pub fn load_program(&mut self, prog: &Program) {
+        self.load_str(&prog.source, prog.dialect);
+    }
+
+    fn load_str(&mut self, s: &str, dialect: Dialect) {
+        self.prog = Vec::<char>::new();
+        self.op_of = vec![];
+        self.embedded_input = None;
+        
+        for (pos, c) in s.char_indices() {
+            match c {
+                
+                    '+' | '-' | '<' | '>' | '[' | ']' | '.' | ',' => {
+                        self.prog.push(c);
+                    },
+                    '#' if dialect.debug_dump => {
+                        self.prog.push(c);
+                    },
+                    '!' if dialect.input_separator => {
+                        self.embedded_input = Some(s[pos + 1..].as_bytes().to_vec());
+                        break;
+                    },
+                    _ => {},
+            }
+        
+        }
+        
+        
+        let mut op = 0;
+        for i in 0..self.prog.len() {
+            let merges = i > 0 && match (self.prog[i - 1], self.prog[i]) {
+                ('+', '+') | ('+', '-') | ('-', '+') | ('-', '-') => true,
+                ('<', '<') | ('<', '>') | ('>', '<') | ('>', '>') => true,
+                _ => false,
+            };
+            if i > 0 && !merges {
+                op += 1;
+            }
+            self.op_of.push(op);
+        }
+
+        self.jmp_table.clear();
+        self.build_jmp_table();
+        self.ip = 0;
+        self.steps = 0;
+    }
+    
+    fn build_jmp_table(&mut self) -> Result<(), &'static str> {
+        let mut ci = self.prog.iter().enumerate();
+        
+        let mut stack = Vec::<usize>::new();
+        
+        loop {
+            match ci.next() {
+                Some((i,&c)) => {
+                    match c {
+                        '[' => {
+                                stack.push(i);
+                            },
+                        ']' => {
+                                match stack.pop() {
+                                    Some(x) => {
+                                        self.jmp_table.insert(i, x);
+                                        self.jmp_table.insert(x, i);
+                                    },
+                                    None    => {
+                                            println!("Unbalanced brackets!");
+                                            break
+                                        
+                                        },
+                                }
+                            },
+                         _  => {},
+                    
+                    }
+                },
+                None => { 
+                    if stack.len() > 0 {
+                        println!("Unbalanced brackets!");
+                    }
+                    break
+                }
+            }
+        }
+        
+        
+        Ok(())
+    }
+    
+    
+    //Runs on stdin, or on the input embedded after `!`
+    pub fn run(&mut self) {
+        let res = match self.embedded_input.clone() {
+            Some(input) => self.run_io(&mut &input[..], &mut io::stdout()),
+            None => self.run_io(&mut io::stdin(), &mut io::stdout()),
+        };
+        if let Err(err) = res {
+            println!("Error: {}", err);
+        }
+    }
+
+
+
+}
+
+impl Engine for Interpreter {
+    fn run_io(&mut self, input: &mut dyn Read, output: &mut dyn Write) -> Result<(), ExecError> {
+        let limiter = self.limits.start();
+        let mut next_poll = self.steps;
+
+        while self.ip < self.prog.len() {
+            //a run of commands the parser merges into one op is one step,
+            //like in the other engines
+            let starts_op = self.ip == 0 || self.op_of[self.ip] != self.op_of[self.ip - 1];
+            if starts_op && self.steps >= next_poll {
+                next_poll = match limiter.poll(self.steps) {
+                    Ok(n) => n,
+                    Err(int) => return Err(int.to_error(self.op_of[self.ip], self.mem_ptr, &self.mem)),
+                };
+            }
+            
+            match self.prog[self.ip]{
+                //Some(x) => {
+                    
+                    //match x {
+                        '+' => self.mem[self.mem_ptr] = self.mem[self.mem_ptr].wrapping_add(1) & self.cell_mask,
+                        '-' => self.mem[self.mem_ptr] = self.mem[self.mem_ptr].wrapping_sub(1) & self.cell_mask,
+                        '<' => {
+                                if self.mem_ptr == 0 {
+                                    return Err(ExecError::PointerOutOfRange{ip: self.op_of[self.ip]});
+                                }
+                                self.mem_ptr -= 1;
+                            },
+                        '>' => {
+                                if self.mem_ptr + 1 >= self.mem.len() {
+                                    return Err(ExecError::PointerOutOfRange{ip: self.op_of[self.ip]});
+                                }
+                                self.mem_ptr += 1;
+                            },
+                        '[' => {
+                                if self.mem[self.mem_ptr] == 0 {
+                                    self.ip = *self.jmp_table.get(&self.ip).unwrap();
+                                }
+                            },
+                        ']' => {
+                                if self.mem[self.mem_ptr] != 0 {
+                                    self.ip = *self.jmp_table.get(&self.ip).unwrap();
+                                }
+                            },
+                        '.' => {
+                                self.io.write(output, self.mem[self.mem_ptr])?;
+                            },
+                        ',' => {
+                                self.mem[self.mem_ptr] = self.io.read(input, self.mem[self.mem_ptr])?;
+                            },
+                        '#' => {
+                                let _ = dump_tape(&mut io::stderr(), &self.mem, self.mem_ptr);
+                            },
+                        _ => {},
+                        
+                    //}
+                   
+                
+                //},
+
+                //None => {break},
+            }
+            self.ip += 1;
+            if starts_op {
+                self.steps += 1;
+            }
+        
+        }
+        Ok(())
+    }
+    
+    fn tape(&self) -> &[u32] {
+        &self.mem
+    }
+
+    fn mem_ptr(&self) -> usize {
+        self.mem_ptr
+    }
+
+    fn ip(&self) -> usize {
+        self.ip
+    }
+
+    fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    fn program_hash(&self) -> u64 {
+        let s: String = self.prog.iter().cloned().collect();
+        snapshot::hash_bytes(snapshot::HASH_INIT, s.as_bytes())
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            program_hash: self.program_hash(),
+            tape: self.mem.clone(),
+            mem_ptr: self.mem_ptr,
+            ip: self.ip,
+            steps: self.steps,
+            input_pos: self.io.input_pos,
+            output_pos: self.io.output_pos,
+        }
+    }
+
+    fn restore(&mut self, snap: &Snapshot) -> Result<(), ExecError> {
+        snap.check(self.program_hash(), self.prog.len())?;
+        self.mem = snap.tape.clone();
+        self.mem_ptr = snap.mem_ptr;
+        self.ip = snap.ip;
+        self.steps = snap.steps;
+        self.io.input_pos = snap.input_pos;
+        self.io.output_pos = snap.output_pos;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exec;
+
+    const ENGINES: [exec::EngineKind; 6] = [exec::EngineKind::Naive, exec::EngineKind::Optimized, exec::EngineKind::Bytecode,
+                                            exec::EngineKind::Threaded, exec::EngineKind::Jit, exec::EngineKind::Tiered];
+
+    fn dialect(debug_dump: bool, input_separator: bool) -> Dialect {
+        Dialect {debug_dump: debug_dump, input_separator: input_separator}
+    }
+
+    #[test]
+    fn splits_input_off_at_a_bang() {
+        let prog = Program::parse_dialect(",.,.!hi[", dialect(false, true)).unwrap();
+        assert_eq!(prog.ops, vec![Opcode::In, Opcode::Out, Opcode::In, Opcode::Out]);
+        assert_eq!(prog.input, Some(b"hi[".to_vec()));
+
+        for &kind in &ENGINES {
+            let mut config = Config::new();
+            config.engine = kind;
+            config.dialect = prog.dialect;
+            assert_eq!(exec::run(&prog, b"", &config).output, b"hi".to_vec(), "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn ignores_a_bang_by_default() {
+        let prog = Program::parse(",.!+").unwrap();
+        assert_eq!(prog.ops, vec![Opcode::In, Opcode::Out, Opcode::Byte(1)]);
+        assert_eq!(prog.input, None);
+        assert!(Program::parse(",.![").is_err());
+    }
+
+    #[test]
+    fn dumps_at_a_hash_only_in_the_dialect() {
+        let plain = Program::parse("+#>+#").unwrap();
+        assert_eq!(plain.ops, vec![Opcode::Byte(1), Opcode::Ptr(1), Opcode::Byte(1)]);
+
+        let prog = Program::parse_dialect("+#>+#", dialect(true, false)).unwrap();
+        assert_eq!(prog.ops, vec![Opcode::Byte(1), Opcode::Debug, Opcode::Ptr(1), Opcode::Byte(1), Opcode::Debug]);
+        assert_eq!(prog.line_col(4), (1, 5));
+
+        //the dump goes to stderr and leaves the tape alone
+        for &kind in &ENGINES {
+            let mut config = Config::new();
+            config.engine = kind;
+            config.dialect = prog.dialect;
+            let r = exec::run(&prog, b"", &config);
+            assert_eq!(r.error, None, "{:?}", kind);
+            assert_eq!((r.mem_ptr, &r.tape[..3]), (1, &[1, 1, 0][..]), "{:?}", kind);
+        }
+    }
+}
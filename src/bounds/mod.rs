@@ -0,0 +1,134 @@
+use bf::Opcode;
+
+
+//Pointer range analysis. Between two checks the pointer only moves by
+//amounts known up front, so one check that every cell the code can reach is
+//on the tape covers all of it. A loop whose body leaves the pointer where it
+//found it belongs to the code around it, every iteration reaching the same
+//cells. Any other loop needs a check at the head of its body and after it.
+pub struct Bounds {
+    start: usize,
+    end: usize,
+    //per op, the op whose check covers it and the pointer's offset from there
+    check: Vec<usize>,
+    offset: Vec<isize>,
+    //per op starting a check, the lowest and highest offset the code it
+    //covers can move the pointer to
+    reach: Vec<Option<(isize, isize)>>,
+}
+
+impl Bounds {
+    //Analyzes ops start..end, which must not branch out of the range
+    pub fn analyze(ops: &[Opcode], start: usize, end: usize) -> Bounds {
+        let balanced = balanced_loops(ops, start, end);
+        let mut bounds = Bounds {
+            start: start,
+            end: end,
+            check: vec![start; end - start],
+            offset: vec![0; end - start],
+            reach: vec![None; end - start],
+        };
+
+        let mut check = start;
+        let (mut offset, mut lo, mut hi) = (0isize, 0isize, 0isize);
+        for ip in start..end {
+            let new_check = ip > start && match ops[ip - 1] {
+                Opcode::LoopEnter(_) => !balanced[ip - 1 - start],
+                Opcode::LoopExit(enter) => !balanced[enter - start],
+                _ => false,
+            };
+            if new_check {
+                bounds.reach[check - start] = Some((lo, hi));
+                check = ip;
+                offset = 0;
+                lo = 0;
+                hi = 0;
+            }
+            bounds.check[ip - start] = check;
+            bounds.offset[ip - start] = offset;
+            if let Opcode::Ptr(x) = ops[ip] {
+                offset += x as isize;
+                lo = if offset < lo { offset } else { lo };
+                hi = if offset > hi { offset } else { hi };
+            }
+        }
+        if end > start {
+            bounds.reach[check - start] = Some((lo, hi));
+        }
+        bounds
+    }
+
+    //The offsets to check before ip, if ip starts a check
+    pub fn reach(&self, ip: usize) -> Option<(isize, isize)> {
+        if ip < self.end { self.reach[ip - self.start] } else { None }
+    }
+
+    //How many checks the ops need, the one at the start included
+    pub fn checks(&self) -> usize {
+        self.reach.iter().filter(|r| r.is_some()).count()
+    }
+
+    //Whether code from ip on stays on a tape of len cells up to the next
+    //check, given the pointer there
+    pub fn fits(&self, ip: usize, mem_ptr: usize, len: usize) -> bool {
+        if ip >= self.end {
+            return true;
+        }
+        let i = ip - self.start;
+        let (lo, hi) = self.reach[self.check[i] - self.start].unwrap();
+        let base = mem_ptr as isize - self.offset[i];
+        base + lo >= 0 && base + hi < len as isize
+    }
+}
+
+//Per op, true for the LoopEnter of a loop that moves the pointer by 0 in
+//total and only has such loops inside
+fn balanced_loops(ops: &[Opcode], start: usize, end: usize) -> Vec<bool> {
+    let mut balanced = vec![false; end - start];
+    //per open loop, how far its body moved the pointer so far and whether
+    //that is still known
+    let mut open: Vec<(isize, bool)> = vec![];
+    for ip in start..end {
+        match ops[ip] {
+            Opcode::Ptr(x) => if let Some(top) = open.last_mut() {
+                top.0 += x as isize;
+            },
+            Opcode::LoopEnter(_) => open.push((0, true)),
+            Opcode::LoopExit(enter) => {
+                let (shift, known) = open.pop().unwrap();
+                balanced[enter - start] = known && shift == 0;
+                if let Some(top) = open.last_mut() {
+                    top.1 = top.1 && balanced[enter - start];
+                }
+            },
+            _ => {},
+        }
+    }
+    balanced
+}
+
+
+#[cfg(test)]
+mod tests {
+    use bf::Program;
+    use exec::{self, Config, EngineKind, ExecError};
+    use super::Bounds;
+
+    //Both loops at the start leave the pointer where they found it, so only
+    //the scan needs checks of its own, the 7 moves share 2
+    #[test]
+    fn checks_once_per_unbalanced_loop() {
+        let prog = Program::parse("++++[>++[>+<-]<-]+>+>+[<]").unwrap();
+        let b = Bounds::analyze(&prog.ops, 0, prog.ops.len());
+        assert_eq!(b.checks(), 2);
+        assert_eq!(b.reach(0), Some((0, 2)));
+        assert_eq!(b.reach(19), Some((-1, 0)));
+        assert!(b.fits(6, 2, 3) && !b.fits(6, 2, 2));
+
+        let mut config = Config::new();
+        config.engine = EngineKind::Jit;
+        let r = exec::run(&prog, b"", &config);
+        assert_eq!(r.error, Some(ExecError::PointerOutOfRange{ip: 19}));
+        assert_eq!((r.mem_ptr, &r.tape[..3]), (0, &[1, 1, 9][..]));
+    }
+}
@@ -0,0 +1,529 @@
+use std::io::{self, Read, Write};
+
+use bf::{self, dump_tape, CellIo, Opcode, Program};
+use exec::{Config, Engine, ExecError, Limits};
+use snapshot::Snapshot;
+
+
+//What the interpreter runs: operands are decoded up front and jumps hold
+//the index of the instruction they land on. Instructions with a shift first
+//move the pointer by it, folding in the Ptr op before them when it isn't 0.
+#[derive(Debug, Copy, Clone)]
+enum Insn {
+    //cell += x, with x already wrapped to a u32
+    Add(u32),
+    Move(i32),
+    //Byte then Ptr, and Ptr then Byte
+    AddMove(u32, i32),
+    MoveAdd(i32, u32),
+    //loop head and back-edge with their shift, both jump past the other end
+    JumpIfZero(i32, u32),
+    JumpIfNonZero(i32, u32),
+    //[-] and [+] with their shift, by what one iteration adds
+    Clear(i32, u32),
+    //[>], [<<] and the like
+    Scan(i32),
+    //a loop adding multiples of its head cell to other cells with its shift,
+    //index into Bytecode::linear. The plain loop follows it in case the
+    //pointer would leave the tape.
+    Linear(i32, u32),
+    Out,
+    In,
+    Debug,
+    End,
+}
+
+//Body of a loop made of Byte and Ptr only, that ends where it started and
+//adds 1 or -1 to the head cell
+#[derive(Debug, Clone)]
+pub struct Linear {
+    //offset from the head and what one iteration adds there
+    pub terms: Vec<(isize, u32)>,
+    //what one iteration adds to the head cell
+    pub delta: u32,
+    //how far the body moves the pointer either way
+    pub min: isize,
+    pub max: isize,
+    //ops in the body
+    pub body: u64,
+    //instruction after the plain loop
+    pub exit: u32,
+}
+
+//Iterations until a loop adding delta to its head cell brings c down to zero
+pub fn iterations(c: u32, delta: u32, cell_mask: u32) -> u64 {
+    if delta == 1 {
+        cell_mask as u64 + 1 - c as u64
+    }else{
+        c as u64
+    }
+}
+
+pub fn linear(body: &[Opcode], cell_mask: u32) -> Option<Linear> {
+    let mut offset = 0isize;
+    let mut min = 0;
+    let mut max = 0;
+    let mut terms: Vec<(isize, u32)> = vec![];
+    for op in body {
+        match *op {
+            Opcode::Ptr(x) => {
+                offset += x as isize;
+                min = if offset < min { offset } else { min };
+                max = if offset > max { offset } else { max };
+            },
+            Opcode::Byte(x) => match terms.iter().position(|t| t.0 == offset) {
+                Some(i) => terms[i].1 = terms[i].1.wrapping_add(x as u32),
+                None => terms.push((offset, x as u32)),
+            },
+            _ => return None,
+        }
+    }
+    if offset != 0 {
+        return None;
+    }
+    let head = terms.iter().position(|t| t.0 == 0).map_or(0, |i| terms.remove(i).1) & cell_mask;
+    if head != 1 && head != cell_mask {
+        return None;
+    }
+    terms.retain(|t| t.1 & cell_mask != 0);
+    Some(Linear {terms: terms, delta: if head == 1 { 1 } else { !0 }, min: min, max: max, body: body.len() as u64, exit: 0})
+}
+
+
+//Interpreter for hosts where the JIT can't run. Step counts, errors and the
+//ops it stops at are the same as OptimizedInterpreter's.
+pub struct Bytecode {
+    code: Vec<Insn>,
+    linear: Vec<Linear>,
+    //per instruction, the op it starts at
+    ips: Vec<usize>,
+    //per op, the instruction starting there, if any
+    entries: Vec<Option<u32>>,
+    program_hash: u64,
+    mem: Vec<u32>,
+    mem_ptr: usize,
+    ip: usize,
+    steps: u64,
+    cell_mask: u32,
+    io: CellIo,
+    limits: Limits,
+}
+
+impl Bytecode {
+    pub fn new(prog: &Program, config: &Config) -> Bytecode {
+        let mut b = Bytecode {
+            code: vec![],
+            linear: vec![],
+            ips: vec![],
+            entries: vec![None; prog.ops.len() + 1],
+            program_hash: bf::hash_ops(&prog.ops),
+            mem: vec![0u32; config.tape_size],
+            mem_ptr: 0,
+            ip: 0,
+            steps: 0,
+            cell_mask: config.cell_width.mask(),
+            io: CellIo::new(config),
+            limits: config.limits.clone(),
+        };
+        b.translate(&prog.ops);
+        b
+    }
+
+    //Returns where insn ended up, which is in place of the Move before it
+    //when that gets folded in
+    fn push(&mut self, insn: Insn, ip: usize) -> usize {
+        let shift = match self.code.last() {
+            Some(&Insn::Move(x)) if x != 0 => x,
+            _ => 0,
+        };
+        let fused = match insn {
+            Insn::JumpIfZero(0, t) if shift != 0 => Some(Insn::JumpIfZero(shift, t)),
+            Insn::JumpIfNonZero(0, t) if shift != 0 => Some(Insn::JumpIfNonZero(shift, t)),
+            Insn::Clear(0, d) if shift != 0 => Some(Insn::Clear(shift, d)),
+            Insn::Linear(0, i) if shift != 0 => Some(Insn::Linear(shift, i)),
+            _ => None,
+        };
+        if let Some(insn) = fused {
+            let at = self.code.len() - 1;
+            self.code[at] = insn;
+            return at;
+        }
+        if self.entries[ip].is_none() {
+            self.entries[ip] = Some(self.code.len() as u32);
+        }
+        self.code.push(insn);
+        self.ips.push(ip);
+        self.code.len() - 1
+    }
+
+    fn translate(&mut self, ops: &[Opcode]) {
+        //per open loop, its JumpIfZero and the Linear in front of it
+        let mut open: Vec<(usize, Option<usize>)> = vec![];
+        let mut ip = 0;
+        while ip < ops.len() {
+            match (ops[ip], ops.get(ip + 1).cloned()) {
+                (Opcode::Byte(x), Some(Opcode::Ptr(y))) => {
+                    self.push(Insn::AddMove(x as u32, y), ip);
+                    ip += 2;
+                    continue;
+                },
+                (Opcode::Ptr(x), Some(Opcode::Byte(y))) => {
+                    self.push(Insn::MoveAdd(x, y as u32), ip);
+                    ip += 2;
+                    continue;
+                },
+                (Opcode::Byte(x), _) => {
+                    self.push(Insn::Add(x as u32), ip);
+                },
+                (Opcode::Ptr(x), _) => {
+                    self.push(Insn::Move(x), ip);
+                },
+                (Opcode::LoopEnter(exit), _) => {
+                    let simple = match &ops[ip + 1..exit] {
+                        &[Opcode::Byte(1)] => Some(Insn::Clear(0, 1)),
+                        &[Opcode::Byte(-1)] => Some(Insn::Clear(0, !0)),
+                        &[Opcode::Ptr(x)] if x != 0 => Some(Insn::Scan(x)),
+                        _ => None,
+                    };
+                    if let Some(insn) = simple {
+                        self.push(insn, ip);
+                        ip = exit + 1;
+                        continue;
+                    }
+                    let lin = linear(&ops[ip + 1..exit], self.cell_mask).map(|l| {
+                        self.linear.push(l);
+                        self.linear.len() - 1
+                    });
+                    if let Some(i) = lin {
+                        self.push(Insn::Linear(0, i as u32), ip);
+                    }
+                    let head = self.push(Insn::JumpIfZero(0, 0), ip);
+                    open.push((head, lin));
+                },
+                (Opcode::LoopExit(_), _) => {
+                    let (head, lin) = open.pop().expect("Unbalanced loops in a parsed program");
+                    self.push(Insn::JumpIfNonZero(0, head as u32 + 1), ip);
+                    let after = self.code.len() as u32;
+                    if let Insn::JumpIfZero(shift, _) = self.code[head] {
+                        self.code[head] = Insn::JumpIfZero(shift, after);
+                    }
+                    if let Some(i) = lin {
+                        self.linear[i].exit = after;
+                    }
+                },
+                (Opcode::Out, _) => {
+                    self.push(Insn::Out, ip);
+                },
+                (Opcode::In, _) => {
+                    self.push(Insn::In, ip);
+                },
+                (Opcode::Debug, _) => {
+                    self.push(Insn::Debug, ip);
+                },
+            }
+            ip += 1;
+        }
+        self.push(Insn::End, ops.len());
+    }
+}
+
+//The pointer is checked on every move and the program always runs into End,
+//which makes the bounds checks on the tape and the code redundant
+#[inline(always)]
+fn cell(tape: &mut [u32], ptr: usize) -> &mut u32 {
+    debug_assert!(ptr < tape.len());
+    unsafe { tape.get_unchecked_mut(ptr) }
+}
+
+//The pointer after moving it by x, None when that leaves the tape
+#[inline(always)]
+fn moved(ptr: usize, x: i32, len: usize) -> Option<usize> {
+    let p = ptr.wrapping_add(x as usize);
+    if p < len { Some(p) } else { None }
+}
+
+impl Engine for Bytecode {
+    fn run_io(&mut self, input: &mut dyn Read, output: &mut dyn Write) -> Result<(), ExecError> {
+        let mut pc = match self.entries.get(self.ip) {
+            Some(&Some(pc)) => pc as usize,
+            _ => return Err(ExecError::Snapshot("The bytecode interpreter cannot resume at this op")),
+        };
+        let limiter = self.limits.start();
+        let mut next_poll = match limiter.poll(self.steps) {
+            Ok(n) => n,
+            Err(int) => return Err(int.to_error(self.ip, self.mem_ptr, &self.mem)),
+        };
+
+        let code = &self.code[..];
+        let linear = &self.linear[..];
+        let ips = &self.ips[..];
+        let tape = &mut self.mem[..];
+        let len = tape.len();
+        let mask = self.cell_mask;
+        let mut ptr = self.mem_ptr;
+        let mut steps = self.steps;
+        let mut io = self.io;
+
+        //Ends with the op execution stopped at
+        let (ip, result) = loop {
+            let insn = unsafe { *code.get_unchecked(pc) };
+            match insn {
+                Insn::Add(x) => {
+                    let c = cell(tape, ptr);
+                    *c = c.wrapping_add(x) & mask;
+                    steps += 1;
+                },
+                Insn::Move(x) => {
+                    match moved(ptr, x, len) {
+                        Some(p) => ptr = p,
+                        None => break (ips[pc], Err(ExecError::PointerOutOfRange{ip: ips[pc]})),
+                    }
+                    steps += 1;
+                },
+                Insn::AddMove(x, y) => {
+                    let c = cell(tape, ptr);
+                    *c = c.wrapping_add(x) & mask;
+                    steps += 1;
+                    match moved(ptr, y, len) {
+                        Some(p) => ptr = p,
+                        None => break (ips[pc] + 1, Err(ExecError::PointerOutOfRange{ip: ips[pc] + 1})),
+                    }
+                    steps += 1;
+                },
+                Insn::MoveAdd(x, y) => {
+                    match moved(ptr, x, len) {
+                        Some(p) => ptr = p,
+                        None => break (ips[pc], Err(ExecError::PointerOutOfRange{ip: ips[pc]})),
+                    }
+                    let c = cell(tape, ptr);
+                    *c = c.wrapping_add(y) & mask;
+                    steps += 2;
+                },
+                Insn::JumpIfZero(x, target) => {
+                    if x != 0 {
+                        match moved(ptr, x, len) {
+                            Some(p) => ptr = p,
+                            None => break (ips[pc], Err(ExecError::PointerOutOfRange{ip: ips[pc]})),
+                        }
+                        steps += 1;
+                    }
+                    steps += 1;
+                    if *cell(tape, ptr) == 0 {
+                        pc = target as usize;
+                        continue;
+                    }
+                },
+                Insn::JumpIfNonZero(x, target) => {
+                    if x != 0 {
+                        match moved(ptr, x, len) {
+                            Some(p) => ptr = p,
+                            None => break (ips[pc], Err(ExecError::PointerOutOfRange{ip: ips[pc]})),
+                        }
+                        steps += 1;
+                    }
+                    steps += 1;
+                    if *cell(tape, ptr) != 0 {
+                        pc = target as usize;
+                        //budget and cancellation are checked on back-edges, like the JIT does
+                        if steps >= next_poll {
+                            next_poll = match limiter.poll(steps) {
+                                Ok(n) => n,
+                                Err(int) => break (ips[pc], Err(int.to_error(ips[pc], ptr, tape))),
+                            };
+                        }
+                        continue;
+                    }
+                },
+                Insn::Clear(x, delta) => {
+                    let p = if x == 0 { ptr } else {
+                        match moved(ptr, x, len) {
+                            Some(p) => p,
+                            None => break (ips[pc], Err(ExecError::PointerOutOfRange{ip: ips[pc]})),
+                        }
+                    };
+                    let c = *cell(tape, p);
+                    let n = if c != 0 { iterations(c, delta, mask) } else { 0 };
+                    let cost = (x != 0) as u64 + 1 + 2 * n;
+                    //a budget running out halfway stops in front of the loop
+                    if steps + cost > next_poll {
+                        next_poll = match limiter.poll(steps + cost - 1) {
+                            Ok(n) => n,
+                            Err(int) => break (ips[pc], Err(int.to_error(ips[pc], ptr, tape))),
+                        };
+                    }
+                    ptr = p;
+                    *cell(tape, ptr) = 0;
+                    steps += cost;
+                },
+                Insn::Scan(x) => {
+                    steps += 1;
+                    let mut stopped = None;
+                    while *cell(tape, ptr) != 0 {
+                        if steps >= next_poll {
+                            match limiter.poll(steps) {
+                                Ok(n) => next_poll = n,
+                                Err(int) => {
+                                    //stops in front of the loop, which counts its head again when resumed
+                                    steps -= 1;
+                                    stopped = Some((ips[pc], int.to_error(ips[pc], ptr, tape)));
+                                    break;
+                                },
+                            }
+                        }
+                        match moved(ptr, x, len) {
+                            Some(p) => ptr = p,
+                            None => {
+                                stopped = Some((ips[pc] + 1, ExecError::PointerOutOfRange{ip: ips[pc] + 1}));
+                                break;
+                            },
+                        }
+                        steps += 2;
+                    }
+                    if let Some((ip, err)) = stopped {
+                        break (ip, Err(err));
+                    }
+                },
+                Insn::Linear(x, i) => {
+                    let p = if x == 0 { ptr } else {
+                        match moved(ptr, x, len) {
+                            Some(p) => p,
+                            None => break (ips[pc], Err(ExecError::PointerOutOfRange{ip: ips[pc]})),
+                        }
+                    };
+                    let l = &linear[i as usize];
+                    let c = *cell(tape, p);
+                    ptr = p;
+                    steps += (x != 0) as u64;
+                    if c == 0 {
+                        steps += 1;
+                        pc = l.exit as usize;
+                        continue;
+                    }
+                    let q = p as isize;
+                    let mut fits = q + l.min >= 0 && q + l.max < len as isize;
+                    let n = iterations(c, l.delta, mask);
+                    let cost = 1 + n * (l.body + 1);
+                    if fits && steps + cost > next_poll {
+                        match limiter.poll(steps + cost - 1) {
+                            Ok(n) => next_poll = n,
+                            Err(_) => fits = false,
+                        }
+                    }
+                    if fits {
+                        for &(offset, x) in &l.terms {
+                            let c = cell(tape, (q + offset) as usize);
+                            *c = c.wrapping_add(x.wrapping_mul(n as u32)) & mask;
+                        }
+                        *cell(tape, ptr) = 0;
+                        steps += cost;
+                        pc = l.exit as usize;
+                        continue;
+                    }
+                    //the plain loop right after leaves the tape or runs out of
+                    //budget where the other engines do
+                },
+                Insn::Out => {
+                    if let Err(err) = io.write(output, *cell(tape, ptr)) {
+                        break (ips[pc], Err(err));
+                    }
+                    steps += 1;
+                },
+                Insn::In => {
+                    match io.read(input, *cell(tape, ptr)) {
+                        Ok(c) => *cell(tape, ptr) = c,
+                        Err(err) => break (ips[pc], Err(err)),
+                    }
+                    steps += 1;
+                },
+                Insn::Debug => {
+                    let _ = dump_tape(&mut io::stderr(), tape, ptr);
+                    steps += 1;
+                },
+                Insn::End => break (ips[pc], Ok(())),
+            }
+            pc += 1;
+        };
+
+        self.ip = ip;
+        self.mem_ptr = ptr;
+        self.steps = steps;
+        self.io = io;
+        result
+    }
+
+    fn tape(&self) -> &[u32] {
+        &self.mem
+    }
+
+    fn mem_ptr(&self) -> usize {
+        self.mem_ptr
+    }
+
+    fn ip(&self) -> usize {
+        self.ip
+    }
+
+    fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    fn program_hash(&self) -> u64 {
+        self.program_hash
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            program_hash: self.program_hash,
+            tape: self.mem.clone(),
+            mem_ptr: self.mem_ptr,
+            ip: self.ip,
+            steps: self.steps,
+            input_pos: self.io.input_pos,
+            output_pos: self.io.output_pos,
+        }
+    }
+
+    //Ops folded into a superinstruction other than its first can't be resumed at
+    fn restore(&mut self, snap: &Snapshot) -> Result<(), ExecError> {
+        snap.check(self.program_hash, self.entries.len() - 1)?;
+        if self.entries[snap.ip].is_none() {
+            return Err(ExecError::Snapshot("The bytecode interpreter cannot resume at this op"));
+        }
+        self.mem = snap.tape.clone();
+        self.mem_ptr = snap.mem_ptr;
+        self.ip = snap.ip;
+        self.steps = snap.steps;
+        self.io.input_pos = snap.input_pos;
+        self.io.output_pos = snap.output_pos;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use bf::Program;
+    use difftest;
+    use exec::{self, EngineKind, ExecError};
+
+    //Fused and inlined, the bytecode still ends up where the optimized
+    //interpreter does, cell widths and tape ends included
+    #[test]
+    fn matches_the_optimized_interpreter() {
+        for case in difftest::builtin_corpus() {
+            let prog = Program::parse(&case.source).unwrap();
+            for base in difftest::configs() {
+                let mut config = base.clone();
+                config.engine = EngineKind::Optimized;
+                let want = exec::run(&prog, &case.input, &config);
+                if let Some(ExecError::BudgetExhausted{..}) = want.error {
+                    continue;
+                }
+                config.engine = EngineKind::Bytecode;
+                let got = exec::run(&prog, &case.input, &config);
+                assert_eq!((&got.output, &got.tape, got.mem_ptr, &got.error),
+                           (&want.output, &want.tape, want.mem_ptr, &want.error), "{}", case.name);
+            }
+        }
+    }
+}
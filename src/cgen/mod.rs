@@ -0,0 +1,310 @@
+use std::env;
+use std::fmt::Write as FmtWrite;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+
+use bf::{self, Opcode, Program};
+use exec::{self, CellWidth, Config, EngineKind, EofPolicy};
+
+
+//A loop that only adds to cells and ends where it started, with the loop
+//cell stepping by one: it runs a computable number of times and each cell
+//changes by a multiple of that, e.g. [-] or [->++>+<<]
+struct LinearLoop {
+    //what one iteration adds to the loop cell, 1 or -1
+    step: i32,
+    //(offset, added per iteration) of every other cell the loop touches
+    adds: Vec<(i32, i32)>,
+}
+
+fn linear_loop(ops: &[Opcode], enter: usize) -> Option<LinearLoop> {
+    let exit = match ops[enter] {
+        Opcode::LoopEnter(x) => x,
+        _ => return None,
+    };
+
+    let mut offset = 0i32;
+    let mut step = 0i32;
+    let mut adds: Vec<(i32, i32)> = vec![];
+    for op in &ops[enter + 1..exit] {
+        match *op {
+            Opcode::Ptr(x) => offset += x,
+            Opcode::Byte(x) if offset == 0 => step = step.wrapping_add(x),
+            Opcode::Byte(x) => {
+                match adds.iter().position(|a| a.0 == offset) {
+                    Some(i) => adds[i].1 = adds[i].1.wrapping_add(x),
+                    None => adds.push((offset, x)),
+                }
+            },
+            _ => return None,
+        }
+    }
+
+    if offset != 0 || (step != 1 && step != -1) {
+        return None;
+    }
+    adds.retain(|a| a.1 != 0);
+    Some(LinearLoop {step: step, adds: adds})
+}
+
+
+fn cell_type(width: CellWidth) -> &'static str {
+    match width {
+        CellWidth::U8  => "uint8_t",
+        CellWidth::U16 => "uint16_t",
+        CellWidth::U32 => "uint32_t",
+    }
+}
+
+fn cell_ref(offset: i32) -> String {
+    if offset == 0 { "*p".to_string() } else { format!("p[{}]", offset) }
+}
+
+//`+= x` or `-= x` for a signed amount
+fn add_assign(x: i32) -> String {
+    if x < 0 { format!("-= {}", (x as i64).abs()) } else { format!("+= {}", x) }
+}
+
+struct Generator<'a> {
+    prog: &'a Program,
+    config: &'a Config,
+    out: String,
+    depth: usize,
+}
+
+impl<'a> Generator<'a> {
+    fn line(&mut self, text: &str) {
+        for _ in 0..self.depth + 1 {
+            self.out.push_str("    ");
+        }
+        self.out.push_str(text);
+        self.out.push('\n');
+    }
+
+    fn linear(&mut self, l: &LinearLoop) {
+        if l.adds.is_empty() {
+            self.line("*p = 0;");
+            return;
+        }
+        self.line("if (*p) {");
+        self.depth += 1;
+        //all arithmetic in uint32_t, narrower cells wrap on assignment
+        let cell = cell_type(self.config.cell_width);
+        if l.step < 0 {
+            self.line("uint32_t n = *p;");
+        }else{
+            let line = format!("uint32_t n = ({})(0u - *p);", cell);
+            self.line(&line);
+        }
+        for &(offset, factor) in &l.adds {
+            let line = if factor < 0 {
+                format!("{} -= n * {}u;", cell_ref(offset), (factor as i64).abs())
+            }else{
+                format!("{} += n * {}u;", cell_ref(offset), factor)
+            };
+            self.line(&line);
+        }
+        self.line("*p = 0;");
+        self.depth -= 1;
+        self.line("}");
+    }
+
+    fn read(&mut self) {
+        let on_eof = match self.config.eof {
+            EofPolicy::Zero      => " else *p = 0;".to_string(),
+            EofPolicy::MinusOne  => format!(" else *p = {}u;", self.config.cell_width.mask()),
+            EofPolicy::Unchanged => String::new(),
+        };
+        let line = format!("{{ int c = read_byte(); if (c != EOF) *p = c;{} }}", on_eof);
+        self.line(&line);
+    }
+
+    fn body(&mut self) {
+        let prog = self.prog;
+        let ops = &prog.ops;
+        let mut ip = 0;
+        while ip < ops.len() {
+            match ops[ip] {
+                Opcode::Ptr(x) => {
+                    let line = format!("p {};", add_assign(x));
+                    self.line(&line);
+                },
+                Opcode::Byte(x) => {
+                    let line = format!("*p {};", add_assign(x));
+                    self.line(&line);
+                },
+                Opcode::LoopEnter(x) => {
+                    if let Some(l) = linear_loop(ops, ip) {
+                        self.linear(&l);
+                        ip = x + 1;
+                        continue;
+                    }
+                    if let (Opcode::Ptr(step), true) = (ops[ip + 1], x == ip + 2) {
+                        let line = format!("while (*p) p {};", add_assign(step));
+                        self.line(&line);
+                        ip = x + 1;
+                        continue;
+                    }
+                    self.line("while (*p) {");
+                    self.depth += 1;
+                },
+                Opcode::LoopExit(_) => {
+                    self.depth -= 1;
+                    self.line("}");
+                },
+                Opcode::Out => self.line("putchar(*p);"),
+                Opcode::In => self.read(),
+                Opcode::Debug => self.line("dump(p);"),
+            }
+            ip += 1;
+        }
+    }
+}
+
+//Readable C for a program: loops become while statements and linear loops
+//straight-line code. The pointer isn't bounds checked.
+pub fn generate(prog: &Program, config: &Config) -> String {
+    let cell = cell_type(config.cell_width);
+    let mut out = String::new();
+    writeln!(out, "#include <stdint.h>").unwrap();
+    writeln!(out, "#include <stdio.h>").unwrap();
+    writeln!(out, "").unwrap();
+    writeln!(out, "static {} tape[{}];", cell, config.tape_size).unwrap();
+    writeln!(out, "").unwrap();
+
+    match prog.input {
+        Some(ref input) => {
+            write!(out, "static const unsigned char input[{}] = {{", input.len() + 1).unwrap();
+            for (i, b) in input.iter().enumerate() {
+                if i % 16 == 0 {
+                    write!(out, "\n    ").unwrap();
+                }
+                write!(out, "{},", b).unwrap();
+            }
+            writeln!(out, "\n}};").unwrap();
+            writeln!(out, "static size_t input_pos;").unwrap();
+            writeln!(out, "").unwrap();
+            writeln!(out, "static int read_byte(void) {{").unwrap();
+            writeln!(out, "    return input_pos < {} ? input[input_pos++] : EOF;", input.len()).unwrap();
+            writeln!(out, "}}").unwrap();
+        },
+        None if prog.ops.contains(&Opcode::In) => {
+            writeln!(out, "static int read_byte(void) {{").unwrap();
+            writeln!(out, "    return getchar();").unwrap();
+            writeln!(out, "}}").unwrap();
+        },
+        None => {},
+    }
+
+    if prog.ops.contains(&Opcode::Debug) {
+        writeln!(out, "").unwrap();
+        writeln!(out, "static void dump(const {} *p) {{", cell).unwrap();
+        writeln!(out, "    long ptr = p - tape, i;").unwrap();
+        writeln!(out, "    long first = ptr > 8 ? ptr - 8 : 0;").unwrap();
+        writeln!(out, "    long last = ptr + 8 < {} ? ptr + 8 : {};", config.tape_size, config.tape_size - 1).unwrap();
+        writeln!(out, "    fprintf(stderr, \"#ptr=%ld cells %ld..%ld:\", ptr, first, last);").unwrap();
+        writeln!(out, "    for (i = first; i <= last; i++)").unwrap();
+        writeln!(out, "        fprintf(stderr, i == ptr ? \" [%lu]\" : \" %lu\", (unsigned long)tape[i]);").unwrap();
+        writeln!(out, "    fprintf(stderr, \"\\n\");").unwrap();
+        writeln!(out, "}}").unwrap();
+    }
+
+    writeln!(out, "").unwrap();
+    writeln!(out, "int main(void) {{").unwrap();
+    writeln!(out, "    {} *p = tape;", cell).unwrap();
+    let mut g = Generator {prog: prog, config: config, out: out, depth: 0};
+    g.body();
+    let mut out = g.out;
+    writeln!(out, "    return 0;").unwrap();
+    writeln!(out, "}}").unwrap();
+    out
+}
+
+
+//Builds the generated C into `exe` with the system compiler or the one in
+//$CC, at an optimization flag like "-O2"
+pub fn build<P: AsRef<Path>>(prog: &Program, config: &Config, opt: &str, exe: P) -> Result<(), String> {
+    let stem = format!("bf_cgen_{}_{:x}", ::std::process::id(), bf::hash_ops(&prog.ops));
+    let src = env::temp_dir().join(format!("{}.c", stem));
+    File::create(&src).and_then(|mut f| f.write_all(generate(prog, config).as_bytes()))
+        .map_err(|err| format!("Could not write {}: {}", src.display(), err))?;
+
+    let cc = env::var("CC").unwrap_or("cc".to_string());
+    let status = Command::new(&cc).arg(opt).arg("-o").arg(exe.as_ref()).arg(&src).status()
+        .map_err(|err| format!("Could not run {}: {}", cc, err))?;
+    if !status.success() {
+        return Err(format!("{} failed on {}", cc, src.display()));
+    }
+    let _ = ::std::fs::remove_file(&src);
+    Ok(())
+}
+
+//Builds the generated C, runs it on `input` and compares its output with the
+//optimized interpreter's
+pub fn check(prog: &Program, config: &Config, input: &[u8]) -> Result<(), String> {
+    let exe = env::temp_dir().join(format!("bf_cgen_{}_{:x}", ::std::process::id(), bf::hash_ops(&prog.ops)));
+    build(prog, config, "-O2", &exe)?;
+
+    let mut child = Command::new(&exe).stdin(Stdio::piped()).stdout(Stdio::piped()).spawn()
+        .map_err(|err| format!("Could not run {}: {}", exe.display(), err))?;
+    //fed from another thread, the program may fill the stdout pipe before it
+    //reads all of its input. It may also exit without reading everything.
+    let mut stdin = child.stdin.take().unwrap();
+    let input_copy = input.to_vec();
+    let feeder = thread::spawn(move || { let _ = stdin.write_all(&input_copy); });
+    let native = child.wait_with_output().map_err(|err| err.to_string())?;
+    let _ = feeder.join();
+
+    let mut reference = config.clone();
+    reference.engine = EngineKind::Optimized;
+    let expected = exec::run(prog, input, &reference);
+    if let Some(err) = expected.error {
+        return Err(format!("Interpreter failed: {}", err));
+    }
+
+    let _ = ::std::fs::remove_file(&exe);
+
+    if native.stdout != expected.output {
+        let at = native.stdout.iter().zip(expected.output.iter()).take_while(|&(a, b)| a == b).count();
+        return Err(format!("Output differs at byte {} ({} bytes from C, {} from the interpreter)",
+                           at, native.stdout.len(), expected.output.len()));
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::process::{Command, Stdio};
+    use bf::Program;
+    use exec::{CellWidth, Config};
+
+    fn have_cc() -> bool {
+        Command::new("cc").arg("--version").stdout(Stdio::null()).status().is_ok()
+    }
+
+    #[test]
+    fn matches_the_interpreter() {
+        if !have_cc() {
+            return;
+        }
+        let prog = Program::parse("++++++++[>++++[>++>+++<<-]>+<<-]>>.>+.[-]<[->+<]>.,[.,]").unwrap();
+        super::check(&prog, &Config::new(), b"abc").unwrap();
+    }
+
+    //More output than a pipe holds before the program reads its input, which
+    //is also more than a pipe holds
+    #[test]
+    fn does_not_block_on_large_input_and_output() {
+        if !have_cc() {
+            return;
+        }
+        let prog = Program::parse("-[>-[.-]<-],[.,]").unwrap();
+        let mut config = Config::new();
+        config.cell_width = CellWidth::U8;
+        super::check(&prog, &config, &vec![b'a'; 1 << 17]).unwrap();
+    }
+}
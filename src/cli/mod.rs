@@ -0,0 +1,472 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use aot;
+use bench;
+use bf::{Opcode, OptimizedInterpreter, Program};
+use cgen;
+use debugger::Debugger;
+use difftest;
+use emitter::text::Syntax;
+use exec::{self, CellWidth, Config, Engine, EngineKind, EofPolicy, ExecError, GuardPolicy};
+use repl::Repl;
+use wasm;
+
+
+pub const USAGE: &'static str = "\
+usage: bf_jit <command> [options]
+
+commands:
+    run FILE        run a program, on stdin unless --input is given
+    compile FILE    compile a program ahead of time, see --target
+    dump-ir FILE    list the ops a program parses to
+    disasm FILE     print the machine code the AOT compiler generates
+    repl [FILE]     run lines of BF interactively on one tape, after FILE if given
+    debug FILE      step through a program with commands read from stdin, see `help` in it
+    bench           time the standard programs under bench/
+    difftest [DIR]  check all engines agree on the built-in programs and the ones in DIR
+    help            print this
+
+options:
+    -e, --engine E      naive, optimized, bytecode, threaded, jit or tiered
+    -O0 .. -O3          engine by level: naive, optimized, tiered, jit (default -O1)
+    --cell-width N      8, 16 or 32 bits (default 32)
+    --tape-size N       cells on the tape (default 30000)
+    --eof P             what , reads at end of input: zero, minus-one or unchanged
+    --guard P           run the JIT on a tape between guard pages instead of checking
+                        bounds, running into them is an error or grows the tape: error or grow
+    -i, --input FILE    read input from FILE instead of stdin
+    -o, --output FILE   where compile and bench write to
+    --target T          compile to elf, object, asm, c, wasm or wat (default elf)
+    --syntax S          intel or att, for asm and disasm (default intel)
+    --runs N            timed runs per program and variant (default 5)
+    --warmup N          untimed runs before them (default 1)
+    --compare FILE      bench results to compare against
+    --profile           run on the optimized interpreter and print how often each op
+                        and loop ran to stderr
+";
+
+//Exit codes
+pub const EXIT_OK: i32 = 0;
+//the program failed while running, or bench found a regression
+pub const EXIT_RUN_ERROR: i32 = 1;
+pub const EXIT_USAGE: i32 = 2;
+//unbalanced brackets
+pub const EXIT_PARSE: i32 = 3;
+//reading the source or input, or writing the output failed
+pub const EXIT_FILE: i32 = 4;
+//the program can't be compiled, or the JIT can't run here
+pub const EXIT_COMPILE: i32 = 5;
+
+
+#[derive(Eq, PartialEq, Debug, Copy, Clone)]
+enum Target {
+    Elf,
+    Object,
+    Asm,
+    C,
+    Wasm,
+    Wat,
+}
+
+impl Target {
+    fn extension(&self) -> &'static str {
+        match *self {
+            Target::Elf    => "",
+            Target::Object => "o",
+            Target::Asm    => "s",
+            Target::C      => "c",
+            Target::Wasm   => "wasm",
+            Target::Wat    => "wat",
+        }
+    }
+}
+
+struct Options {
+    command: String,
+    file: Option<String>,
+    config: Config,
+    engine: Option<EngineKind>,
+    input: Option<String>,
+    output: Option<String>,
+    target: Target,
+    syntax: Syntax,
+    runs: Option<usize>,
+    warmup: Option<usize>,
+    compare: Option<String>,
+    profile: bool,
+}
+
+fn engine_at_level(level: &str) -> Result<EngineKind, String> {
+    match level {
+        "0" => Ok(EngineKind::Naive),
+        "1" => Ok(EngineKind::Optimized),
+        "2" => Ok(EngineKind::Tiered),
+        "3" => Ok(EngineKind::Jit),
+        _ => Err(format!("Unknown optimization level -O{}", level)),
+    }
+}
+
+fn number<T: ::std::str::FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("{} needs a number, not {:?}", flag, value))
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut args = args.iter();
+    let command = match args.next() {
+        Some(c) => c.clone(),
+        None => return Err("No command given".to_string()),
+    };
+    let mut o = Options {command: command, file: None, config: Config::new(), engine: None, input: None, output: None,
+                         target: Target::Elf, syntax: Syntax::Intel, runs: None, warmup: None, compare: None, profile: false};
+
+    while let Some(arg) = args.next() {
+        if !arg.starts_with('-') {
+            if o.file.is_some() {
+                return Err(format!("Unexpected argument {:?}", arg));
+            }
+            o.file = Some(arg.clone());
+            continue;
+        }
+        if arg.starts_with("-O") && arg.len() > 2 {
+            o.engine = Some(engine_at_level(&arg[2..])?);
+            continue;
+        }
+
+        //--flag=value or --flag value
+        let (flag, inline) = match arg.find('=') {
+            Some(i) => (&arg[..i], Some(arg[i + 1..].to_string())),
+            None => (&arg[..], None),
+        };
+        let mut value = || -> Result<String, String> {
+            match inline.clone() {
+                Some(v) => Ok(v),
+                None => args.next().cloned().ok_or(format!("{} needs a value", flag)),
+            }
+        };
+        match flag {
+            "-e" | "--engine" => {
+                o.engine = Some(match &*value()? {
+                    "naive"     => EngineKind::Naive,
+                    "optimized" => EngineKind::Optimized,
+                    "jit"       => EngineKind::Jit,
+                    "tiered"    => EngineKind::Tiered,
+                    "bytecode"  => EngineKind::Bytecode,
+                    "threaded"  => EngineKind::Threaded,
+                    v => return Err(format!("Unknown engine {:?}", v)),
+                });
+            },
+            "-O" => o.engine = Some(engine_at_level(&value()?)?),
+            "--cell-width" => {
+                o.config.cell_width = match &*value()? {
+                    "8"  => CellWidth::U8,
+                    "16" => CellWidth::U16,
+                    "32" => CellWidth::U32,
+                    v => return Err(format!("Cells are 8, 16 or 32 bits, not {:?}", v)),
+                };
+            },
+            "--tape-size" => {
+                o.config.tape_size = number(flag, &value()?)?;
+                if o.config.tape_size == 0 {
+                    return Err("The tape needs at least one cell".to_string());
+                }
+            },
+            "--eof" => {
+                o.config.eof = match &*value()? {
+                    "zero"      => EofPolicy::Zero,
+                    "minus-one" => EofPolicy::MinusOne,
+                    "unchanged" => EofPolicy::Unchanged,
+                    v => return Err(format!("Unknown EOF policy {:?}", v)),
+                };
+            },
+            "--guard" => {
+                o.config.guard = match &*value()? {
+                    "error" => Some(GuardPolicy::Error),
+                    "grow"  => Some(GuardPolicy::Grow),
+                    v => return Err(format!("Unknown guard policy {:?}", v)),
+                };
+            },
+            "-i" | "--input" => o.input = Some(value()?),
+            "-o" | "--output" => o.output = Some(value()?),
+            "--target" => {
+                o.target = match &*value()? {
+                    "elf"    => Target::Elf,
+                    "object" => Target::Object,
+                    "asm"    => Target::Asm,
+                    "c"      => Target::C,
+                    "wasm"   => Target::Wasm,
+                    "wat"    => Target::Wat,
+                    v => return Err(format!("Unknown target {:?}", v)),
+                };
+            },
+            "--syntax" => {
+                o.syntax = match &*value()? {
+                    "intel" => Syntax::Intel,
+                    "att"   => Syntax::Att,
+                    v => return Err(format!("Unknown syntax {:?}", v)),
+                };
+            },
+            "--runs" => o.runs = Some(number(flag, &value()?)?),
+            "--warmup" => o.warmup = Some(number(flag, &value()?)?),
+            "--compare" => o.compare = Some(value()?),
+            "--profile" => o.profile = true,
+            "-h" | "--help" => o.command = "help".to_string(),
+            _ => return Err(format!("Unknown option {}", arg)),
+        }
+    }
+    if let Some(engine) = o.engine {
+        o.config.engine = engine;
+    }
+    if o.config.guard.is_some() && o.config.engine != EngineKind::Jit {
+        return Err("--guard only works with the jit engine".to_string());
+    }
+    if o.profile && o.engine.is_some() && o.engine != Some(EngineKind::Optimized) {
+        return Err("--profile only works with the optimized engine".to_string());
+    }
+    Ok(o)
+}
+
+
+//Failures carry their exit code and what to tell the user
+type CliResult = Result<(), (i32, String)>;
+
+fn read_file(path: &str) -> Result<Vec<u8>, (i32, String)> {
+    let mut data = vec![];
+    File::open(path).and_then(|mut f| f.read_to_end(&mut data))
+        .map_err(|err| (EXIT_FILE, format!("Could not read {}: {}", path, err)))?;
+    Ok(data)
+}
+
+fn load(o: &Options) -> Result<Program, (i32, String)> {
+    let path = match o.file {
+        Some(ref f) => f,
+        None => return Err((EXIT_USAGE, format!("{} needs a source file", o.command))),
+    };
+    let source = String::from_utf8_lossy(&read_file(path)?).into_owned();
+    Program::parse_dialect(&source, o.config.dialect).map_err(|err| (EXIT_PARSE, format!("{}: {}", path, err)))
+}
+
+fn exit_code(err: &ExecError) -> i32 {
+    match *err {
+        ExecError::Parse(_) => EXIT_PARSE,
+        ExecError::Jit(_) => EXIT_COMPILE,
+        ExecError::Config(_) => EXIT_USAGE,
+        _ => EXIT_RUN_ERROR,
+    }
+}
+
+//Errors at an op point into the source
+fn describe(err: &ExecError, prog: &Program) -> String {
+    match *err {
+        ExecError::PointerOutOfRange{ip} if ip < prog.ops.len() => {
+            let (line, col) = prog.line_col(ip);
+            format!("pointer out of range at {}:{}", line, col)
+        },
+        _ => err.to_string(),
+    }
+}
+
+fn run(o: &Options) -> CliResult {
+    let prog = load(o)?;
+    if o.profile {
+        let mut interp = OptimizedInterpreter::with_config(&o.config);
+        interp.load_program(&prog);
+        interp.enable_profiling();
+        let result = run_on(&mut interp, &prog, o);
+        interp.profile().unwrap().report(&prog, &mut io::stderr()).map_err(|err| (EXIT_FILE, err.to_string()))?;
+        return result;
+    }
+    let mut engine = exec::create_engine(&prog, &o.config).map_err(|err| (exit_code(&err), err.to_string()))?;
+    run_on(&mut *engine, &prog, o)
+}
+
+//Runs on the program's own input, the --input file or stdin
+fn run_on(engine: &mut dyn Engine, prog: &Program, o: &Options) -> CliResult {
+    let stdout = io::stdout();
+    let mut output = stdout.lock();
+    let result = match (&prog.input, &o.input) {
+        (&Some(ref input), _) => engine.run_io(&mut &input[..], &mut output),
+        (&None, &Some(ref path)) => {
+            let input = read_file(path)?;
+            engine.run_io(&mut &input[..], &mut output)
+        },
+        (&None, &None) => {
+            let stdin = io::stdin();
+            let mut input = stdin.lock();
+            engine.run_io(&mut input, &mut output)
+        },
+    };
+    let _ = output.flush();
+    result.map_err(|err| (exit_code(&err), describe(&err, prog)))
+}
+
+fn compile(o: &Options) -> CliResult {
+    let prog = load(o)?;
+    let out = match o.output {
+        Some(ref out) => out.clone(),
+        None => {
+            let src = Path::new(o.file.as_ref().unwrap());
+            src.with_extension(o.target.extension()).to_string_lossy().into_owned()
+        },
+    };
+    if Some(Path::new(&out)) == o.file.as_ref().map(Path::new) {
+        return Err((EXIT_USAGE, format!("Output {} would overwrite the source", out)));
+    }
+
+    let compiled: Result<Vec<u8>, String> = match o.target {
+        Target::Elf    => aot::compile_elf(&prog, &o.config).map_err(|e| e.to_string()),
+        Target::Object => aot::compile_object(&prog, &o.config, "bf_main").map_err(|e| e.to_string()),
+        Target::Asm    => aot::compile_asm(&prog, &o.config, "bf_main", o.syntax).map(String::into_bytes).map_err(|e| e.to_string()),
+        Target::C      => Ok(cgen::generate(&prog, &o.config).into_bytes()),
+        Target::Wasm   => wasm::compile(&prog, &o.config).map_err(|e| e.to_string()),
+        Target::Wat    => wasm::compile(&prog, &o.config).map_err(|e| e.to_string())
+                              .and_then(|m| wasm::to_wat(&m)).map(String::into_bytes),
+    };
+    let bytes = compiled.map_err(|err| (EXIT_COMPILE, err))?;
+
+    let written = File::create(&out).and_then(|mut f| {
+        f.write_all(&bytes)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            if o.target == Target::Elf {
+                f.set_permissions(PermissionsExt::from_mode(0o755))?;
+            }
+        }
+        Ok(())
+    });
+    written.map_err(|err| (EXIT_FILE, format!("Could not write {}: {}", out, err)))
+}
+
+//One op per line, with where it starts in the source, indented by loop depth
+fn dump_ir(o: &Options) -> CliResult {
+    let prog = load(o)?;
+    let stdout = io::stdout();
+    let mut w = stdout.lock();
+    let mut depth = 0;
+    for (ip, op) in prog.ops.iter().enumerate() {
+        if let Opcode::LoopExit(_) = *op {
+            depth -= 1;
+        }
+        let (line, col) = prog.line_col(ip);
+        writeln!(w, "{:>6} {:>10}  {}{}", ip, format!("{}:{}", line, col), "  ".repeat(depth), op)
+            .map_err(|err| (EXIT_FILE, err.to_string()))?;
+        if let Opcode::LoopEnter(_) = *op {
+            depth += 1;
+        }
+    }
+    Ok(())
+}
+
+fn disasm(o: &Options) -> CliResult {
+    let prog = load(o)?;
+    let listing = aot::compile_asm(&prog, &o.config, "bf_main", o.syntax).map_err(|err| (EXIT_COMPILE, err.to_string()))?;
+    print!("{}", listing);
+    Ok(())
+}
+
+fn run_repl(o: &Options) -> CliResult {
+    let mut repl = Repl::new(&o.config);
+    let stdout = io::stdout();
+    let mut w = stdout.lock();
+    if o.file.is_some() {
+        let prog = load(o)?;
+        repl.line(&prog.source, &mut w).map_err(|err| (EXIT_FILE, err.to_string()))?;
+    }
+    let stdin = io::stdin();
+    let mut commands = stdin.lock();
+    repl.run_commands(&mut commands, &mut w).map_err(|err| (EXIT_FILE, err.to_string()))
+}
+
+fn run_debugger(o: &Options) -> CliResult {
+    let prog = load(o)?;
+    let mut d = Debugger::new(prog, &o.config);
+    let stdin = io::stdin();
+    let mut commands = stdin.lock();
+    let stdout = io::stdout();
+    let mut w = stdout.lock();
+    d.run_commands(&mut commands, &mut w).map_err(|err| (EXIT_FILE, err.to_string()))
+}
+
+fn run_bench(o: &Options) -> CliResult {
+    let benches = bench::standard().map_err(|err| (EXIT_FILE, format!("Could not load the bench programs: {}", err)))?;
+    let mut options = bench::Options::new();
+    options.config = o.config.clone();
+    if let Some(engine) = o.engine {
+        options.variants = vec![bench::Variant::Engine(engine)];
+    }
+    if let Some(runs) = o.runs {
+        options.runs = runs;
+    }
+    if let Some(warmup) = o.warmup {
+        options.warmup = warmup;
+    }
+
+    let results = bench::run(&benches, &options, &mut io::stderr());
+    let stdout = io::stdout();
+    let mut w = stdout.lock();
+    bench::report(&results, &mut w).map_err(|err| (EXIT_FILE, err.to_string()))?;
+    if let Some(ref path) = o.output {
+        bench::write_results(path, &results).map_err(|err| (EXIT_FILE, format!("Could not write {}: {}", path, err)))?;
+    }
+    if let Some(ref path) = o.compare {
+        let old = bench::read_results(path).map_err(|err| (EXIT_FILE, format!("Could not read {}: {}", path, err)))?;
+        writeln!(w, "").map_err(|err| (EXIT_FILE, err.to_string()))?;
+        let slower = bench::compare(&old, &results, &mut w).map_err(|err| (EXIT_FILE, err.to_string()))?;
+        if slower > 0 {
+            return Err((EXIT_RUN_ERROR, format!("{} results regressed against {}", slower, path)));
+        }
+    }
+    if results.iter().any(|m| m.error.is_some()) {
+        return Err((EXIT_RUN_ERROR, "Some benchmarks failed".to_string()));
+    }
+    Ok(())
+}
+
+fn run_difftest(o: &Options) -> CliResult {
+    let mut cases = difftest::builtin_corpus();
+    if let Some(ref dir) = o.file {
+        cases.extend(difftest::load_corpus(dir).map_err(|err| (EXIT_FILE, format!("Could not read {}: {}", dir, err)))?);
+    }
+    let report = difftest::run(&cases, &difftest::configs());
+    print!("{}", report);
+    if !report.mismatches.is_empty() {
+        return Err((EXIT_RUN_ERROR, format!("{} runs disagree between engines", report.mismatches.len())));
+    }
+    Ok(())
+}
+
+//Runs the command line in `args`, without the program name, and returns the
+//exit code
+pub fn main(args: &[String]) -> i32 {
+    let o = match parse_args(args) {
+        Ok(o) => o,
+        Err(err) => {
+            eprintln!("bf_jit: {}\n\n{}", err, USAGE);
+            return EXIT_USAGE;
+        },
+    };
+    let result = match &*o.command {
+        "run"     => run(&o),
+        "compile" => compile(&o),
+        "dump-ir" => dump_ir(&o),
+        "disasm"  => disasm(&o),
+        "repl"    => run_repl(&o),
+        "debug"   => run_debugger(&o),
+        "bench"   => run_bench(&o),
+        "difftest" => run_difftest(&o),
+        "help"    => {
+            print!("{}", USAGE);
+            Ok(())
+        },
+        c => Err((EXIT_USAGE, format!("Unknown command {:?}\n\n{}", c, USAGE))),
+    };
+    match result {
+        Ok(()) => EXIT_OK,
+        Err((code, err)) => {
+            eprintln!("bf_jit: {}", err);
+            code
+        },
+    }
+}
@@ -0,0 +1,100 @@
+use std::collections::VecDeque;
+
+use bf::CellIo;
+use snapshot::Snapshot;
+
+
+//Ops the undo log holds before dropping its oldest entries, going back further
+//replays forward from a checkpoint
+const UNDO_LIMIT: usize = 1 << 17;
+//Ops between two checkpoints of the whole tape. Must not exceed UNDO_LIMIT, so
+//replaying from a checkpoint refills the undo log completely.
+pub const CHECKPOINT_INTERVAL: u64 = 1 << 15;
+//The oldest checkpoint bounds how far back the debugger can go
+const MAX_CHECKPOINTS: usize = 64;
+
+
+//State an op started from. An op writes at most the cell under the pointer, so
+//that cell's old value is all of the tape we have to remember.
+#[derive(Debug, Copy, Clone)]
+pub struct Undo {
+    pub ip: usize,
+    pub mem_ptr: usize,
+    pub cell: u32,
+    pub io: CellIo,
+}
+
+pub struct History {
+    undo: VecDeque<Undo>,
+    checkpoints: VecDeque<Snapshot>,
+    //Every byte the program consumed, indexed by input position, so rewinding
+    //past a read can hand the byte back to the input queue
+    inputs: Vec<u8>,
+}
+
+impl History {
+    pub fn new() -> History {
+        History {undo: VecDeque::new(), checkpoints: VecDeque::new(), inputs: vec![]}
+    }
+
+    pub fn clear(&mut self) {
+        self.clear_steps();
+        self.inputs.clear();
+    }
+
+    //Forgets the ops run so far but keeps the consumed input, whose positions
+    //are still the ones the interpreter counts from
+    pub fn clear_steps(&mut self) {
+        self.undo.clear();
+        self.checkpoints.clear();
+    }
+
+    pub fn record(&mut self, undo: Undo) {
+        if self.undo.len() == UNDO_LIMIT {
+            self.undo.pop_front();
+        }
+        self.undo.push_back(undo);
+    }
+
+    pub fn pop(&mut self) -> Option<Undo> {
+        self.undo.pop_back()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.undo.is_empty()
+    }
+
+    //Replaying after a rewind reaches the same steps again, those already have one
+    pub fn checkpoint(&mut self, snap: Snapshot) {
+        if self.checkpoints.back().map_or(false, |c| c.steps >= snap.steps) {
+            return;
+        }
+        if self.checkpoints.len() == MAX_CHECKPOINTS {
+            self.checkpoints.pop_front();
+        }
+        self.checkpoints.push_back(snap);
+    }
+
+    pub fn checkpoint_before(&self, steps: u64) -> Option<&Snapshot> {
+        self.checkpoints.iter().rev().find(|c| c.steps < steps)
+    }
+
+    pub fn record_input(&mut self, byte: u8) {
+        self.inputs.push(byte);
+    }
+
+    //Forgets and returns the bytes consumed at or after input position `pos`
+    pub fn take_inputs(&mut self, pos: u64) -> Vec<u8> {
+        let pos = pos as usize;
+        if pos < self.inputs.len() { self.inputs.split_off(pos) } else { vec![] }
+    }
+
+    //Earliest step the debugger can rewind to from `steps`
+    pub fn start(&self, steps: u64) -> u64 {
+        let logged = steps - self.undo.len() as u64;
+        match self.checkpoints.front() {
+            Some(c) if c.steps < logged => c.steps,
+            _ => logged,
+        }
+    }
+}
@@ -0,0 +1,628 @@
+use std::io::{self, BufRead, Write};
+
+use bf::{Opcode, OptimizedInterpreter, Program};
+use exec::{Config, Engine, ExecError, InputQueue};
+
+mod history;
+use self::history::{History, Undo, CHECKPOINT_INTERVAL};
+
+
+const HELP: &'static str = "\
+Commands:
+  b, break LINE:COL | OP    set a breakpoint on a source position or op index
+  d, delete N               delete breakpoint N
+  w, watch CELL             stop whenever CELL changes
+  u, unwatch CELL           remove the watchpoint on CELL
+  i, info                   list breakpoints and watchpoints
+  s, step [N]               execute N ops (default 1)
+  n, next                   step over the loop starting at the current op
+  c, continue               run until a breakpoint, watchpoint or the end
+  bs, back [N]              undo the last N ops (default 1)
+  rc, rcontinue             run backwards until a breakpoint or watchpoint
+  lw, last-write CELL       run backwards to the op that last wrote CELL
+  t, tape [RADIUS]          show the cells around the pointer (default 8)
+  p, print CELL             show one cell
+  set CELL VALUE            write VALUE into CELL
+  l, list                   show the source around the current op
+  input TEXT                feed TEXT (plus a newline) to the program
+  eof                       close the program's input
+  r, restart                start over with the same breakpoints
+  q, quit                   leave the debugger
+Running backwards doesn't take back output, `set` and `restart` clear the history.";
+
+
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub enum Stop {
+    Step,
+    Breakpoint(usize),
+    Watchpoint{cell: usize, old: u32, new: u32},
+    Finished,
+    NeedsInput,
+    Error(ExecError),
+    LastWrite(usize),
+    HistoryStart,
+}
+
+
+pub struct Debugger {
+    prog: Program,
+    config: Config,
+    interp: OptimizedInterpreter,
+    input: InputQueue,
+    breakpoints: Vec<usize>,
+    //watched cell and the value it had when last looked at
+    watchpoints: Vec<(usize, u32)>,
+    history: History,
+}
+
+impl Debugger {
+    pub fn new(prog: Program, config: &Config) -> Debugger {
+        let mut interp = OptimizedInterpreter::with_config(config);
+        interp.load_program(&prog);
+        let mut d = Debugger {
+            prog: prog,
+            config: config.clone(),
+            interp: interp,
+            input: InputQueue::new(),
+            breakpoints: vec![],
+            watchpoints: vec![],
+            history: History::new(),
+        };
+        d.feed_embedded_input();
+        d
+    }
+
+    fn feed_embedded_input(&mut self) {
+        if let Some(ref embedded) = self.prog.input {
+            self.input.feed(embedded);
+            self.input.close();
+        }
+    }
+
+    pub fn restart(&mut self) {
+        self.interp = OptimizedInterpreter::with_config(&self.config);
+        self.interp.load_program(&self.prog);
+        self.input = InputQueue::new();
+        self.feed_embedded_input();
+        self.history.clear();
+        for w in self.watchpoints.iter_mut() {
+            w.1 = 0;
+        }
+    }
+
+    pub fn interpreter(&self) -> &OptimizedInterpreter {
+        &self.interp
+    }
+
+    pub fn add_breakpoint(&mut self, op: usize) -> Result<usize, &'static str> {
+        if op >= self.prog.ops.len() {
+            return Err("No such op");
+        }
+        self.breakpoints.push(op);
+        Ok(self.breakpoints.len() - 1)
+    }
+
+    pub fn add_watchpoint(&mut self, cell: usize) -> Result<(), &'static str> {
+        if cell >= self.interp.tape().len() {
+            return Err("No such cell");
+        }
+        let value = self.interp.tape()[cell];
+        self.watchpoints.push((cell, value));
+        Ok(())
+    }
+
+    //First op at or after LINE:COL on that line
+    pub fn op_at(&self, line: usize, col: usize) -> Option<usize> {
+        (0..self.prog.ops.len()).find(|&i| {
+            let (l, c) = self.prog.line_col(i);
+            l == line && c >= col
+        })
+    }
+
+    //Executes one op and logs what it takes to undo it
+    fn exec_logged(&mut self, out: &mut dyn Write) -> Result<(), ExecError> {
+        let ptr = self.interp.mem_ptr();
+        let undo = Undo {
+            ip: self.interp.ip(),
+            mem_ptr: ptr,
+            cell: self.interp.tape()[ptr],
+            io: self.interp.io(),
+        };
+        if self.interp.steps() % CHECKPOINT_INTERVAL == 0 {
+            self.history.checkpoint(self.interp.snapshot());
+        }
+        self.interp.step(&mut self.input, out)?;
+        self.history.record(undo);
+        if self.interp.io().input_pos > undo.io.input_pos {
+            self.history.record_input(self.interp.tape()[ptr] as u8);
+        }
+        Ok(())
+    }
+
+    fn check_watchpoints(&mut self) -> Option<Stop> {
+        for w in self.watchpoints.iter_mut() {
+            let value = self.interp.tape()[w.0];
+            if value != w.1 {
+                let old = w.1;
+                w.1 = value;
+                return Some(Stop::Watchpoint{cell: w.0, old: old, new: value});
+            }
+        }
+        None
+    }
+
+    fn step_one(&mut self, out: &mut dyn Write) -> Option<Stop> {
+        if self.interp.is_finished() {
+            return Some(Stop::Finished);
+        }
+        match self.exec_logged(out) {
+            Ok(_) => {},
+            Err(ExecError::NeedsInput) => return Some(Stop::NeedsInput),
+            Err(err) => return Some(Stop::Error(err)),
+        }
+        if let Some(stop) = self.check_watchpoints() {
+            return Some(stop);
+        }
+        if self.interp.is_finished() {
+            return Some(Stop::Finished);
+        }
+        None
+    }
+
+    //Rebuilds the undo log by replaying from the latest checkpoint before the
+    //current step, false if there is none
+    fn refill_history(&mut self) -> bool {
+        let steps = self.interp.steps();
+        let snap = match self.history.checkpoint_before(steps) {
+            Some(snap) => snap.clone(),
+            None => return false,
+        };
+        if self.interp.restore(&snap).is_err() {
+            return false;
+        }
+        let bytes = self.history.take_inputs(snap.input_pos);
+        self.input.unread(&bytes);
+        for _ in snap.steps..steps {
+            if self.exec_logged(&mut io::sink()).is_err() {
+                return false;
+            }
+        }
+        true
+    }
+
+    //Undoes the last op and returns how it started, None at the start of the history
+    fn back_one(&mut self) -> Option<Undo> {
+        if self.history.is_empty() && !self.refill_history() {
+            return None;
+        }
+        let undo = self.history.pop()?;
+        self.interp.unstep(undo.ip, undo.mem_ptr, undo.cell, undo.io);
+        let bytes = self.history.take_inputs(undo.io.input_pos);
+        self.input.unread(&bytes);
+        Some(undo)
+    }
+
+    fn sync_watchpoints(&mut self) {
+        for w in self.watchpoints.iter_mut() {
+            w.1 = self.interp.tape()[w.0];
+        }
+    }
+
+    pub fn step_back(&mut self, n: usize) -> Stop {
+        for _ in 0..n {
+            if self.back_one().is_none() {
+                self.sync_watchpoints();
+                return Stop::HistoryStart;
+            }
+        }
+        self.sync_watchpoints();
+        Stop::Step
+    }
+
+    pub fn reverse_cont(&mut self) -> Stop {
+        loop {
+            if self.back_one().is_none() {
+                self.sync_watchpoints();
+                return Stop::HistoryStart;
+            }
+            if let Some(stop) = self.check_watchpoints() {
+                return stop;
+            }
+            let ip = self.interp.ip();
+            if self.breakpoints.contains(&ip) {
+                return Stop::Breakpoint(ip);
+            }
+        }
+    }
+
+    //Stops in front of the op that last wrote `cell`
+    pub fn last_write(&mut self, cell: usize) -> Stop {
+        let stop = loop {
+            let undo = match self.back_one() {
+                Some(undo) => undo,
+                None => break Stop::HistoryStart,
+            };
+            match self.prog.ops[undo.ip] {
+                Opcode::Byte(_) | Opcode::In if undo.mem_ptr == cell => break Stop::LastWrite(cell),
+                _ => {},
+            }
+        };
+        self.sync_watchpoints();
+        stop
+    }
+
+    pub fn step(&mut self, n: usize, out: &mut dyn Write) -> Stop {
+        for _ in 0..n {
+            if let Some(stop) = self.step_one(out) {
+                return stop;
+            }
+        }
+        Stop::Step
+    }
+
+    //Runs until ip reaches `until` (if given) or a breakpoint is hit. The op we
+    //start on is always executed, so continuing from a breakpoint makes progress.
+    fn run_until(&mut self, until: Option<usize>, out: &mut dyn Write) -> Stop {
+        loop {
+            if let Some(stop) = self.step_one(out) {
+                return stop;
+            }
+            let ip = self.interp.ip();
+            if Some(ip) == until {
+                return Stop::Step;
+            }
+            if self.breakpoints.contains(&ip) {
+                return Stop::Breakpoint(ip);
+            }
+        }
+    }
+
+    pub fn cont(&mut self, out: &mut dyn Write) -> Stop {
+        self.run_until(None, out)
+    }
+
+    pub fn step_over(&mut self, out: &mut dyn Write) -> Stop {
+        match self.prog.ops.get(self.interp.ip()) {
+            Some(&Opcode::LoopEnter(x)) => self.run_until(Some(x + 1), out),
+            _ => self.step(1, out),
+        }
+    }
+
+    fn location(&self) -> String {
+        let ip = self.interp.ip();
+        let ptr = self.interp.mem_ptr();
+        let cell = self.interp.tape()[ptr];
+        match self.prog.ops.get(ip) {
+            Some(op) => {
+                let (line, col) = self.prog.line_col(ip);
+                format!("op {} at {}:{}: {}    ptr={} [{}]={}", ip, line, col, op, ptr, ptr, cell)
+            },
+            None => format!("end of program    ptr={} [{}]={}", ptr, ptr, cell),
+        }
+    }
+
+    fn show_stop(&self, stop: &Stop, out: &mut dyn Write) -> io::Result<()> {
+        match *stop {
+            Stop::Step => {},
+            Stop::Breakpoint(op) => {
+                let n = self.breakpoints.iter().position(|&b| b == op).unwrap_or(0);
+                writeln!(out, "Breakpoint {} hit", n)?;
+            },
+            Stop::Watchpoint{cell, old, new} => writeln!(out, "Cell {} changed: {} -> {}", cell, old, new)?,
+            Stop::Finished => writeln!(out, "Program finished after {} ops", self.interp.steps())?,
+            Stop::NeedsInput => writeln!(out, "Program is waiting for input, use `input` or `eof`")?,
+            Stop::Error(ref err) => writeln!(out, "Error: {}", err)?,
+            Stop::LastWrite(cell) => writeln!(out, "Cell {} was last written here", cell)?,
+            Stop::HistoryStart => writeln!(out, "Reached the start of the recorded history")?,
+        }
+        writeln!(out, "{}", self.location())
+    }
+
+    fn show_tape(&self, radius: usize, out: &mut dyn Write) -> io::Result<()> {
+        let tape = self.interp.tape();
+        let ptr = self.interp.mem_ptr();
+        let first = if ptr > radius { ptr - radius } else { 0 };
+        let last = if ptr + radius < tape.len() { ptr + radius } else { tape.len() - 1 };
+        for i in first..last + 1 {
+            let marker = if i == ptr { ">" } else { " " };
+            let c = tape[i];
+            let printable = if c >= 0x20 && c < 0x7f { (c as u8 as char).to_string() } else { String::new() };
+            writeln!(out, "{} {:>6}: {:>10} {}", marker, i, c, printable)?;
+        }
+        Ok(())
+    }
+
+    fn show_source(&self, out: &mut dyn Write) -> io::Result<()> {
+        let ip = self.interp.ip();
+        if ip >= self.prog.ops.len() {
+            return writeln!(out, "end of program");
+        }
+        let (line, col) = self.prog.line_col(ip);
+        for (i, text) in self.prog.source.lines().enumerate() {
+            if i + 3 >= line && i <= line + 1 {
+                writeln!(out, "{:>5} | {}", i + 1, text)?;
+                if i + 1 == line {
+                    writeln!(out, "      | {}^", " ".repeat(col - 1))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn parse_cell(&self, arg: Option<&str>) -> Result<usize, &'static str> {
+        match arg.map(|a| a.parse::<usize>()) {
+            Some(Ok(cell)) if cell < self.interp.tape().len() => Ok(cell),
+            _ => Err("Expected a cell index"),
+        }
+    }
+
+    fn command(&mut self, line: &str, out: &mut dyn Write) -> io::Result<bool> {
+        let mut args = line.split_whitespace();
+        let cmd = match args.next() {
+            Some(cmd) => cmd,
+            None => return Ok(true),
+        };
+
+        match cmd {
+            "b" | "break" => {
+                let op = match args.next() {
+                    Some(spec) if spec.contains(':') => {
+                        let mut lc = spec.splitn(2, ':').map(|x| x.parse::<usize>());
+                        match (lc.next(), lc.next()) {
+                            (Some(Ok(line)), Some(Ok(col))) => self.op_at(line, col).ok_or("No op at that position"),
+                            _ => Err("Expected LINE:COL"),
+                        }
+                    },
+                    Some(spec) => spec.trim_start_matches('#').parse::<usize>().map_err(|_| "Expected an op index"),
+                    None => Err("Expected LINE:COL or an op index"),
+                };
+                match op.and_then(|op| self.add_breakpoint(op).map(|n| (n, op))) {
+                    Ok((n, op)) => {
+                        let (line, col) = self.prog.line_col(op);
+                        writeln!(out, "Breakpoint {} at op {} ({}:{})", n, op, line, col)?;
+                    },
+                    Err(err) => writeln!(out, "{}", err)?,
+                }
+            },
+            "d" | "delete" => {
+                match args.next().map(|a| a.parse::<usize>()) {
+                    Some(Ok(n)) if n < self.breakpoints.len() => {
+                        self.breakpoints.remove(n);
+                    },
+                    _ => writeln!(out, "No such breakpoint")?,
+                }
+            },
+            "w" | "watch" => {
+                match self.parse_cell(args.next()).and_then(|cell| self.add_watchpoint(cell).map(|_| cell)) {
+                    Ok(cell) => writeln!(out, "Watching cell {}", cell)?,
+                    Err(err) => writeln!(out, "{}", err)?,
+                }
+            },
+            "u" | "unwatch" => {
+                match self.parse_cell(args.next()) {
+                    Ok(cell) => self.watchpoints.retain(|w| w.0 != cell),
+                    Err(err) => writeln!(out, "{}", err)?,
+                }
+            },
+            "i" | "info" => {
+                for (n, &op) in self.breakpoints.iter().enumerate() {
+                    let (line, col) = self.prog.line_col(op);
+                    writeln!(out, "Breakpoint {}: op {} ({}:{}) {}", n, op, line, col, self.prog.ops[op])?;
+                }
+                for &(cell, value) in &self.watchpoints {
+                    writeln!(out, "Watchpoint: cell {} = {}", cell, value)?;
+                }
+                let steps = self.interp.steps();
+                writeln!(out, "History: {} ops back to step {}", steps - self.history.start(steps), self.history.start(steps))?;
+            },
+            "s" | "step" => {
+                let n = args.next().and_then(|a| a.parse::<usize>().ok()).unwrap_or(1);
+                let stop = self.step(n, out);
+                self.show_stop(&stop, out)?;
+            },
+            "n" | "next" => {
+                let stop = self.step_over(out);
+                self.show_stop(&stop, out)?;
+            },
+            "c" | "continue" => {
+                let stop = self.cont(out);
+                self.show_stop(&stop, out)?;
+            },
+            "bs" | "back" => {
+                let n = args.next().and_then(|a| a.parse::<usize>().ok()).unwrap_or(1);
+                let stop = self.step_back(n);
+                self.show_stop(&stop, out)?;
+            },
+            "rc" | "rcontinue" => {
+                let stop = self.reverse_cont();
+                self.show_stop(&stop, out)?;
+            },
+            "lw" | "last-write" => {
+                match self.parse_cell(args.next()) {
+                    Ok(cell) => {
+                        let stop = self.last_write(cell);
+                        self.show_stop(&stop, out)?;
+                    },
+                    Err(err) => writeln!(out, "{}", err)?,
+                }
+            },
+            "t" | "tape" => {
+                let radius = args.next().and_then(|a| a.parse::<usize>().ok()).unwrap_or(8);
+                self.show_tape(radius, out)?;
+            },
+            "p" | "print" => {
+                match self.parse_cell(args.next()) {
+                    Ok(cell) => writeln!(out, "[{}]={}", cell, self.interp.tape()[cell])?,
+                    Err(err) => writeln!(out, "{}", err)?,
+                }
+            },
+            "set" => {
+                let cell = self.parse_cell(args.next());
+                match (cell, args.next().map(|a| a.parse::<u32>())) {
+                    (Ok(cell), Some(Ok(value))) => {
+                        self.interp.set_cell(cell, value);
+                        self.history.clear_steps();
+                        //writes by the user shouldn't trip watchpoints
+                        for w in self.watchpoints.iter_mut().filter(|w| w.0 == cell) {
+                            w.1 = self.interp.tape()[cell];
+                        }
+                    },
+                    _ => writeln!(out, "Expected CELL VALUE")?,
+                }
+            },
+            "l" | "list" => self.show_source(out)?,
+            "input" => {
+                let text = line.trim_start()["input".len()..].trim_start().trim_end_matches(&['\r', '\n'][..]);
+                self.input.feed(text.as_bytes());
+                self.input.feed(b"\n");
+            },
+            "eof" => self.input.close(),
+            "r" | "restart" => {
+                self.restart();
+                writeln!(out, "{}", self.location())?;
+            },
+            "h" | "help" => writeln!(out, "{}", HELP)?,
+            "q" | "quit" => return Ok(false),
+            _ => writeln!(out, "Unknown command `{}`, try `help`", cmd)?,
+        }
+        Ok(true)
+    }
+
+    pub fn run_commands(&mut self, commands: &mut dyn BufRead, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "{}", self.location())?;
+        loop {
+            write!(out, "(bfdb) ")?;
+            out.flush()?;
+            let mut line = String::new();
+            if commands.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            if !self.command(&line, out)? {
+                return Ok(());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    //cell 0 is moved to cell 1 and printed: [>+<-] are ops 1 to 6
+    const MOVE: &'static str = "++[>+<-]>.";
+
+    fn debugger(source: &str) -> Debugger {
+        Debugger::new(Program::parse(source).unwrap(), &Config::new())
+    }
+
+    fn command(d: &mut Debugger, line: &str) -> String {
+        let mut out = Vec::new();
+        assert!(d.command(line, &mut out).unwrap());
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn stops_at_breakpoints() {
+        let mut d = debugger(MOVE);
+        assert_eq!(command(&mut d, "b 1:5"), "Breakpoint 0 at op 3 (1:5)\n");
+        let mut out = Vec::new();
+        assert_eq!(d.cont(&mut out), Stop::Breakpoint(3));
+        assert_eq!(d.interpreter().tape()[..2], [2, 0]);
+        assert_eq!(d.cont(&mut out), Stop::Breakpoint(3));
+        assert_eq!(d.interpreter().tape()[..2], [1, 1]);
+        command(&mut d, "delete 0");
+        assert_eq!(d.cont(&mut out), Stop::Finished);
+        assert_eq!(out, vec![2]);
+    }
+
+    #[test]
+    fn steps_ops_and_over_loops() {
+        let mut d = debugger(MOVE);
+        let mut out = Vec::new();
+        assert_eq!(d.step(1, &mut out), Stop::Step);
+        assert_eq!(d.interpreter().ip(), 1);
+        assert_eq!(d.step_over(&mut out), Stop::Step);
+        assert_eq!(d.interpreter().ip(), 7);
+        assert_eq!(d.interpreter().tape()[..2], [0, 2]);
+        assert_eq!(d.interpreter().steps(), 12);
+        assert_eq!(d.step(5, &mut out), Stop::Finished);
+        assert_eq!(d.interpreter().steps(), 14);
+        assert_eq!(out, vec![2]);
+    }
+
+    #[test]
+    fn stops_when_a_watched_cell_changes() {
+        let mut d = debugger(MOVE);
+        assert_eq!(command(&mut d, "watch 1"), "Watching cell 1\n");
+        let mut out = Vec::new();
+        assert_eq!(d.cont(&mut out), Stop::Watchpoint{cell: 1, old: 0, new: 1});
+        assert_eq!(d.interpreter().ip(), 4);
+        assert_eq!(d.cont(&mut out), Stop::Watchpoint{cell: 1, old: 1, new: 2});
+        assert_eq!(d.cont(&mut out), Stop::Finished);
+    }
+
+    #[test]
+    fn steps_back() {
+        let mut d = debugger(MOVE);
+        let mut out = Vec::new();
+        d.step(4, &mut out);
+        assert_eq!(d.interpreter().tape()[..2], [2, 1]);
+        assert_eq!(d.step_back(2), Stop::Step);
+        assert_eq!(d.interpreter().ip(), 2);
+        assert_eq!((d.interpreter().mem_ptr(), d.interpreter().tape()[..2].to_vec()), (0, vec![2, 0]));
+        assert_eq!(d.step_back(5), Stop::HistoryStart);
+        assert_eq!(d.interpreter().ip(), 0);
+        assert_eq!(d.cont(&mut out), Stop::Finished);
+        assert_eq!(out, vec![2]);
+    }
+
+    #[test]
+    fn runs_back_to_breakpoints_and_writes() {
+        let mut d = debugger(MOVE);
+        let mut out = Vec::new();
+        d.add_breakpoint(4).unwrap();
+        d.add_breakpoint(7).unwrap();
+        assert_eq!(d.cont(&mut out), Stop::Breakpoint(4));
+        assert_eq!(d.cont(&mut out), Stop::Breakpoint(4));
+        assert_eq!(d.cont(&mut out), Stop::Breakpoint(7));
+        assert_eq!(d.reverse_cont(), Stop::Breakpoint(4));
+        assert_eq!(d.interpreter().tape()[..2], [1, 2]);
+
+        d.step(2, &mut out);
+        assert_eq!(d.interpreter().tape()[..2], [0, 2]);
+        assert_eq!(d.last_write(1), Stop::LastWrite(1));
+        assert_eq!(d.interpreter().ip(), 3);
+        assert_eq!(d.interpreter().tape()[..2], [1, 1]);
+        assert_eq!(d.last_write(0), Stop::LastWrite(0));
+        assert_eq!(d.interpreter().ip(), 5);
+        assert_eq!(d.interpreter().tape()[..2], [2, 1]);
+    }
+
+    #[test]
+    fn steps_back_over_input_read_after_a_set() {
+        let mut d = debugger(",.,.");
+        d.input.feed(b"ab");
+        let mut out = Vec::new();
+        d.step(2, &mut out);
+        command(&mut d, "set 1 7");
+        d.step(1, &mut out);
+        assert_eq!(d.step_back(1), Stop::Step);
+        assert_eq!(d.step_back(1), Stop::HistoryStart);
+        assert_eq!(d.cont(&mut out), Stop::Finished);
+        assert_eq!(out, b"ab".to_vec());
+        assert_eq!(d.interpreter().tape()[..2], [98, 7]);
+    }
+
+    #[test]
+    fn set_writes_a_cell_without_tripping_watchpoints() {
+        let mut d = debugger(MOVE);
+        let mut out = Vec::new();
+        d.step(1, &mut out);
+        command(&mut d, "watch 0");
+        assert_eq!(command(&mut d, "set 0 5"), "");
+        assert_eq!(command(&mut d, "print 0"), "[0]=5\n");
+        command(&mut d, "unwatch 0");
+        assert_eq!(d.cont(&mut out), Stop::Finished);
+        assert_eq!(out, vec![5]);
+    }
+}
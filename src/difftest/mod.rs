@@ -0,0 +1,317 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::Path;
+
+use bf::Program;
+use exec::{self, CellWidth, Config, EngineKind, EofPolicy, ExecError};
+
+
+//Runs every program through all engines and checks they end the same way.
+//An optimization is only correct if this stays quiet.
+
+//Engines compared, the naive interpreter is the reference
+pub const ENGINES: [EngineKind; 6] = [EngineKind::Naive, EngineKind::Optimized, EngineKind::Bytecode,
+                                      EngineKind::Threaded, EngineKind::Jit, EngineKind::Tiered];
+
+//Ops a case may run before it counts as inconclusive. Engines count steps
+//differently, so runs cut short by the budget can't be compared.
+pub const FUEL: u64 = 1_000_000;
+
+
+pub struct Case {
+    pub name: String,
+    pub source: String,
+    pub input: Vec<u8>,
+}
+
+impl Case {
+    pub fn new(name: &str, source: &str, input: &[u8]) -> Case {
+        Case {name: name.to_string(), source: source.to_string(), input: input.to_vec()}
+    }
+}
+
+//Small programs covering each op, the loop idioms and the error paths
+pub fn builtin_corpus() -> Vec<Case> {
+    vec![
+        Case::new("hello", "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.", b""),
+        Case::new("cat", ",[.,]", b"Hello, World!\n"),
+        Case::new("read past eof", ",.,.,+.", b"a"),
+        Case::new("wrap down", "-.>--.", b""),
+        Case::new("wrap up", "-+>++++++++[<++++++++>-]<[>++++<-]>.", b""),
+        Case::new("clear", "+++++[-]+.>-[+]-.", b""),
+        Case::new("linear loops", "++++[->++>+++<<]>.>.<<+++[->>-<<]>>.", b""),
+        Case::new("nested loops", "++[>++[>++<-]<-]>>.", b""),
+        Case::new("scan right", "+>+>+>>+<<<<[>]+<[<]>.>.>.>.", b""),
+        Case::new("scan left", ">>>>+<+<+<+[<]>.", b""),
+        Case::new("comments", "[skip . and , here]+.this is ignored>+.", b""),
+        Case::new("echo upper", ",[>++++[<-------->-]<.,]", b"hello"),
+        Case::new("pointer below zero", "+.<.", b""),
+        Case::new("pointer below zero in loop", "+[<]", b""),
+        Case::new("pointer past the end", "+[>+]", b""),
+        Case::new("moves back in range", ">+<<<>>>.", b""),
+        Case::new("endless", "+[]", b""),
+    ]
+}
+
+//Every *.b and *.bf file in `dir`, with the contents of NAME.in as input
+pub fn load_corpus<P: AsRef<Path>>(dir: P) -> io::Result<Vec<Case>> {
+    let mut cases = vec![];
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("b") | Some("bf") => {},
+            _ => continue,
+        }
+        let mut source = String::new();
+        File::open(&path)?.read_to_string(&mut source)?;
+        let mut input = vec![];
+        if let Ok(mut f) = File::open(path.with_extension("in")) {
+            f.read_to_end(&mut input)?;
+        }
+        let name = path.file_name().unwrap().to_string_lossy().into_owned();
+        cases.push(Case {name: name, source: source, input: input});
+    }
+    cases.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(cases)
+}
+
+//Every cell width and EOF policy, on a small tape so running off it is cheap
+pub fn configs() -> Vec<Config> {
+    let mut configs = vec![];
+    for &width in &[CellWidth::U8, CellWidth::U16, CellWidth::U32] {
+        for &eof in &[EofPolicy::Zero, EofPolicy::MinusOne, EofPolicy::Unchanged] {
+            let mut config = Config::new();
+            config.tape_size = 4096;
+            config.cell_width = width;
+            config.eof = eof;
+            config.limits.fuel = Some(FUEL);
+            configs.push(config);
+        }
+    }
+    configs
+}
+
+
+//What a run is compared on. Errors only by kind, the op index they report
+//differs between the engines' forms of the program.
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct Outcome {
+    pub output: Vec<u8>,
+    pub tape: Vec<u32>,
+    pub mem_ptr: usize,
+    pub error: Option<&'static str>,
+}
+
+fn error_kind(err: &ExecError) -> &'static str {
+    match *err {
+        ExecError::Parse(_)                => "parse error",
+        ExecError::PointerOutOfRange{..}   => "pointer out of range",
+        ExecError::Io(_)                   => "I/O error",
+        ExecError::Jit(_)                  => "JIT error",
+        ExecError::BudgetExhausted{..}     => "budget exhausted",
+        ExecError::Cancelled{..}           => "cancelled",
+        ExecError::Snapshot(_)             => "snapshot error",
+        ExecError::NeedsInput              => "input exhausted",
+        ExecError::Config(_)               => "invalid config",
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        //the end of the tape is usually zero, show up to the last used cell
+        let used = self.tape.iter().rposition(|&c| c != 0).map_or(0, |i| i + 1).max(self.mem_ptr + 1).min(self.tape.len());
+        write!(f, "output {:?}, pointer {}, tape {:?}", String::from_utf8_lossy(&self.output), self.mem_ptr, &self.tape[..used])?;
+        if let Some(err) = self.error {
+            write!(f, ", {}", err)?;
+        }
+        Ok(())
+    }
+}
+
+pub enum Verdict {
+    Agree,
+    //some engine ran out of fuel
+    Inconclusive,
+    Disagree(Vec<(EngineKind, Outcome)>),
+}
+
+fn off_tape(outcomes: &[(EngineKind, Outcome)]) -> bool {
+    outcomes.first().map_or(false, |o| o.1.error == Some("pointer out of range"))
+}
+
+//Runs one program on every engine, the reference first. Engines that aren't
+//available here, like the JIT failing to get executable memory, are left out.
+pub fn compare(prog: &Program, input: &[u8], config: &Config) -> Verdict {
+    let mut outcomes: Vec<(EngineKind, Outcome)> = vec![];
+    for &engine in &ENGINES {
+        let mut config = config.clone();
+        config.engine = engine;
+        let r = exec::run(prog, input, &config);
+        let error = r.error.as_ref().map(error_kind);
+        match r.error {
+            Some(ExecError::Jit(_)) => continue,
+            Some(ExecError::BudgetExhausted{..}) => return Verdict::Inconclusive,
+            _ => {},
+        }
+        outcomes.push((engine, Outcome {output: r.output, tape: r.tape, mem_ptr: r.mem_ptr, error: error}));
+    }
+
+    //The naive interpreter checks every move, the others only where a run of
+    //moves ends. When it left the tape the others are compared among themselves.
+    let compared = if off_tape(&outcomes) && outcomes.len() > 1 { &outcomes[1..] } else { &outcomes[..] };
+    if compared.iter().all(|o| o.1 == compared[0].1) {
+        Verdict::Agree
+    }else{
+        Verdict::Disagree(outcomes)
+    }
+}
+
+fn disagrees(source: &str, input: &[u8], config: &Config) -> bool {
+    match Program::parse_dialect(source, config.dialect) {
+        Ok(prog) => match compare(&prog, input, config) {
+            Verdict::Disagree(_) => true,
+            _ => false,
+        },
+        Err(_) => false,
+    }
+}
+
+//Removes chunks of `items` as long as `fails` keeps holding, halving the
+//chunk size whenever no chunk can go
+pub fn shrink<T: Clone, F: Fn(&[T]) -> bool>(items: Vec<T>, fails: F) -> Vec<T> {
+    let mut items = items;
+    let mut chunk = items.len() / 2;
+    while chunk > 0 {
+        let mut start = 0;
+        let mut progress = false;
+        while start < items.len() {
+            let end = (start + chunk).min(items.len());
+            let mut candidate = items[..start].to_vec();
+            candidate.extend_from_slice(&items[end..]);
+            if fails(&candidate) {
+                items = candidate;
+                progress = true;
+            }else{
+                start += chunk;
+            }
+        }
+        if !progress {
+            chunk /= 2;
+        }
+    }
+    items
+}
+
+//Smallest source and input found that still make the engines disagree.
+//Comments go first, then chunks of the program, then of the input.
+pub fn minimize(source: &str, input: &[u8], config: &Config) -> (String, Vec<u8>) {
+    let mut commands: Vec<char> = "+-<>[].,".chars().collect();
+    if config.dialect.debug_dump {
+        commands.push('#');
+    }
+    if config.dialect.input_separator {
+        commands.push('!');
+    }
+
+    //the embedded input isn't code, keep it as it is
+    let (code, embedded) = match source.find('!') {
+        Some(i) if config.dialect.input_separator => (&source[..i], &source[i..]),
+        _ => (source, ""),
+    };
+    let mut chars: Vec<char> = code.chars().filter(|c| commands.contains(c)).collect();
+    if !disagrees(&(chars.iter().collect::<String>() + embedded), input, config) {
+        chars = code.chars().collect();
+    }
+
+    let chars = shrink(chars, |c| disagrees(&(c.iter().collect::<String>() + embedded), input, config));
+    let source = chars.iter().collect::<String>() + embedded;
+    let input = shrink(input.to_vec(), |i| disagrees(&source, i, config));
+    (source, input)
+}
+
+
+pub struct Mismatch {
+    pub case: String,
+    pub config: Config,
+    pub source: String,
+    pub input: Vec<u8>,
+    pub outcomes: Vec<(EngineKind, Outcome)>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}: engines disagree ({:?} cells, {:?} at EOF, tape of {})",
+                 self.case, self.config.cell_width, self.config.eof, self.config.tape_size)?;
+        writeln!(f, "    source: {}", self.source)?;
+        writeln!(f, "    input:  {:?}", String::from_utf8_lossy(&self.input))?;
+        for &(engine, ref outcome) in &self.outcomes {
+            writeln!(f, "    {:<10} {}", format!("{:?}", engine), outcome)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Report {
+    pub runs: usize,
+    pub inconclusive: usize,
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} runs, {} inconclusive, {} mismatches", self.runs, self.inconclusive, self.mismatches.len())?;
+        for m in &self.mismatches {
+            write!(f, "{}", m)?;
+        }
+        Ok(())
+    }
+}
+
+//Every case under every config, mismatches come with a minimized repro
+pub fn run(cases: &[Case], configs: &[Config]) -> Report {
+    let mut report = Report {runs: 0, inconclusive: 0, mismatches: vec![]};
+    for case in cases {
+        for config in configs {
+            let prog = match Program::parse_dialect(&case.source, config.dialect) {
+                Ok(prog) => prog,
+                Err(_) => continue,
+            };
+            report.runs += 1;
+            match compare(&prog, &case.input, config) {
+                Verdict::Agree => {},
+                Verdict::Inconclusive => report.inconclusive += 1,
+                Verdict::Disagree(outcomes) => {
+                    //an engine that only sometimes disagrees may not do so on
+                    //the repro, then the case is reported as it is
+                    let (source, input) = minimize(&case.source, &case.input, config);
+                    let repro = Program::parse_dialect(&source, config.dialect).ok().map(|prog| compare(&prog, &input, config));
+                    let mismatch = match repro {
+                        Some(Verdict::Disagree(repro)) => Mismatch {case: case.name.clone(), config: config.clone(),
+                                                                    source: source, input: input, outcomes: repro},
+                        _ => Mismatch {case: case.name.clone(), config: config.clone(),
+                                       source: case.source.clone(), input: case.input.clone(), outcomes: outcomes},
+                    };
+                    report.mismatches.push(mismatch);
+                },
+            }
+        }
+    }
+    report
+}
+
+
+#[cfg(test)]
+mod tests {
+    use exec::Config;
+    use fuzz::Generator;
+    use super::*;
+
+    #[test]
+    fn engines_agree() {
+        let mut cases = builtin_corpus();
+        cases.extend(Generator::new(1).corpus(50, &Config::new()));
+        let report = run(&cases, &configs());
+        assert!(report.mismatches.is_empty(), "{}", report);
+    }
+}
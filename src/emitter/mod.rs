@@ -4,6 +4,8 @@ extern crate byteorder;
 
 use CodeBuff;
 
+pub mod text;
+
 pub mod x64 {
 
     #[derive(Eq, PartialEq, Debug, Copy, Clone)]
@@ -26,6 +28,7 @@ pub mod x64 {
         R15 = 15,
     }
 
+    #[derive(Eq, PartialEq, Debug, Copy, Clone)]
     pub enum Jmp{
       JO   = 0x0,
       JNO  = 0x1,
@@ -81,24 +84,44 @@ pub mod x64 {
 
     #[derive(Eq, PartialEq, Debug, Copy, Clone)]
     pub enum Opcode{
+        Add,
+        And,
+        Call,
         Cmp,
         Dec,
         Inc,
+        Jcc(Jmp),
+        Jmp,
         Mov,
+        Movsxd,
+        Pop,
+        Push,
         Ret,
+        Sub,
+        Syscall,
+        Test,
 
 
     }
 
+    #[derive(Eq, PartialEq, Debug, Copy, Clone)]
     pub enum Operand {
         None,
         Register(Register),
         Imm8(u8),
         Imm32(u32),
+        Rel32(i32),
         Reg64Imm32{r:Reg64, i:u32},
+        Reg64Imm64{r:Reg64, i:u64},
         Reg64Reg64{d:Reg64, s:Reg64},
+        Reg64Mem64{d:Reg64, s:Reg64, o:i32},
+        Mem64Reg64{d:Reg64, o:i32, s:Reg64},
+        Mem64Imm32{d:Reg64, o:i32, i:u32},
+        Reg32Mem32{d:Reg64, s:Reg64, o:i32},
+        Mem32Reg32{d:Reg64, o:i32, s:Reg64},
         BytePtr(Reg64),
-        BytePtrImm8{d:Reg64, s:u8}
+        BytePtrImm8{d:Reg64, s:u8},
+        DwordPtrImm32{d:Reg64, o:i32, i:u32},
     }
 
 }
@@ -130,11 +153,12 @@ impl Emitter{
         match oprnd {
             Operand::BytePtrImm8{ d:r, s: imm8} => {
                 let b = (r as u8 >> 3) & 0x1;
-                let reg = r as u8 & 07;
 
 
 
-                let mut temp = vec![0x80u8, Emitter::ModRM(0b11, reg, 0), imm8];
+                let mut temp = vec![0x80u8];
+                temp.append(&mut Emitter::mem_operand(7, r, 0));
+                temp.push(imm8);
                 if  b == 1 {
                     temp.insert(0, Emitter::REX(false, false, false, true));
                 }
@@ -142,9 +166,12 @@ impl Emitter{
 
                 Ok(temp)
             },
-            _ => {
-                Err("Unimplemented")
-            }
+            Operand::Reg64Mem64{d, s, o} => {
+                let mut temp = vec![Emitter::REX(true, (d as u8 >> 3) & 0x1 == 1, false, (s as u8 >> 3) & 0x1 == 1), 0x3b];
+                temp.append(&mut Emitter::mem_operand(d as u8, s, o));
+                Ok(temp)
+            },
+            o => Emitter::emit_alu(o, 7),
 
         }
 
@@ -161,7 +188,6 @@ impl Emitter{
                         let temp = r64;
                         let rm:u8 = (temp as u8) & 0x7;
                         let b:u8  = ((temp as u8) >> 3) & 0x1;
-                        println!("{:02x} {:02x} {:02x}", Emitter::REX(true, false, false, b == 1), 0xff, Emitter::ModRM(0b11, reg, rm));
                         return Ok(vec![Emitter::REX(true, false, false, b == 1), 0xff, Emitter::ModRM(0b11, reg, rm)]);
                     },
 
@@ -171,14 +197,13 @@ impl Emitter{
 
             Operand::BytePtr(r) => {
                 let  b:u8 = (r as u8 >> 3) & 0x1;
-                let rm:u8 = (r as u8 & 0x7);
 
+                let mut temp = vec![0xfe];
+                temp.append(&mut Emitter::mem_operand(reg, r, 0));
                 if b == 1 {
-                    return Ok(vec![Emitter::REX(false, false, false, false), 0xfe, Emitter::ModRM(0,reg,rm)]);
-
-                }else{
-                    return Ok(vec![0xfe, Emitter::ModRM(0,reg,rm)]);
+                    temp.insert(0, Emitter::REX(false, false, false, true));
                 }
+                return Ok(temp);
 
 
             },
@@ -199,7 +224,6 @@ impl Emitter{
                 let temp = r;
                 let rm:u8 = (temp as u8) & 0x7;
                 let b:u8  = ((temp as u8) >> 3) & 0x1;
-                println!("{:02x} {:02x} {:02x}", Emitter::REX(true, false, false, b == 1), 0xff, Emitter::ModRM(0b11, 0, rm));
                 let mut temp_vec = vec![Emitter::REX(true, false, false, b == 1), 0xc7, Emitter::ModRM(0b11, 0, rm)];
                 let mut le = vec![];
                 le.write_u32::<LittleEndian>(i).unwrap();
@@ -213,8 +237,68 @@ impl Emitter{
                 let b:u8  = ((d as u8) >> 3) & 0x1;
                 let reg:u8 = (s as u8) & 0x7;
                 let r:u8 = ((s as u8) >> 3) & 0x1;
-                println!("{:02x} {:02x} {:02x}", Emitter::REX(true, r == 1, false, b == 1), 0x89, Emitter::ModRM(0b11, reg, rm));
-                let mut temp_vec = vec![Emitter::REX(true, false, r == 1, b == 1), 0x89, Emitter::ModRM(0b11, reg, rm)];
+                let temp_vec = vec![Emitter::REX(true, r == 1, false, b == 1), 0x89, Emitter::ModRM(0b11, reg, rm)];
+                return Ok(temp_vec);
+            },
+
+            Operand::Reg64Imm64{r,i} => {
+                let b:u8  = ((r as u8) >> 3) & 0x1;
+                let mut temp_vec = vec![Emitter::REX(true, false, false, b == 1), 0xb8 + (r as u8 & 0x7)];
+                temp_vec.write_u64::<LittleEndian>(i).unwrap();
+                return Ok(temp_vec);
+            },
+
+            Operand::Reg64Mem64{d,s,o} => {
+                let mut temp_vec = vec![Emitter::REX(true, (d as u8 >> 3) & 0x1 == 1, false, (s as u8 >> 3) & 0x1 == 1), 0x8b];
+                temp_vec.append(&mut Emitter::mem_operand(d as u8, s, o));
+                return Ok(temp_vec);
+            },
+
+            Operand::Mem64Reg64{d,o,s} => {
+                let mut temp_vec = vec![Emitter::REX(true, (s as u8 >> 3) & 0x1 == 1, false, (d as u8 >> 3) & 0x1 == 1), 0x89];
+                temp_vec.append(&mut Emitter::mem_operand(s as u8, d, o));
+                return Ok(temp_vec);
+            },
+
+            Operand::Mem64Imm32{d,o,i} => {
+                let mut temp_vec = vec![Emitter::REX(true, false, false, (d as u8 >> 3) & 0x1 == 1), 0xc7];
+                temp_vec.append(&mut Emitter::mem_operand(0, d, o));
+                temp_vec.write_u32::<LittleEndian>(i).unwrap();
+                return Ok(temp_vec);
+            },
+
+            Operand::Reg32Mem32{d,s,o} => {
+                let r = (d as u8 >> 3) & 0x1 == 1;
+                let b = (s as u8 >> 3) & 0x1 == 1;
+                let mut temp_vec = vec![];
+                if r || b {
+                    temp_vec.push(Emitter::REX(false, r, false, b));
+                }
+                temp_vec.push(0x8b);
+                temp_vec.append(&mut Emitter::mem_operand(d as u8, s, o));
+                return Ok(temp_vec);
+            },
+
+            Operand::Mem32Reg32{d,o,s} => {
+                let r = (s as u8 >> 3) & 0x1 == 1;
+                let b = (d as u8 >> 3) & 0x1 == 1;
+                let mut temp_vec = vec![];
+                if r || b {
+                    temp_vec.push(Emitter::REX(false, r, false, b));
+                }
+                temp_vec.push(0x89);
+                temp_vec.append(&mut Emitter::mem_operand(s as u8, d, o));
+                return Ok(temp_vec);
+            },
+
+            Operand::DwordPtrImm32{d,o,i} => {
+                let mut temp_vec = vec![];
+                if (d as u8 >> 3) & 0x1 == 1 {
+                    temp_vec.push(Emitter::REX(false, false, false, true));
+                }
+                temp_vec.push(0xc7);
+                temp_vec.append(&mut Emitter::mem_operand(0, d, o));
+                temp_vec.write_u32::<LittleEndian>(i).unwrap();
                 return Ok(temp_vec);
             },
             _ => {
@@ -236,6 +320,169 @@ impl Emitter{
         }
     }
 
+    #[cfg(unix)]
+    pub fn ArgReg(i: u8) -> x64::Reg64 {
+        match i {
+            0 => x64::Reg64::Rdi,
+            1 => x64::Reg64::Rsi,
+            2 => x64::Reg64::Rdx,
+            3 => x64::Reg64::Rcx,
+            4 => x64::Reg64::R8,
+            5 => x64::Reg64::R9,
+
+            _ => unreachable!(),
+        }
+    }
+
+    //ModRM (plus SIB and displacement) for a [base + disp] memory operand
+    pub fn mem_operand(reg:u8, base:x64::Reg64, disp:i32) -> Vec<u8> {
+        use self::byteorder::{LittleEndian, WriteBytesExt};
+        let rm = base as u8 & 0x7;
+        let mut temp = vec![];
+
+        //rbp and r13 have no disp-less form, so they always get a disp8
+        let m:u8 = if disp == 0 && rm != 5 {
+            0b00
+        }else if disp >= -128 && disp <= 127 {
+            0b01
+        }else{
+            0b10
+        };
+
+        temp.push(Emitter::ModRM(m, reg, rm));
+
+        //rsp and r12 need a SIB byte
+        if rm == 4 {
+            temp.push(0x24);
+        }
+
+        match m {
+            0b01 => temp.push(disp as i8 as u8),
+            0b10 => temp.write_i32::<LittleEndian>(disp).unwrap(),
+            _ => {},
+        }
+        temp
+    }
+
+    //add, and, sub and cmp all share the 0x81 /ext encoding
+    pub fn emit_alu(oprnd: x64::Operand, ext: u8) -> Result<Vec<u8>,&'static str>{
+        use self::x64::Operand;
+        use self::byteorder::{LittleEndian, WriteBytesExt};
+        match oprnd {
+            Operand::Reg64Imm32{r,i} => {
+                let b:u8  = ((r as u8) >> 3) & 0x1;
+                let mut temp_vec = vec![Emitter::REX(true, false, false, b == 1)];
+                if (i as i32) >= -128 && (i as i32) <= 127 {
+                    temp_vec.push(0x83);
+                    temp_vec.push(Emitter::ModRM(0b11, ext, r as u8 & 0x7));
+                    temp_vec.push(i as u8);
+                }else if r == x64::Reg64::Rax {
+                    //rax has a shorter form without ModRM, the one assemblers pick
+                    temp_vec.push(0x05 | ext << 3);
+                    temp_vec.write_u32::<LittleEndian>(i).unwrap();
+                }else{
+                    temp_vec.push(0x81);
+                    temp_vec.push(Emitter::ModRM(0b11, ext, r as u8 & 0x7));
+                    temp_vec.write_u32::<LittleEndian>(i).unwrap();
+                }
+                Ok(temp_vec)
+            },
+
+            Operand::DwordPtrImm32{d,o,i} => {
+                let b:u8  = ((d as u8) >> 3) & 0x1;
+                let mut temp_vec = vec![];
+                if b == 1 {
+                    temp_vec.push(Emitter::REX(false, false, false, true));
+                }
+                if (i as i32) >= -128 && (i as i32) <= 127 {
+                    temp_vec.push(0x83);
+                    temp_vec.append(&mut Emitter::mem_operand(ext, d, o));
+                    temp_vec.push(i as u8);
+                }else{
+                    temp_vec.push(0x81);
+                    temp_vec.append(&mut Emitter::mem_operand(ext, d, o));
+                    temp_vec.write_u32::<LittleEndian>(i).unwrap();
+                }
+                Ok(temp_vec)
+            },
+
+            _ => {
+                Err("Unimplemented")
+            }
+        }
+    }
+
+    pub fn emit_branch(op: x64::Opcode, oprnd: x64::Operand) -> Result<Vec<u8>,&'static str>{
+        use self::x64::{Opcode, Operand, Register};
+        use self::byteorder::{LittleEndian, WriteBytesExt};
+        let mut temp_vec = match (op, oprnd) {
+            (Opcode::Jcc(cc), Operand::Rel32(_)) => vec![0x0f, 0x80 | cc as u8],
+            (Opcode::Jmp, Operand::Rel32(_)) => vec![0xe9],
+            (Opcode::Call, Operand::Rel32(_)) => vec![0xe8],
+            (Opcode::Jmp, Operand::Register(Register::Reg64(r))) |
+            (Opcode::Call, Operand::Register(Register::Reg64(r))) => {
+                let ext = if op == Opcode::Call { 2 } else { 4 };
+                let mut temp = vec![0xff, Emitter::ModRM(0b11, ext, r as u8 & 0x7)];
+                if (r as u8 >> 3) & 0x1 == 1 {
+                    temp.insert(0, Emitter::REX(false, false, false, true));
+                }
+                return Ok(temp);
+            },
+            _ => return Err("Unimplemented"),
+        };
+
+        if let Operand::Rel32(rel) = oprnd {
+            temp_vec.write_i32::<LittleEndian>(rel).unwrap();
+        }
+        Ok(temp_vec)
+    }
+
+    pub fn emit_push_pop(oprnd: x64::Operand, push: bool) -> Result<Vec<u8>,&'static str>{
+        use self::x64::{Operand, Register};
+        match oprnd {
+            Operand::Register(Register::Reg64(r)) => {
+                let base:u8 = if push { 0x50 } else { 0x58 };
+                if (r as u8 >> 3) & 0x1 == 1 {
+                    Ok(vec![Emitter::REX(false, false, false, true), base + (r as u8 & 0x7)])
+                }else{
+                    Ok(vec![base + (r as u8 & 0x7)])
+                }
+            },
+            _ => {
+                Err("Unimplemented")
+            }
+        }
+    }
+
+    //Sign extends the low 32 bits of s into d
+    pub fn emit_movsxd(oprnd: x64::Operand) -> Result<Vec<u8>,&'static str>{
+        use self::x64::Operand;
+        match oprnd {
+            Operand::Reg64Reg64{d,s} => {
+                let r = (d as u8 >> 3) & 0x1;
+                let b = (s as u8 >> 3) & 0x1;
+                Ok(vec![Emitter::REX(true, r == 1, false, b == 1), 0x63, Emitter::ModRM(0b11, d as u8 & 0x7, s as u8 & 0x7)])
+            },
+            _ => {
+                Err("Unimplemented")
+            }
+        }
+    }
+
+    pub fn emit_test(oprnd: x64::Operand) -> Result<Vec<u8>,&'static str>{
+        use self::x64::Operand;
+        match oprnd {
+            Operand::Reg64Reg64{d,s} => {
+                let b = (d as u8 >> 3) & 0x1;
+                let r = (s as u8 >> 3) & 0x1;
+                Ok(vec![Emitter::REX(true, r == 1, false, b == 1), 0x85, Emitter::ModRM(0b11, s as u8 & 0x7, d as u8 & 0x7)])
+            },
+            _ => {
+                Err("Unimplemented")
+            }
+        }
+    }
+
 
 
     pub fn REX(w:bool, r:bool, x:bool, b:bool) -> u8{
@@ -255,11 +502,21 @@ impl Emitter{
         let size:i32;
         let ret_bytes = match (op, oprnd) {
             (Ret, self::x64::Operand::None) => Ok(vec![0xc3u8]),
+            (Syscall, self::x64::Operand::None) => Ok(vec![0x0f, 0x05]),
             //(Ret,    ) => {println!("Invalid instruction.");}
             //(Ret, _)  => Err("Invalid"),
             (Inc, o) => Emitter::emit_inc_dec(o,true),
             (Dec, o) => Emitter::emit_inc_dec(o,false),
             (Mov, o) => Emitter::emit_mov(o),
+            (Movsxd, o) => Emitter::emit_movsxd(o),
+            (Add, o) => Emitter::emit_alu(o, 0),
+            (And, o) => Emitter::emit_alu(o, 4),
+            (Sub, o) => Emitter::emit_alu(o, 5),
+            (Cmp, o) => Emitter::emit_cmp(o),
+            (Test, o) => Emitter::emit_test(o),
+            (Push, o) => Emitter::emit_push_pop(o, true),
+            (Pop, o) => Emitter::emit_push_pop(o, false),
+            (Jcc(_), o) | (Jmp, o) | (Call, o) => Emitter::emit_branch(op, o),
             _ => Err("Invalid"),
 
         };
@@ -0,0 +1,176 @@
+//Assembly text for what the Emitter encodes, in a form GNU as turns back into
+//the same bytes: branches are forced to rel32 and immediates are printed the
+//way the Emitter sign extends them.
+
+use super::x64::{Jmp, Opcode, Operand, Reg64, Register};
+
+
+#[derive(Eq, PartialEq, Debug, Copy, Clone)]
+pub enum Syntax {
+    Intel,
+    Att,
+}
+
+impl Syntax {
+    //Directive selecting the syntax, for the top of a source file
+    pub fn directive(&self) -> &'static str {
+        match *self {
+            Syntax::Intel => ".intel_syntax noprefix",
+            Syntax::Att   => ".att_syntax prefix",
+        }
+    }
+}
+
+const REG64: [&'static str; 16] = ["rax", "rcx", "rdx", "rbx", "rsp", "rbp", "rsi", "rdi",
+                                   "r8", "r9", "r10", "r11", "r12", "r13", "r14", "r15"];
+const REG32: [&'static str; 16] = ["eax", "ecx", "edx", "ebx", "esp", "ebp", "esi", "edi",
+                                   "r8d", "r9d", "r10d", "r11d", "r12d", "r13d", "r14d", "r15d"];
+
+fn cond(cc: Jmp) -> &'static str {
+    match cc {
+        Jmp::JO   => "jo",
+        Jmp::JNO  => "jno",
+        Jmp::JB   => "jb",
+        Jmp::JNB  => "jnb",
+        Jmp::JZ   => "jz",
+        Jmp::JNZ  => "jnz",
+        Jmp::JBE  => "jbe",
+        Jmp::JNBE => "jnbe",
+        Jmp::JS   => "js",
+        Jmp::JNS  => "jns",
+        Jmp::JP   => "jp",
+        Jmp::JNP  => "jnp",
+        Jmp::JL   => "jl",
+        Jmp::JNL  => "jnl",
+        Jmp::JLE  => "jle",
+        Jmp::JNLE => "jnle",
+    }
+}
+
+//Operands in a syntax, with Intel operand order: destination first
+struct Fmt {
+    syntax: Syntax,
+}
+
+impl Fmt {
+    fn reg(&self, r: Reg64, wide: bool) -> String {
+        let name = if wide { REG64[r as usize] } else { REG32[r as usize] };
+        match self.syntax {
+            Syntax::Intel => name.to_string(),
+            Syntax::Att   => format!("%{}", name),
+        }
+    }
+
+    fn imm(&self, i: i64) -> String {
+        match self.syntax {
+            Syntax::Intel => i.to_string(),
+            Syntax::Att   => format!("${}", i),
+        }
+    }
+
+    fn mem(&self, size: &str, base: Reg64, disp: i32) -> String {
+        match self.syntax {
+            Syntax::Intel if disp == 0 => format!("{} ptr [{}]", size, REG64[base as usize]),
+            Syntax::Intel => format!("{} ptr [{}{:+}]", size, REG64[base as usize], disp),
+            Syntax::Att if disp == 0 => format!("(%{})", REG64[base as usize]),
+            Syntax::Att => format!("{}(%{})", disp, REG64[base as usize]),
+        }
+    }
+
+    //`mnemonic dst, src`, reversed for AT&T, which also wants a size suffix
+    fn two(&self, name: &str, suffix: &str, dst: String, src: String) -> String {
+        match self.syntax {
+            Syntax::Intel => format!("{} {}, {}", name, dst, src),
+            Syntax::Att   => format!("{}{} {}, {}", name, suffix, src, dst),
+        }
+    }
+
+    fn one(&self, name: &str, suffix: &str, x: String) -> String {
+        match self.syntax {
+            Syntax::Intel => format!("{} {}", name, x),
+            Syntax::Att   => format!("{}{} {}", name, suffix, x),
+        }
+    }
+}
+
+//One instruction as assembly text. `target` names what a Rel32 operand
+//points at, a label or a symbol, since the displacement alone means nothing
+//to an assembler.
+pub fn format(op: Opcode, oprnd: Operand, target: Option<&str>, syntax: Syntax) -> Result<String, &'static str> {
+    let f = Fmt {syntax: syntax};
+    let name = match op {
+        Opcode::Add     => "add",
+        Opcode::And     => "and",
+        Opcode::Call    => "call",
+        Opcode::Cmp     => "cmp",
+        Opcode::Dec     => "dec",
+        Opcode::Inc     => "inc",
+        Opcode::Jcc(cc) => cond(cc),
+        Opcode::Jmp     => "jmp",
+        Opcode::Mov     => "mov",
+        Opcode::Movsxd  => "movsxd",
+        Opcode::Pop     => "pop",
+        Opcode::Push    => "push",
+        Opcode::Ret     => "ret",
+        Opcode::Sub     => "sub",
+        Opcode::Syscall => "syscall",
+        Opcode::Test    => "test",
+    };
+
+    let text = match (op, oprnd) {
+        (Opcode::Ret, Operand::None) | (Opcode::Syscall, Operand::None) => name.to_string(),
+
+        (Opcode::Jcc(_), Operand::Rel32(_)) | (Opcode::Jmp, Operand::Rel32(_)) | (Opcode::Call, Operand::Rel32(_)) => {
+            match target {
+                //as would pick a rel8 whenever the target is close enough
+                Some(t) if op == Opcode::Call => format!("call {}", t),
+                Some(t) => format!("{{disp32}} {} {}", name, t),
+                None => return Err("Branch without a target"),
+            }
+        },
+        (Opcode::Jmp, Operand::Register(Register::Reg64(r))) | (Opcode::Call, Operand::Register(Register::Reg64(r))) => {
+            match syntax {
+                Syntax::Intel => format!("{} {}", name, f.reg(r, true)),
+                Syntax::Att   => format!("{} *{}", name, f.reg(r, true)),
+            }
+        },
+
+        (Opcode::Push, Operand::Register(Register::Reg64(r))) |
+        (Opcode::Pop, Operand::Register(Register::Reg64(r))) => f.one(name, "q", f.reg(r, true)),
+        (Opcode::Inc, Operand::Register(Register::Reg64(r))) |
+        (Opcode::Dec, Operand::Register(Register::Reg64(r))) => f.one(name, "q", f.reg(r, true)),
+        (Opcode::Inc, Operand::BytePtr(r)) |
+        (Opcode::Dec, Operand::BytePtr(r)) => f.one(name, "b", f.mem("byte", r, 0)),
+
+        (Opcode::Cmp, Operand::BytePtrImm8{d, s}) => f.two(name, "b", f.mem("byte", d, 0), f.imm(s as i8 as i64)),
+
+        //the 64 bit move of an immediate that doesn't sign extend from 32 bits
+        (Opcode::Mov, Operand::Reg64Imm64{r, i}) => f.two("movabs", "q", f.reg(r, true), f.imm(i as i64)),
+        (Opcode::Movsxd, Operand::Reg64Reg64{d, s}) => {
+            match syntax {
+                Syntax::Intel => format!("movsxd {}, {}", f.reg(d, true), f.reg(s, false)),
+                Syntax::Att   => format!("movslq {}, {}", f.reg(s, false), f.reg(d, true)),
+            }
+        },
+
+        (Opcode::Mov, Operand::Reg64Imm32{r, i}) | (Opcode::Add, Operand::Reg64Imm32{r, i}) |
+        (Opcode::And, Operand::Reg64Imm32{r, i}) | (Opcode::Sub, Operand::Reg64Imm32{r, i}) |
+        (Opcode::Cmp, Operand::Reg64Imm32{r, i}) => f.two(name, "q", f.reg(r, true), f.imm(i as i32 as i64)),
+        (Opcode::Mov, Operand::Reg64Reg64{d, s}) | (Opcode::Test, Operand::Reg64Reg64{d, s}) => {
+            f.two(name, "q", f.reg(d, true), f.reg(s, true))
+        },
+        (Opcode::Mov, Operand::Reg64Mem64{d, s, o}) | (Opcode::Cmp, Operand::Reg64Mem64{d, s, o}) => {
+            f.two(name, "q", f.reg(d, true), f.mem("qword", s, o))
+        },
+        (Opcode::Mov, Operand::Mem64Reg64{d, o, s}) => f.two(name, "q", f.mem("qword", d, o), f.reg(s, true)),
+        (Opcode::Mov, Operand::Mem64Imm32{d, o, i}) => f.two(name, "q", f.mem("qword", d, o), f.imm(i as i32 as i64)),
+        (Opcode::Mov, Operand::Reg32Mem32{d, s, o}) => f.two(name, "l", f.reg(d, false), f.mem("dword", s, o)),
+        (Opcode::Mov, Operand::Mem32Reg32{d, o, s}) => f.two(name, "l", f.mem("dword", d, o), f.reg(s, false)),
+        (Opcode::Mov, Operand::DwordPtrImm32{d, o, i}) | (Opcode::Add, Operand::DwordPtrImm32{d, o, i}) |
+        (Opcode::And, Operand::DwordPtrImm32{d, o, i}) | (Opcode::Sub, Operand::DwordPtrImm32{d, o, i}) |
+        (Opcode::Cmp, Operand::DwordPtrImm32{d, o, i}) => f.two(name, "l", f.mem("dword", d, o), f.imm(i as i32 as i64)),
+
+        _ => return Err("Unimplemented"),
+    };
+    Ok(text)
+}
@@ -0,0 +1,579 @@
+use std::io::{self, Read, Write};
+use std::fmt;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use bf;
+use jit;
+use tiered;
+use bytecode;
+use threaded;
+use snapshot::Snapshot;
+
+
+#[derive(Eq, PartialEq, Debug, Copy, Clone)]
+pub enum EngineKind {
+    Naive,
+    Optimized,
+    Jit,
+    Tiered,
+    Bytecode,
+    Threaded,
+}
+
+#[derive(Eq, PartialEq, Debug, Copy, Clone)]
+pub enum EofPolicy {
+    Zero,
+    MinusOne,
+    Unchanged,
+}
+
+//What the JIT does when the pointer runs into the guard pages around its tape
+#[derive(Eq, PartialEq, Debug, Copy, Clone)]
+pub enum GuardPolicy {
+    Error,
+    Grow,
+}
+
+#[derive(Eq, PartialEq, Debug, Copy, Clone)]
+pub enum CellWidth {
+    U8,
+    U16,
+    U32,
+}
+
+impl CellWidth {
+    pub fn mask(&self) -> u32 {
+        match *self {
+            CellWidth::U8  => 0xff,
+            CellWidth::U16 => 0xffff,
+            CellWidth::U32 => 0xffff_ffff,
+        }
+    }
+}
+
+
+//Optional extensions to the eight commands, both off by default
+#[derive(Eq, PartialEq, Debug, Copy, Clone)]
+pub struct Dialect {
+    //`#` dumps the pointer and the cells around it to stderr
+    pub debug_dump: bool,
+    //`!` ends the program, the rest of the source is its input
+    pub input_separator: bool,
+}
+
+impl Dialect {
+    pub fn new() -> Dialect {
+        Dialect {debug_dump: false, input_separator: false}
+    }
+}
+
+
+//Cloneable handle that stops a running engine from another thread
+#[derive(Debug, Clone)]
+pub struct CancelToken {
+    flag: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken {flag: Arc::new(AtomicBool::new(false))}
+    }
+
+    pub fn cancel(&self) {
+        self.flag.store(true, Ordering::Relaxed);
+    }
+
+    pub fn reset(&self) {
+        self.flag.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.flag.load(Ordering::Relaxed)
+    }
+}
+
+
+//Execution budget; fuel counts executed ops over the engine's lifetime,
+//the timeout applies to each call of run_io
+#[derive(Debug, Clone)]
+pub struct Limits {
+    pub fuel: Option<u64>,
+    pub timeout: Option<Duration>,
+    pub cancel: Option<CancelToken>,
+}
+
+impl Limits {
+    pub fn new() -> Limits {
+        Limits {fuel: None, timeout: None, cancel: None}
+    }
+
+    pub fn start(&self) -> Limiter {
+        Limiter {
+            fuel: self.fuel.unwrap_or(!0),
+            deadline: self.timeout.map(|t| Instant::now() + t),
+            cancel: self.cancel.clone(),
+        }
+    }
+}
+
+
+//How many ops may run between two checks of the wall clock and cancel token
+pub const POLL_INTERVAL: u64 = 1 << 16;
+
+#[derive(Eq, PartialEq, Debug, Copy, Clone)]
+pub enum Interrupt {
+    Budget,
+    Cancelled,
+}
+
+impl Interrupt {
+    pub fn to_error(&self, ip: usize, mem_ptr: usize, tape: &[u32]) -> ExecError {
+        match *self {
+            Interrupt::Budget    => ExecError::BudgetExhausted{ip: ip, mem_ptr: mem_ptr, tape: tape.to_vec()},
+            Interrupt::Cancelled => ExecError::Cancelled{ip: ip},
+        }
+    }
+}
+
+//Running state of a Limits, engines poll it whenever their step count reaches
+//the value the previous poll returned
+pub struct Limiter {
+    fuel: u64,
+    deadline: Option<Instant>,
+    cancel: Option<CancelToken>,
+}
+
+impl Limiter {
+    //Returns the step count to poll at next, or why execution has to stop
+    pub fn poll(&self, steps: u64) -> Result<u64, Interrupt> {
+        if let Some(ref cancel) = self.cancel {
+            if cancel.is_cancelled() {
+                return Err(Interrupt::Cancelled);
+            }
+        }
+        if steps >= self.fuel {
+            return Err(Interrupt::Budget);
+        }
+        if let Some(deadline) = self.deadline {
+            if Instant::now() >= deadline {
+                return Err(Interrupt::Budget);
+            }
+        }
+        Ok(if self.fuel - steps < POLL_INTERVAL { self.fuel } else { steps + POLL_INTERVAL })
+    }
+}
+
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub engine: EngineKind,
+    pub tape_size: usize,
+    pub cell_width: CellWidth,
+    pub eof: EofPolicy,
+    pub dialect: Dialect,
+    pub limits: Limits,
+    //JIT only: run on a tape between guard pages instead of checking bounds
+    pub guard: Option<GuardPolicy>,
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config {
+            engine: EngineKind::Optimized,
+            tape_size: 30000,
+            cell_width: CellWidth::U32,
+            eof: EofPolicy::Zero,
+            dialect: Dialect::new(),
+            limits: Limits::new(),
+            guard: None,
+        }
+    }
+}
+
+
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub enum ExecError {
+    Parse(&'static str),
+    PointerOutOfRange{ip: usize},
+    Io(String),
+    Jit(&'static str),
+    BudgetExhausted{ip: usize, mem_ptr: usize, tape: Vec<u32>},
+    Cancelled{ip: usize},
+    Snapshot(&'static str),
+    NeedsInput,
+    Config(&'static str),
+}
+
+impl fmt::Display for ExecError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExecError::Parse(s)                 => write!(f, "parse error: {}", s),
+            ExecError::PointerOutOfRange{ip}    => write!(f, "pointer out of range at op {}", ip),
+            ExecError::Io(ref s)                => write!(f, "I/O error: {}", s),
+            ExecError::Jit(s)                   => write!(f, "JIT error: {}", s),
+            ExecError::BudgetExhausted{ip, mem_ptr, ..} => write!(f, "budget exhausted at op {} (pointer {})", ip, mem_ptr),
+            ExecError::Cancelled{ip}            => write!(f, "cancelled at op {}", ip),
+            ExecError::Snapshot(s)              => write!(f, "snapshot error: {}", s),
+            ExecError::NeedsInput               => write!(f, "input exhausted"),
+            ExecError::Config(s)                => write!(f, "invalid config: {}", s),
+        }
+    }
+}
+
+
+//Everything an embedder gets back from a run, whether it finished or not
+#[derive(Debug, Clone)]
+pub struct ExecResult {
+    pub output: Vec<u8>,
+    pub tape: Vec<u32>,
+    pub mem_ptr: usize,
+    pub steps: u64,
+    pub error: Option<ExecError>,
+}
+
+
+//Common interface of the interpreters and the JIT
+pub trait Engine {
+    fn run_io(&mut self, input: &mut dyn Read, output: &mut dyn Write) -> Result<(), ExecError>;
+    fn tape(&self) -> &[u32];
+    fn mem_ptr(&self) -> usize;
+    fn ip(&self) -> usize;
+    fn steps(&self) -> u64;
+
+    //Hash of the program in the form the engine executes it, snapshots only
+    //restore into engines with the same hash
+    fn program_hash(&self) -> u64;
+    fn snapshot(&self) -> Snapshot;
+    fn restore(&mut self, snap: &Snapshot) -> Result<(), ExecError>;
+}
+
+
+pub fn create_engine(prog: &bf::Program, config: &Config) -> Result<Box<dyn Engine>, ExecError> {
+    //the engines all assume there is a cell under the pointer
+    if config.tape_size == 0 {
+        return Err(ExecError::Config("The tape needs at least one cell"));
+    }
+    match config.engine {
+        EngineKind::Naive => {
+            let mut e = bf::Interpreter::with_config(config);
+            e.load_program(prog);
+            Ok(Box::new(e))
+        },
+        EngineKind::Optimized => {
+            let mut e = bf::OptimizedInterpreter::with_config(config);
+            e.load_program(prog);
+            Ok(Box::new(e))
+        },
+        EngineKind::Jit => {
+            let e = jit::Jit::compile(prog, config)?;
+            Ok(Box::new(e))
+        },
+        EngineKind::Tiered => {
+            Ok(Box::new(tiered::Tiered::new(prog, config)))
+        },
+        EngineKind::Bytecode => {
+            Ok(Box::new(bytecode::Bytecode::new(prog, config)))
+        },
+        EngineKind::Threaded => {
+            Ok(Box::new(threaded::Threaded::new(prog, config)?))
+        },
+    }
+}
+
+
+//A program with input embedded after `!` runs on that instead of `input`
+pub fn run(prog: &bf::Program, input: &[u8], config: &Config) -> ExecResult {
+    let mut engine = match create_engine(prog, config) {
+        Ok(e) => e,
+        Err(err) => return ExecResult {
+            output: vec![],
+            tape: vec![0; config.tape_size],
+            mem_ptr: 0,
+            steps: 0,
+            error: Some(err),
+        },
+    };
+
+    let mut input = prog.input.as_ref().map_or(input, |i| &i[..]);
+    let mut output = Vec::<u8>::new();
+    let error = engine.run_io(&mut input, &mut output).err();
+
+    ExecResult {
+        output: output,
+        tape: engine.tape().to_vec(),
+        mem_ptr: engine.mem_ptr(),
+        steps: engine.steps(),
+        error: error,
+    }
+}
+
+
+//Continues a run from a snapshot. `input` is the complete input of the run,
+//the part the snapshot already consumed is skipped.
+pub fn resume(prog: &bf::Program, snap: &Snapshot, input: &[u8], config: &Config) -> ExecResult {
+    let mut engine = match create_engine(prog, config).and_then(|mut e| e.restore(snap).map(|_| e)) {
+        Ok(e) => e,
+        Err(err) => return ExecResult {
+            output: vec![],
+            tape: snap.tape.clone(),
+            mem_ptr: snap.mem_ptr,
+            steps: snap.steps,
+            error: Some(err),
+        },
+    };
+
+    let input = prog.input.as_ref().map_or(input, |i| &i[..]);
+    let skip = if (snap.input_pos as usize) < input.len() { snap.input_pos as usize } else { input.len() };
+    let mut input = &input[skip..];
+    let mut output = Vec::<u8>::new();
+    let error = engine.run_io(&mut input, &mut output).err();
+
+    ExecResult {
+        output: output,
+        tape: engine.tape().to_vec(),
+        mem_ptr: engine.mem_ptr(),
+        steps: engine.steps(),
+        error: error,
+    }
+}
+
+
+//Input fed piecemeal by the caller. Running dry reports WouldBlock, which makes
+//engines suspend with ExecError::NeedsInput, until close() turns it into EOF.
+pub struct InputQueue {
+    buf: VecDeque<u8>,
+    closed: bool,
+}
+
+impl InputQueue {
+    pub fn new() -> InputQueue {
+        InputQueue {buf: VecDeque::new(), closed: false}
+    }
+
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.buf.extend(bytes.iter().cloned());
+    }
+
+    pub fn close(&mut self) {
+        self.closed = true;
+    }
+
+    //Puts bytes back in front of the queue, for rewinding past reads
+    pub fn unread(&mut self, bytes: &[u8]) {
+        for b in bytes.iter().rev() {
+            self.buf.push_front(*b);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+}
+
+impl Read for InputQueue {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.buf.is_empty() {
+            if self.closed {
+                return Ok(0);
+            }
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, "Input queue is empty"));
+        }
+
+        let mut n = 0;
+        while n < buf.len() {
+            match self.buf.pop_front() {
+                Some(b) => buf[n] = b,
+                None => break,
+            }
+            n += 1;
+        }
+        Ok(n)
+    }
+}
+
+
+#[derive(Eq, PartialEq, Debug, Copy, Clone)]
+pub enum RunStatus {
+    Finished,
+    NeedsInput,
+}
+
+//Coroutine style execution for event loops: feed input as it arrives and
+//resume until the program finishes
+pub struct Session {
+    engine: Box<dyn Engine>,
+    input: InputQueue,
+}
+
+impl Session {
+    pub fn new(prog: &bf::Program, config: &Config) -> Result<Session, ExecError> {
+        let mut input = InputQueue::new();
+        if let Some(ref embedded) = prog.input {
+            input.feed(embedded);
+            input.close();
+        }
+        Ok(Session {engine: create_engine(prog, config)?, input: input})
+    }
+
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.input.feed(bytes);
+    }
+
+    pub fn close_input(&mut self) {
+        self.input.close();
+    }
+
+    pub fn resume(&mut self, output: &mut dyn Write) -> Result<RunStatus, ExecError> {
+        match self.engine.run_io(&mut self.input, output) {
+            Ok(_) => Ok(RunStatus::Finished),
+            Err(ExecError::NeedsInput) => Ok(RunStatus::NeedsInput),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn engine(&self) -> &dyn Engine {
+        &*self.engine
+    }
+}
+
+
+pub fn run_source(src: &str, input: &[u8], config: &Config) -> ExecResult {
+    match bf::Program::parse_dialect(src, config.dialect) {
+        Ok(prog) => run(&prog, input, config),
+        Err(err) => ExecResult {
+            output: vec![],
+            tape: vec![0; config.tape_size],
+            mem_ptr: 0,
+            steps: 0,
+            error: Some(ExecError::Parse(err)),
+        },
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use bf;
+    use super::*;
+
+    const ENGINES: [EngineKind; 6] = [EngineKind::Naive, EngineKind::Optimized, EngineKind::Jit,
+                                      EngineKind::Tiered, EngineKind::Bytecode, EngineKind::Threaded];
+
+    //Every engine stops at the same op, the JIT included, whichever end of
+    //the tape the pointer runs off
+    #[test]
+    fn reports_the_same_op_off_the_tape() {
+        for &(src, ip) in &[("+>+ <<-", 3), ("+[>+]", 2), ("+[->++[>+<-]>]", 6)] {
+            let prog = bf::Program::parse(src).unwrap();
+            for &kind in &ENGINES {
+                let mut config = Config::new();
+                config.engine = kind;
+                config.tape_size = 64;
+                let r = run(&prog, b"", &config);
+                assert_eq!(r.error, Some(ExecError::PointerOutOfRange{ip: ip}), "{:?} on {:?}", kind, src);
+                assert_eq!(r.tape.len(), 64);
+            }
+        }
+    }
+
+    //Steps count ops, comments and merged runs of commands don't add any
+    #[test]
+    fn counts_the_same_steps() {
+        for src in &["++ two ++[->+<]>.", "+++++[->++>>+++<<<]>[-]>>[<+>-]", ",[.,]"] {
+            let prog = bf::Program::parse(src).unwrap();
+            let mut steps = vec![];
+            for &kind in &ENGINES {
+                let mut config = Config::new();
+                config.engine = kind;
+                steps.push(run(&prog, b"ab", &config).steps);
+            }
+            assert!(steps.iter().all(|&s| s == steps[1]), "{:?} on {:?}", steps, src);
+        }
+    }
+
+    #[test]
+    fn stops_when_the_budget_runs_out() {
+        let prog = bf::Program::parse("+[>+<]").unwrap();
+        for &kind in &ENGINES {
+            let mut config = Config::new();
+            config.engine = kind;
+            config.limits.fuel = Some(1000);
+            let r = run(&prog, b"", &config);
+            match r.error {
+                Some(ExecError::BudgetExhausted{..}) => assert!(r.steps >= 1000, "{:?} stopped early", kind),
+                err => panic!("{:?} ran past its fuel: {:?}", kind, err),
+            }
+
+            let mut config = Config::new();
+            config.engine = kind;
+            config.limits.timeout = Some(Duration::from_millis(20));
+            match run(&prog, b"", &config).error {
+                Some(ExecError::BudgetExhausted{..}) => {},
+                err => panic!("{:?} ran past its timeout: {:?}", kind, err),
+            }
+        }
+    }
+
+    #[test]
+    fn stops_when_cancelled_from_another_thread() {
+        let prog = bf::Program::parse("+[]").unwrap();
+        for &kind in &ENGINES {
+            let token = CancelToken::new();
+            let mut config = Config::new();
+            config.engine = kind;
+            config.limits.cancel = Some(token.clone());
+            let canceller = ::std::thread::spawn(move || {
+                ::std::thread::sleep(Duration::from_millis(20));
+                token.cancel();
+            });
+            let r = run(&prog, b"", &config);
+            canceller.join().unwrap();
+            match r.error {
+                Some(ExecError::Cancelled{..}) => {},
+                err => panic!("{:?} was not cancelled: {:?}", kind, err),
+            }
+        }
+    }
+
+    #[test]
+    fn session_matches_a_run_with_all_input_up_front() {
+        let prog = bf::Program::parse(",.,.").unwrap();
+        let input = b"hi";
+        for &kind in &ENGINES {
+            let mut config = Config::new();
+            config.engine = kind;
+            let mut session = Session::new(&prog, &config).unwrap();
+            let mut output = Vec::new();
+            let mut fed = 0;
+            loop {
+                match session.resume(&mut output).unwrap() {
+                    RunStatus::Finished => break,
+                    RunStatus::NeedsInput => {
+                        assert!(fed < input.len(), "{:?} asked for more input than it reads", kind);
+                        session.feed(&input[fed..fed+1]);
+                        fed += 1;
+                    }
+                }
+            }
+            assert_eq!(output, run(&prog, input, &config).output, "{:?}", kind);
+            assert_eq!(output, b"hi".to_vec());
+        }
+    }
+
+    #[test]
+    fn rejects_an_empty_tape() {
+        let prog = bf::Program::parse("+++.").unwrap();
+        for &kind in &ENGINES {
+            let mut config = Config::new();
+            config.engine = kind;
+            config.tape_size = 0;
+            match run(&prog, b"", &config).error {
+                Some(ExecError::Config(_)) => {},
+                err => panic!("{:?} ran on an empty tape: {:?}", kind, err),
+            }
+        }
+    }
+}
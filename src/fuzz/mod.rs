@@ -0,0 +1,471 @@
+use std::fmt;
+use std::panic;
+
+use CodeBuff;
+use aot;
+use bf::{Opcode, Program};
+use difftest::{self, Case, Verdict};
+use emitter::{x64, Emitter};
+use emitter::text::{self, Syntax};
+use emitter::x64::{Jmp, Operand, Reg64, Register};
+use exec::{self, Config, EngineKind, ExecError};
+
+
+//Random programs and fuzz targets. Everything comes from a seed, so a failure
+//reported with its seed replays.
+
+//xorshift64*, good enough to pick commands and not worth a dependency
+pub struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        //a splitmix64 step spreads close seeds apart, and the state must
+        //never be zero
+        let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        Rng {state: (z ^ (z >> 31)).max(1)}
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    //Uniform in 0..n
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    pub fn percent(&mut self, p: u32) -> bool {
+        self.below(100) < p as usize
+    }
+
+    pub fn bytes(&mut self, n: usize) -> Vec<u8> {
+        (0..n).map(|_| self.next_u64() as u8).collect()
+    }
+}
+
+
+//Makes bracket-balanced programs that end within a step budget
+pub struct Generator {
+    pub rng: Rng,
+    //commands per block, roughly
+    pub size: usize,
+    pub max_depth: usize,
+    //percent of loops that are clear, multiply or scan loops instead of a
+    //random body
+    pub idioms: u32,
+    //steps the naive interpreter may take on a generated program
+    pub budget: u64,
+    //bytes of input that come with a case, at most
+    pub max_input: usize,
+}
+
+impl Generator {
+    pub fn new(seed: u64) -> Generator {
+        Generator {rng: Rng::new(seed), size: 24, max_depth: 3, idioms: 50, budget: 100_000, max_input: 8}
+    }
+
+    fn command(&mut self) -> char {
+        //weighted towards loops and the ops they depend on
+        match self.rng.below(18) {
+            0..=4   => '+',
+            5..=7   => '-',
+            8..=10  => '>',
+            11..=12 => '<',
+            13 => '.',
+            14 => ',',
+            _  => '[',
+        }
+    }
+
+    fn idiom(&mut self, out: &mut String) {
+        match self.rng.below(4) {
+            0 => out.push_str(if self.rng.percent(50) { "[-]" } else { "[+]" }),
+            1 => out.push_str(["[>]", "[<]", "[>>]", "[<<]"][self.rng.below(4)]),
+            _ => {
+                //[->++>+++<<] and the like, the targets on either side
+                out.push_str("[-");
+                let mut at: i32 = 0;
+                for _ in 0..1 + self.rng.below(3) {
+                    let to = self.rng.below(7) as i32 - 3;
+                    let to = if to == 0 { 1 } else { to };
+                    let moves = if to > at { ">" } else { "<" };
+                    out.push_str(&moves.repeat((to - at).abs() as usize));
+                    let add = if self.rng.percent(70) { "+" } else { "-" };
+                    out.push_str(&add.repeat(1 + self.rng.below(4)));
+                    at = to;
+                }
+                out.push_str(&(if at > 0 { "<" } else { ">" }).repeat(at.abs() as usize));
+                out.push(']');
+            },
+        }
+    }
+
+    fn block(&mut self, out: &mut String, depth: usize) {
+        let len = 1 + self.rng.below(self.size);
+        for _ in 0..len {
+            match self.command() {
+                '[' if depth < self.max_depth => {
+                    if self.rng.percent(self.idioms) {
+                        self.idiom(out);
+                    }else{
+                        //counting the cell down first makes it likely to end
+                        out.push('[');
+                        if self.rng.percent(70) {
+                            out.push('-');
+                        }
+                        self.block(out, depth + 1);
+                        out.push(']');
+                    }
+                },
+                '[' => out.push('+'),
+                c => out.push(c),
+            }
+        }
+    }
+
+    //A balanced program, which may well run forever or off the tape
+    pub fn program(&mut self) -> String {
+        let mut out = String::new();
+        self.block(&mut out, 0);
+        out
+    }
+
+    //A program with its input that ends on the tape within the budget under
+    //`config`. After enough tries the loops are dropped, which always works.
+    pub fn case(&mut self, config: &Config) -> Case {
+        let input = {
+            let n = self.rng.below(self.max_input + 1);
+            self.rng.bytes(n)
+        };
+        let mut config = config.clone();
+        config.engine = EngineKind::Naive;
+        config.limits.fuel = Some(self.budget);
+
+        for _ in 0..100 {
+            let source = self.program();
+            let prog = Program::parse(&source).unwrap();
+            match exec::run(&prog, &input, &config).error {
+                Some(ExecError::BudgetExhausted{..}) | Some(ExecError::PointerOutOfRange{..}) => {},
+                _ => return Case::new("generated", &source, &input),
+            }
+        }
+
+        let mut source = String::new();
+        let mut ptr = 0;
+        for c in self.program().chars() {
+            match c {
+                '[' | ']' => {},
+                '<' if ptr == 0 => {},
+                '<' => {ptr -= 1; source.push(c)},
+                '>' => {ptr += 1; source.push(c)},
+                c => source.push(c),
+            }
+        }
+        Case::new("generated", &source, &input)
+    }
+
+    //`count` cases under one config
+    pub fn corpus(&mut self, count: usize, config: &Config) -> Vec<Case> {
+        (0..count).map(|i| {
+            let mut case = self.case(config);
+            case.name = format!("generated {}", i);
+            case
+        }).collect()
+    }
+}
+
+
+//A failing input, shrunk as far as it keeps failing
+pub struct Failure {
+    pub input: Vec<u8>,
+    pub error: String,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", self.error)?;
+        match ::std::str::from_utf8(&self.input) {
+            Ok(s) => write!(f, "    input: {:?}", s),
+            Err(_) => write!(f, "    input: {:02x?}", self.input),
+        }
+    }
+}
+
+//Runs `target` on `iterations` inputs from `gen` and shrinks the first one it
+//rejects
+pub fn fuzz<G, F>(mut gen: G, target: F, iterations: usize) -> Option<Failure>
+    where G: FnMut() -> Vec<u8>, F: Fn(&[u8]) -> Result<(), String> {
+    for _ in 0..iterations {
+        let input = gen();
+        if target(&input).is_err() {
+            let input = difftest::shrink(input, |i| target(i).is_err());
+            let error = target(&input).err().unwrap();
+            return Some(Failure {input: input, error: error});
+        }
+    }
+    None
+}
+
+
+//Target for the loaders: any bytes as source. Program::parse, which the
+//optimized interpreter loads from, must get the bracket balance right and
+//keep the ops where the source has them, and the naive interpreter, which
+//loads from the source, must run the result like the other engines.
+pub fn parse_target(data: &[u8]) -> Result<(), String> {
+    let source = String::from_utf8_lossy(data).into_owned();
+    let parsed = {
+        let source = &source;
+        panic::catch_unwind(|| Program::parse(source)).map_err(|_| "Program::parse panicked".to_string())?
+    };
+
+    let mut depth = 0;
+    let balanced = source.chars().all(|c| {
+        match c {
+            '[' => depth += 1,
+            ']' => depth -= 1,
+            _ => {},
+        }
+        depth >= 0
+    }) && depth == 0;
+    let prog = match parsed {
+        Ok(prog) if balanced => prog,
+        Err(_) if !balanced => return Ok(()),
+        Ok(_) => return Err("Parsed unbalanced brackets".to_string()),
+        Err(err) => return Err(format!("Rejected balanced brackets: {}", err)),
+    };
+
+    if prog.positions.len() != prog.ops.len() {
+        return Err(format!("{} ops but {} positions", prog.ops.len(), prog.positions.len()));
+    }
+    for (ip, &op) in prog.ops.iter().enumerate() {
+        let c = prog.source[prog.positions[ip]..].chars().next().unwrap();
+        let fits = match op {
+            Opcode::Byte(_)      => c == '+' || c == '-',
+            Opcode::Ptr(_)       => c == '<' || c == '>',
+            Opcode::LoopEnter(x) => c == '[' && x > ip && prog.ops.get(x) == Some(&Opcode::LoopExit(ip)),
+            Opcode::LoopExit(x)  => c == ']' && x < ip && prog.ops.get(x) == Some(&Opcode::LoopEnter(ip)),
+            Opcode::Out          => c == '.',
+            Opcode::In           => c == ',',
+            Opcode::Debug        => c == '#',
+        };
+        if !fits {
+            return Err(format!("Op {} is {} but starts at {:?}", ip, op, c));
+        }
+    }
+
+    let mut config = Config::new();
+    config.tape_size = 256;
+    config.limits.fuel = Some(10_000);
+    match difftest::compare(&prog, b"input", &config) {
+        Verdict::Disagree(outcomes) => {
+            let mut err = "Engines disagree:".to_string();
+            for (engine, outcome) in outcomes {
+                err.push_str(&format!("\n    {:<10} {}", format!("{:?}", engine), outcome));
+            }
+            Err(err)
+        },
+        _ => Ok(()),
+    }
+}
+
+//Sources with a few random edits, unbalancing some brackets and adding
+//non-commands, for parse_target
+pub fn mutated_sources(seed: u64) -> Box<dyn FnMut() -> Vec<u8>> {
+    let mut gen = Generator::new(seed);
+    Box::new(move || {
+        let mut source = gen.program().into_bytes();
+        for _ in 0..gen.rng.below(4) {
+            let at = gen.rng.below(source.len() + 1);
+            match gen.rng.below(3) {
+                0 if at < source.len() => {source.remove(at);},
+                1 => source.insert(at, b"[]!#\n x"[gen.rng.below(7)]),
+                _ => source.insert(at, gen.rng.next_u64() as u8),
+            }
+        }
+        source
+    })
+}
+
+
+const OPCODES: [x64::Opcode; 16] = [x64::Opcode::Add, x64::Opcode::And, x64::Opcode::Call, x64::Opcode::Cmp,
+                                    x64::Opcode::Dec, x64::Opcode::Inc, x64::Opcode::Jcc(Jmp::JO), x64::Opcode::Jmp,
+                                    x64::Opcode::Mov, x64::Opcode::Movsxd, x64::Opcode::Pop, x64::Opcode::Push,
+                                    x64::Opcode::Ret, x64::Opcode::Sub, x64::Opcode::Syscall, x64::Opcode::Test];
+const CONDITIONS: [Jmp; 16] = [Jmp::JO, Jmp::JNO, Jmp::JB, Jmp::JNB, Jmp::JZ, Jmp::JNZ, Jmp::JBE, Jmp::JNBE,
+                               Jmp::JS, Jmp::JNS, Jmp::JP, Jmp::JNP, Jmp::JL, Jmp::JNL, Jmp::JLE, Jmp::JNLE];
+const REGS: [Reg64; 16] = [Reg64::Rax, Reg64::Rcx, Reg64::Rdx, Reg64::Rbx, Reg64::Rsp, Reg64::Rbp, Reg64::Rsi, Reg64::Rdi,
+                           Reg64::R8, Reg64::R9, Reg64::R10, Reg64::R11, Reg64::R12, Reg64::R13, Reg64::R14, Reg64::R15];
+
+//Operand forms of an op, numbered like Decoder::instruction reads them
+fn forms(op: x64::Opcode) -> &'static [u8] {
+    match op {
+        x64::Opcode::Add | x64::Opcode::And | x64::Opcode::Sub => &[5, 15],
+        x64::Opcode::Cmp => &[5, 8, 14, 15],
+        x64::Opcode::Call | x64::Opcode::Jmp => &[1, 4],
+        x64::Opcode::Jcc(_) => &[4],
+        x64::Opcode::Dec | x64::Opcode::Inc => &[1, 13],
+        x64::Opcode::Mov => &[5, 6, 7, 8, 9, 10, 11, 12, 15],
+        x64::Opcode::Movsxd | x64::Opcode::Test => &[7],
+        x64::Opcode::Pop | x64::Opcode::Push => &[1],
+        x64::Opcode::Ret | x64::Opcode::Syscall => &[0],
+    }
+}
+
+//Reads instructions out of fuzz bytes, zeros once they run out
+struct Decoder<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn u8(&mut self) -> u8 {
+        let b = self.data.get(self.pos).cloned().unwrap_or(0);
+        self.pos += 1;
+        b
+    }
+
+    fn u32(&mut self) -> u32 {
+        (0..4).fold(0, |x, i| x | (self.u8() as u32) << (8 * i))
+    }
+
+    fn reg(&mut self) -> Reg64 {
+        REGS[self.u8() as usize % 16]
+    }
+
+    //Small displacements are the interesting ones for the ModRM forms
+    fn disp(&mut self) -> i32 {
+        match self.u8() % 3 {
+            0 => 0,
+            1 => self.u8() as i8 as i32,
+            _ => self.u32() as i32,
+        }
+    }
+
+    fn imm(&mut self) -> u32 {
+        match self.u8() % 3 {
+            0 => self.u8() as i8 as i32 as u32,
+            _ => self.u32(),
+        }
+    }
+
+    fn instruction(&mut self) -> (x64::Opcode, Operand) {
+        let op = match OPCODES[self.u8() as usize % 16] {
+            x64::Opcode::Jcc(_) => x64::Opcode::Jcc(CONDITIONS[self.u8() as usize % 16]),
+            op => op,
+        };
+        //mostly forms the op has, random ones would hardly ever encode
+        let kind = self.u8();
+        let kind = if kind >= 0xf0 { kind % 16 } else { forms(op)[kind as usize % forms(op).len()] };
+        let oprnd = match kind {
+            0  => Operand::None,
+            1  => Operand::Register(Register::Reg64(self.reg())),
+            2  => Operand::Imm8(self.u8()),
+            3  => Operand::Imm32(self.u32()),
+            4  => Operand::Rel32(self.imm() as i32),
+            5  => Operand::Reg64Imm32{r: self.reg(), i: self.imm()},
+            6  => Operand::Reg64Imm64{r: self.reg(), i: self.u32() as u64 | (self.u32() as u64) << 32},
+            7  => Operand::Reg64Reg64{d: self.reg(), s: self.reg()},
+            8  => Operand::Reg64Mem64{d: self.reg(), s: self.reg(), o: self.disp()},
+            9  => Operand::Mem64Reg64{d: self.reg(), o: self.disp(), s: self.reg()},
+            10 => Operand::Mem64Imm32{d: self.reg(), o: self.disp(), i: self.imm()},
+            11 => Operand::Reg32Mem32{d: self.reg(), s: self.reg(), o: self.disp()},
+            12 => Operand::Mem32Reg32{d: self.reg(), o: self.disp(), s: self.reg()},
+            13 => Operand::BytePtr(self.reg()),
+            14 => Operand::BytePtrImm8{d: self.reg(), s: self.u8()},
+            _  => Operand::DwordPtrImm32{d: self.reg(), o: self.disp(), i: self.imm()},
+        };
+        (op, oprnd)
+    }
+}
+
+//Target for the encoders: bytes decoded into instructions. Everything the
+//text formatter takes must encode, and GNU as must agree on the bytes.
+pub fn emitter_target(data: &[u8]) -> Result<(), String> {
+    let mut decoder = Decoder {data: data, pos: 0};
+    let mut instructions = vec![];
+    while decoder.pos < data.len() {
+        instructions.push(decoder.instruction());
+    }
+
+    let e = Emitter::new();
+    //15 bytes is the longest x86 instruction
+    let pages = 1 + (instructions.len() * 15) as u32 / CodeBuff::get_page_size();
+    let mut cb = CodeBuff::new(pages).map_err(|err| format!("No code buffer: {}", err))?;
+    let mut src = format!("    {}\n", Syntax::Att.directive());
+    let mut emitted = vec![];
+    for &(op, oprnd) in &instructions {
+        if text::format(op, oprnd, Some("."), Syntax::Att).is_err() {
+            continue;
+        }
+        let start = cb.position();
+        let size = e.emit(op, oprnd, &mut cb);
+        if size < 0 || cb.position() - start != size as isize {
+            return Err(format!("{:?} {:?} formats but doesn't encode", op, oprnd));
+        }
+        //a branch target relative to the start of the instruction
+        let target = match oprnd {
+            Operand::Rel32(rel) => Some(format!(".{:+}", size as i64 + rel as i64)),
+            _ => None,
+        };
+        src.push_str(&format!("    {}\n", text::format(op, oprnd, target.as_ref().map(|t| &**t), Syntax::Att)?));
+        emitted.push((op, oprnd, start as usize, cb.position() as usize));
+    }
+    if emitted.is_empty() {
+        return Ok(());
+    }
+
+    let assembled = aot::assemble(&src)?;
+    for &(op, oprnd, start, end) in &emitted {
+        let ours: Vec<u8> = (start..end).map(|i| cb[i]).collect();
+        if assembled.get(start..end) != Some(&ours[..]) {
+            return Err(format!("{:?} {:?}: emitted {:02x?}, assembled {:02x?}", op, oprnd, ours,
+                               &assembled[start.min(assembled.len())..end.min(assembled.len())]));
+        }
+    }
+    if assembled.len() != cb.position() as usize {
+        return Err(format!("Assembled {} bytes, emitted {}", assembled.len(), cb.position()));
+    }
+    Ok(())
+}
+
+//Random bytes for emitter_target, a handful of instructions each
+pub fn random_bytes(seed: u64, max_len: usize) -> Box<dyn FnMut() -> Vec<u8>> {
+    let mut rng = Rng::new(seed);
+    Box::new(move || {
+        let n = 1 + rng.below(max_len);
+        rng.bytes(n)
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process::{Command, Stdio};
+    use super::*;
+
+    #[test]
+    fn parser_survives_mutated_sources() {
+        if let Some(failure) = fuzz(mutated_sources(2), parse_target, 300) {
+            panic!("{}", failure);
+        }
+    }
+
+    #[test]
+    fn emitter_agrees_with_the_assembler() {
+        let assembler = env::var("AS").unwrap_or("as".to_string());
+        if Command::new(assembler).arg("--version").stdout(Stdio::null()).status().is_err() {
+            return;
+        }
+        if let Some(failure) = fuzz(random_bytes(3, 64), emitter_target, 50) {
+            panic!("{}", failure);
+        }
+    }
+}
@@ -0,0 +1,435 @@
+use std::io::{Error, ErrorKind};
+use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+use std::slice;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use libc;
+
+use CodeBuff;
+use exec::GuardPolicy;
+
+
+//The tape sits in one big mapping between two inaccessible guard regions, so
+//native code can touch cells without checking the pointer first. Running off
+//the tape faults, and the SIGSEGV handler either maps more tape in and lets
+//the code carry on, or sends it to a stub that stops at the op to blame.
+//Protection comes in whole pages, so the tape ends right at the upper guard
+//and whatever is left of its first page lies below it. Unless the tape fills
+//whole pages, native code checks moves to the left itself.
+
+//Bytes of guard either side, no single pointer move may jump over them
+pub const GUARD_SIZE: usize = 1 << 30;
+//Most cells a growing tape has room for
+pub const MAX_CELLS: usize = 1 << 28;
+
+//How many guarded runs can be in native code at once, across all threads
+const SLOTS: usize = 16;
+
+
+//An instruction that touches the cell a Ptr op just moved to, the only kind
+//of instruction that can fault. Every Ptr op is followed by one.
+#[derive(Debug, Copy, Clone)]
+pub struct Site {
+    //code offset of the instruction
+    pub offset: u32,
+    //the Ptr op and how many cells it moved
+    pub ip: u32,
+    pub moved: i32,
+    //what to add to the step counter there to get the steps before the Ptr op
+    pub steps: i32,
+}
+
+//What the handler knows about a guarded run in native code
+struct Record {
+    //the whole mapping, guards included
+    lo: usize,
+    hi: usize,
+    //first cell, end of the accessible ones and end of the room to grow into
+    start: usize,
+    end: AtomicUsize,
+    limit: usize,
+    grow: bool,
+    page: usize,
+    code: usize,
+    code_len: usize,
+    sites: *const Site,
+    sites_len: usize,
+    //where faulting code stops, with the Ptr op in rax
+    stub: usize,
+}
+
+//Addresses of the Records in use, 0 for a free slot
+static ACTIVE: [AtomicUsize; SLOTS] = [const { AtomicUsize::new(0) }; SLOTS];
+static INSTALLED: AtomicUsize = AtomicUsize::new(0);
+//whoever handled SIGSEGV before, for the faults that aren't ours
+static mut PREVIOUS: Option<libc::sigaction> = None;
+
+type Handler = extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void);
+
+
+fn round_up(x: usize, page: usize) -> usize {
+    (x + page - 1) / page * page
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+fn map(len: usize) -> Result<*mut u8, Error> {
+    use std::ptr::null_mut;
+
+    let map = unsafe {
+        libc::mmap(null_mut(), len, libc::PROT_NONE, libc::MAP_PRIVATE | libc::MAP_ANON | libc::MAP_NORESERVE, -1, 0)
+    };
+    if map == libc::MAP_FAILED {
+        Err(Error::last_os_error())
+    }else{
+        Ok(map as *mut u8)
+    }
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+fn open(addr: *mut u8, len: usize) -> Result<(), Error> {
+    if unsafe { libc::mprotect(addr as *mut libc::c_void, len, libc::PROT_READ | libc::PROT_WRITE) } != 0 {
+        Err(Error::last_os_error())
+    }else{
+        Ok(())
+    }
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+fn unmap(addr: *mut u8, len: usize) {
+    unsafe { libc::munmap(addr as *mut libc::c_void, len); }
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+fn install() -> Result<(), Error> {
+    use std::mem;
+    use std::ptr::null_mut;
+
+    //0 not yet, 1 being installed, 2 done
+    if INSTALLED.compare_exchange(0, 1, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+        unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = on_fault as Handler as usize;
+            action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+            libc::sigemptyset(&mut action.sa_mask);
+            let mut previous: libc::sigaction = mem::zeroed();
+            if libc::sigaction(libc::SIGSEGV, null_mut(), &mut previous) != 0 {
+                INSTALLED.store(0, Ordering::SeqCst);
+                return Err(Error::last_os_error());
+            }
+            PREVIOUS = Some(previous);
+            if libc::sigaction(libc::SIGSEGV, &action, null_mut()) != 0 {
+                INSTALLED.store(0, Ordering::SeqCst);
+                return Err(Error::last_os_error());
+            }
+        }
+        INSTALLED.store(2, Ordering::SeqCst);
+    }
+    while INSTALLED.load(Ordering::SeqCst) == 1 {}
+    Ok(())
+}
+
+#[cfg(not(all(target_os = "linux", target_arch = "x86_64")))]
+fn map(_len: usize) -> Result<*mut u8, Error> {
+    Err(Error::new(ErrorKind::Other, "Guard pages need Linux on x86-64"))
+}
+
+#[cfg(not(all(target_os = "linux", target_arch = "x86_64")))]
+fn open(_addr: *mut u8, _len: usize) -> Result<(), Error> {
+    Err(Error::new(ErrorKind::Other, "Guard pages need Linux on x86-64"))
+}
+
+#[cfg(not(all(target_os = "linux", target_arch = "x86_64")))]
+fn unmap(_addr: *mut u8, _len: usize) {}
+
+#[cfg(not(all(target_os = "linux", target_arch = "x86_64")))]
+fn install() -> Result<(), Error> {
+    Err(Error::new(ErrorKind::Other, "Guard pages need Linux on x86-64"))
+}
+
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+extern "C" fn on_fault(sig: libc::c_int, info: *mut libc::siginfo_t, context: *mut libc::c_void) {
+    //where the kernel saved the registers, see <sys/ucontext.h>
+    const REG_R15: usize = 7;
+    const REG_RBX: usize = 11;
+    const REG_RAX: usize = 13;
+    const REG_RIP: usize = 16;
+    //offset of si_addr in siginfo_t
+    const SI_ADDR: isize = 16;
+
+    unsafe {
+        let addr = *((info as *const u8).offset(SI_ADDR) as *const usize);
+        let gregs = &mut (*(context as *mut libc::ucontext_t)).uc_mcontext.gregs;
+        let rip = gregs[REG_RIP] as usize;
+
+        for slot in ACTIVE.iter() {
+            let record = slot.load(Ordering::SeqCst) as *const Record;
+            if record.is_null() {
+                continue;
+            }
+            let r = &*record;
+            if addr < r.lo || addr >= r.hi || rip < r.code || rip >= r.code + r.code_len {
+                continue;
+            }
+            //the faulting instruction runs again once the tape reaches it
+            if r.grow && addr >= r.end.load(Ordering::SeqCst) && addr < r.limit && grow(r, addr) {
+                return;
+            }
+            let sites = slice::from_raw_parts(r.sites, r.sites_len);
+            if let Ok(i) = sites.binary_search_by_key(&((rip - r.code) as u32), |s| s.offset) {
+                //back to just before the Ptr op, like a checked engine stops
+                let site = sites[i];
+                gregs[REG_RAX] = site.ip as i64;
+                gregs[REG_R15] += site.steps as i64;
+                gregs[REG_RBX] -= site.moved as i64 * 4;
+                gregs[REG_RIP] = r.stub as i64;
+                return;
+            }
+        }
+        pass_on(sig, info, context);
+    }
+}
+
+//Maps in more tape, up to addr at least and twice as much if there's room
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+fn grow(r: &Record, addr: usize) -> bool {
+    let end = r.end.load(Ordering::SeqCst);
+    let mut new_end = round_up(end + (end - r.start), r.page);
+    let need = round_up(addr + 4, r.page);
+    if need > new_end {
+        new_end = need;
+    }
+    if new_end > r.limit {
+        new_end = r.limit;
+    }
+    if open(end as *mut u8, new_end - end).is_err() {
+        return false;
+    }
+    r.end.store(new_end, Ordering::SeqCst);
+    true
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+unsafe fn pass_on(sig: libc::c_int, info: *mut libc::siginfo_t, context: *mut libc::c_void) {
+    use std::mem;
+    use std::ptr::null_mut;
+
+    let previous = PREVIOUS;
+    match previous {
+        Some(prev) if prev.sa_sigaction != libc::SIG_DFL && prev.sa_sigaction != libc::SIG_IGN => {
+            if prev.sa_flags & libc::SA_SIGINFO != 0 {
+                let handler: Handler = mem::transmute(prev.sa_sigaction);
+                handler(sig, info, context);
+            }else{
+                let handler: extern "C" fn(libc::c_int) = mem::transmute(prev.sa_sigaction);
+                handler(sig);
+            }
+        },
+        //the fault happens again on return and takes the default action
+        _ => {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = libc::SIG_DFL;
+            libc::sigaction(sig, &action, null_mut());
+        },
+    }
+}
+
+
+pub struct GuardedTape {
+    map: *mut u8,
+    map_len: usize,
+    cells: *mut u32,
+    len: usize,
+    //cells the tape can grow to
+    limit: usize,
+    //accessible bytes below the first cell
+    pad: usize,
+    policy: GuardPolicy,
+}
+
+impl GuardedTape {
+    pub fn new(cells: usize, policy: GuardPolicy) -> Result<GuardedTape, Error> {
+        let page = CodeBuff::get_page_size() as usize;
+        let bytes = round_up(if cells > 0 { cells * 4 } else { 4 }, page);
+        let room = match policy {
+            GuardPolicy::Error => bytes,
+            GuardPolicy::Grow => if bytes > MAX_CELLS * 4 { bytes } else { MAX_CELLS * 4 },
+        };
+
+        let map_len = GUARD_SIZE + room + GUARD_SIZE;
+        let map = map(map_len)?;
+        let start = unsafe { map.offset(GUARD_SIZE as isize) };
+        if let Err(err) = open(start, bytes) {
+            unmap(map, map_len);
+            return Err(err);
+        }
+        let pad = bytes - cells * 4;
+        Ok(GuardedTape {map: map, map_len: map_len, cells: unsafe { start.offset(pad as isize) } as *mut u32, len: cells,
+                        limit: (room - pad) / 4, pad: pad, policy: policy})
+    }
+
+    //Whether native code has to check the pointer doesn't move below the
+    //first cell, as the guard can't catch it there
+    pub fn padded(&self) -> bool {
+        self.pad != 0
+    }
+
+    //Copies tape in and clears the cells past it. Fails if tape doesn't fit
+    //and the policy doesn't let the tape grow to fit it.
+    pub fn load(&mut self, tape: &[u32]) -> bool {
+        if tape.len() > self.len {
+            if self.policy != GuardPolicy::Grow || tape.len() > self.limit {
+                return false;
+            }
+            //whole pages, the end of the tape stays at a guard
+            let end = self.cells as usize + self.len * 4;
+            let new_end = round_up(self.cells as usize + tape.len() * 4, CodeBuff::get_page_size() as usize);
+            if open(end as *mut u8, new_end - end).is_err() {
+                return false;
+            }
+            self.len = (new_end - self.cells as usize) / 4;
+        }
+        self[..tape.len()].copy_from_slice(tape);
+        for c in self[tape.len()..].iter_mut() {
+            *c = 0;
+        }
+        true
+    }
+
+    //Has the handler take care of faults at sites in code, as long as the
+    //Watch lives
+    pub fn watch<'a>(&self, code: &CodeBuff, sites: &'a [Site], stub: usize) -> Result<Watch<'a>, Error> {
+        install()?;
+        let start = self.cells as usize;
+        let record = Box::new(Record {
+            lo: self.map as usize,
+            hi: self.map as usize + self.map_len,
+            start: start,
+            end: AtomicUsize::new(start + self.len * 4),
+            limit: start + self.limit * 4,
+            grow: self.policy == GuardPolicy::Grow,
+            page: CodeBuff::get_page_size() as usize,
+            code: code.get_address(0),
+            code_len: code.get_size() as usize,
+            sites: sites.as_ptr(),
+            sites_len: sites.len(),
+            stub: stub,
+        });
+
+        let address = &*record as *const Record as usize;
+        for (slot, active) in ACTIVE.iter().enumerate() {
+            if active.compare_exchange(0, address, Ordering::SeqCst, Ordering::SeqCst).is_ok() {
+                return Ok(Watch {record: record, slot: slot, sites: PhantomData});
+            }
+        }
+        Err(Error::new(ErrorKind::Other, "Too many guarded tapes in native code at once"))
+    }
+
+    //Picks up the cells the handler mapped in
+    pub fn sync(&mut self, watch: &Watch) {
+        self.len = (watch.end().load(Ordering::SeqCst) - self.cells as usize) / 4;
+    }
+}
+
+impl Deref for GuardedTape {
+    type Target = [u32];
+
+    fn deref(&self) -> &[u32] {
+        unsafe { slice::from_raw_parts(self.cells, self.len) }
+    }
+}
+
+impl DerefMut for GuardedTape {
+    fn deref_mut(&mut self) -> &mut [u32] {
+        unsafe { slice::from_raw_parts_mut(self.cells, self.len) }
+    }
+}
+
+impl Drop for GuardedTape {
+    fn drop(&mut self) {
+        unmap(self.map, self.map_len);
+    }
+}
+
+
+pub struct Watch<'a> {
+    record: Box<Record>,
+    slot: usize,
+    sites: PhantomData<&'a [Site]>,
+}
+
+impl<'a> Watch<'a> {
+    //One past the last byte of tape native code can touch right now
+    pub fn end(&self) -> &AtomicUsize {
+        &self.record.end
+    }
+}
+
+impl<'a> Drop for Watch<'a> {
+    fn drop(&mut self) {
+        ACTIVE[self.slot].store(0, Ordering::SeqCst);
+    }
+}
+
+
+#[cfg(all(test, target_os = "linux", target_arch = "x86_64"))]
+mod tests {
+    use bf;
+    use exec::{self, Config, EngineKind, ExecError, GuardPolicy};
+
+    fn config(policy: GuardPolicy, tape_size: usize) -> Config {
+        let mut config = Config::new();
+        config.engine = EngineKind::Jit;
+        config.guard = Some(policy);
+        config.tape_size = tape_size;
+        config
+    }
+
+    //The tape is as long as configured, not as the pages it sits in, at
+    //either end
+    #[test]
+    fn keeps_the_tape_size() {
+        let prog = bf::Program::parse(&format!("{}+", ">".repeat(200))).unwrap();
+        let r = exec::run(&prog, b"", &config(GuardPolicy::Error, 100));
+        assert_eq!(r.error, Some(ExecError::PointerOutOfRange{ip: 0}));
+        assert_eq!(r.tape.len(), 100);
+
+        let prog = bf::Program::parse(&format!("{}+<", ">".repeat(99))).unwrap();
+        let r = exec::run(&prog, b"", &config(GuardPolicy::Error, 100));
+        assert_eq!(r.error, None);
+        assert_eq!((r.tape.len(), r.tape[99]), (100, 1));
+
+        let prog = bf::Program::parse("+<+").unwrap();
+        let r = exec::run(&prog, b"", &config(GuardPolicy::Grow, 100));
+        assert_eq!(r.error, Some(ExecError::PointerOutOfRange{ip: 1}));
+        assert_eq!((r.tape.len(), r.tape[0], r.steps), (100, 1, 1));
+    }
+
+    //A fault stops at the Ptr op that left the tape, like the checked
+    //engines, whether the guard or the check below the first cell caught it
+    #[test]
+    fn reports_the_op_that_moved() {
+        for &size in &[1000, 1024] {
+            for &(src, ip) in &[("+[>+]", 2), ("+[<+]", 2), (">>>+<+<+<+>>>[<<]", 10), ("++[->>>+<<<]>>>[>>+]", 9)] {
+                let prog = bf::Program::parse(src).unwrap();
+                let mut want = Config::new();
+                want.tape_size = size;
+                let want = exec::run(&prog, b"", &want);
+                assert_eq!(want.error, Some(ExecError::PointerOutOfRange{ip: ip}), "{} on {}", src, size);
+                let r = exec::run(&prog, b"", &config(GuardPolicy::Error, size));
+                assert_eq!(r.error, want.error, "{} on {}", src, size);
+                assert_eq!((r.mem_ptr, r.steps, &r.tape), (want.mem_ptr, want.steps, &want.tape), "{} on {}", src, size);
+            }
+        }
+    }
+
+    #[test]
+    fn grows_the_tape() {
+        let prog = bf::Program::parse(&format!("{}+.", ">".repeat(5000))).unwrap();
+        let r = exec::run(&prog, b"", &config(GuardPolicy::Grow, 100));
+        assert_eq!(r.error, None);
+        assert!(r.tape.len() > 5000);
+        assert_eq!((r.mem_ptr, r.tape[5000], &r.output[..]), (5000, 1, &[1][..]));
+    }
+}
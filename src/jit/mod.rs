@@ -0,0 +1,801 @@
+use std::io::{self, Read, Write};
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use CodeBuff;
+use bf::{self, CellIo, Opcode, Program};
+use bounds::Bounds;
+use emitter::{x64, Emitter};
+use emitter::x64::{Reg64, Register, Operand};
+use exec::{Config, Engine, ExecError, Limiter, Limits};
+use guard::{self, GuardedTape, Site};
+use snapshot::Snapshot;
+
+
+//Register assignment, all callee saved on both Win64 and System V:
+//rbx holds the tape pointer, r14 the JitContext and r15 the step counter.
+const TAPE: Reg64 = Reg64::Rbx;
+const CTX: Reg64 = Reg64::R14;
+const STEPS: Reg64 = Reg64::R15;
+
+//Offsets of the JitContext fields the generated code accesses
+const CTX_PTR: i32 = 0;
+const CTX_STEPS: i32 = 8;
+const CTX_IP: i32 = 16;
+const CTX_LIMIT: i32 = 24;
+const CTX_TAPE_START: i32 = 32;
+const CTX_TAPE_END: i32 = 40;
+
+//Values returned in rax by the generated code
+const STATUS_DONE: u64 = 0;
+const STATUS_ERROR: u64 = 1;
+const STATUS_POLL: u64 = 2;
+const STATUS_BOUNDS: u64 = 3;
+const STATUS_FAULT: u64 = 4;
+
+//Worst case number of code bytes per op, I/O calls plus their exit stubs being the largest
+const BYTES_PER_OP: usize = 64;
+
+
+#[repr(C)]
+struct JitContext<'a> {
+    ptr: u64,
+    steps: u64,
+    ip: u64,
+    //step count at which loops hand control back to the host
+    limit: u64,
+    //addresses of the first cell and one past the last
+    tape_start: u64,
+    tape_end: u64,
+
+    input: &'a mut dyn Read,
+    output: &'a mut dyn Write,
+    io: CellIo,
+    error: Option<ExecError>,
+    //start of the tape, so `#` can tell the pointer's cell index
+    base: *const u32,
+    len: usize,
+    //where a guarded tape ends, it may have grown since entering native code
+    end: *const AtomicUsize,
+}
+
+type EntryFn = extern "C" fn(*mut JitContext, *mut u32, usize, u64) -> u64;
+type IoFn = extern "C" fn(*mut JitContext, *mut u32) -> u64;
+
+
+extern "C" fn jit_out(ctx: *mut JitContext, cell: *mut u32) -> u64 {
+    let ctx = unsafe { &mut *ctx };
+    match ctx.io.write(&mut *ctx.output, unsafe { *cell }) {
+        Ok(_) => STATUS_DONE,
+        Err(err) => {
+            ctx.error = Some(err);
+            STATUS_ERROR
+        },
+    }
+}
+
+extern "C" fn jit_in(ctx: *mut JitContext, cell: *mut u32) -> u64 {
+    let ctx = unsafe { &mut *ctx };
+    match ctx.io.read(&mut *ctx.input, unsafe { *cell }) {
+        Ok(c) => {
+            unsafe { *cell = c; }
+            STATUS_DONE
+        },
+        Err(err) => {
+            ctx.error = Some(err);
+            STATUS_ERROR
+        },
+    }
+}
+
+extern "C" fn jit_dump(ctx: *mut JitContext, cell: *mut u32) -> u64 {
+    let ctx = unsafe { &mut *ctx };
+    let len = if ctx.end.is_null() {
+        ctx.len
+    }else{
+        (unsafe { (*ctx.end).load(Ordering::SeqCst) } - ctx.base as usize) / 4
+    };
+    let tape = unsafe { ::std::slice::from_raw_parts(ctx.base, len) };
+    let ptr = (cell as usize - ctx.base as usize) / 4;
+    let _ = bf::dump_tape(&mut io::stderr(), tape, ptr);
+    STATUS_DONE
+}
+
+
+struct Compiler<'a> {
+    e: Emitter,
+    cb: CodeBuff,
+    ops: &'a [Opcode],
+    bounds: &'a Bounds,
+    cell_mask: u32,
+    //no bounds checks, the tape sits between guard pages
+    guarded: bool,
+    //the guarded tape starts partway into a page, so moves to the left
+    //compare the pointer against its start
+    check_low: bool,
+
+    //code offset of every op execution can start or resume at
+    entries: Vec<Option<isize>>,
+    //ops executed since the step counter was last updated
+    pending: u64,
+    flushed: u64,
+    //the last Ptr op, its move and the steps before it, until the next
+    //instruction touching the tape
+    moved: Option<(usize, i32, u64)>,
+    sites: Vec<Site>,
+    //rel32 fields (by end position) of those compares, with the Ptr op, its
+    //move and the steps before it
+    lows: Vec<(isize, usize, i32, u64)>,
+    //rel32 fields (by end position) waiting for the entry of an op
+    fixups: Vec<(isize, usize)>,
+    //rel32 fields (by end position) that should leave the native code at an op,
+    //with the status to return if rax doesn't already hold it
+    exits: Vec<(isize, usize, Option<u64>)>,
+}
+
+impl<'a> Compiler<'a> {
+    fn new(ops: &'a [Opcode], bounds: &'a Bounds, len: usize, config: &Config, guarded: bool, check_low: bool) -> Result<Compiler<'a>, ExecError> {
+        let size = len * BYTES_PER_OP + 256;
+        let page_size = CodeBuff::get_page_size() as usize;
+        let cb = match CodeBuff::new(((size + page_size - 1) / page_size) as u32) {
+            Ok(cb) => cb,
+            Err(_) => return Err(ExecError::Jit("Code buffer creation failed")),
+        };
+
+        Ok(Compiler {
+            e: Emitter::new(),
+            cb: cb,
+            ops: ops,
+            bounds: bounds,
+            cell_mask: config.cell_width.mask(),
+            guarded: guarded,
+            check_low: check_low,
+            entries: vec![None; ops.len() + 1],
+            pending: 0,
+            flushed: 0,
+            moved: None,
+            sites: vec![],
+            lows: vec![],
+            fixups: vec![],
+            exits: vec![],
+        })
+    }
+
+    fn emit(&mut self, op: x64::Opcode, oprnd: Operand) -> Result<(), ExecError> {
+        if self.e.emit(op, oprnd, &mut self.cb) < 0 {
+            Err(ExecError::Jit("Instruction encoding failed"))
+        }else{
+            Ok(())
+        }
+    }
+
+    fn patch(&mut self, at: isize, target: isize) {
+        let pos = self.cb.position();
+        self.cb.set_position(at - 4);
+        self.cb.write_u32((target - at) as i32 as u32);
+        self.cb.set_position(pos);
+    }
+
+    fn flush_steps(&mut self) -> Result<(), ExecError> {
+        if self.pending > 0 {
+            let n = self.pending as u32;
+            self.emit(x64::Opcode::Add, Operand::Reg64Imm32{r: STEPS, i: n})?;
+            self.flushed += self.pending;
+            self.pending = 0;
+        }
+        Ok(())
+    }
+
+    //An I/O op at the head of a loop body keeps the entry from before the
+    //limit check, back-edges jump there and must not skip it
+    fn mark_entry(&mut self, ip: usize) {
+        if self.entries[ip].is_none() {
+            self.entries[ip] = Some(self.cb.position());
+        }
+    }
+
+    fn prologue(&mut self) -> Result<(), ExecError> {
+        use emitter::x64::Opcode::*;
+        self.emit(Push, Operand::Register(Register::Reg64(TAPE)))?;
+        self.emit(Push, Operand::Register(Register::Reg64(CTX)))?;
+        self.emit(Push, Operand::Register(Register::Reg64(STEPS)))?;
+        //keeps the stack 16 byte aligned and doubles as Win64 shadow space
+        self.emit(Sub, Operand::Reg64Imm32{r: Reg64::Rsp, i: 0x20})?;
+        self.emit(Mov, Operand::Reg64Reg64{d: CTX, s: Emitter::ArgReg(0)})?;
+        self.emit(Mov, Operand::Reg64Reg64{d: TAPE, s: Emitter::ArgReg(1)})?;
+        self.emit(Mov, Operand::Reg64Reg64{d: STEPS, s: Emitter::ArgReg(3)})?;
+        self.emit(Jmp, Operand::Register(Register::Reg64(Emitter::ArgReg(2))))
+    }
+
+    fn epilogue(&mut self) -> Result<(), ExecError> {
+        use emitter::x64::Opcode::*;
+        self.emit(Mov, Operand::Mem64Reg64{d: CTX, o: CTX_PTR, s: TAPE})?;
+        self.emit(Mov, Operand::Mem64Reg64{d: CTX, o: CTX_STEPS, s: STEPS})?;
+        self.emit(Add, Operand::Reg64Imm32{r: Reg64::Rsp, i: 0x20})?;
+        self.emit(Pop, Operand::Register(Register::Reg64(STEPS)))?;
+        self.emit(Pop, Operand::Register(Register::Reg64(CTX)))?;
+        self.emit(Pop, Operand::Register(Register::Reg64(TAPE)))?;
+        self.emit(Ret, Operand::None)
+    }
+
+    fn call_io(&mut self, ip: usize, func: usize) -> Result<(), ExecError> {
+        use emitter::x64::Opcode::*;
+        self.emit(Mov, Operand::Reg64Reg64{d: Emitter::ArgReg(0), s: CTX})?;
+        self.emit(Mov, Operand::Reg64Reg64{d: Emitter::ArgReg(1), s: TAPE})?;
+        self.emit(Mov, Operand::Reg64Imm64{r: Reg64::Rax, i: func as u64})?;
+        self.emit(Call, Operand::Register(Register::Reg64(Reg64::Rax)))?;
+        self.emit(Test, Operand::Reg64Reg64{d: Reg64::Rax, s: Reg64::Rax})?;
+        self.emit(Jcc(x64::JNE), Operand::Rel32(0))?;
+        let at = self.cb.position();
+        self.exits.push((at, ip, None));
+        Ok(())
+    }
+
+    //Budget and cancellation check at the head of a loop body, so every back-edge passes through it
+    fn check_limit(&mut self, ip: usize) -> Result<(), ExecError> {
+        use emitter::x64::Opcode::*;
+        self.emit(Cmp, Operand::Reg64Mem64{d: STEPS, s: CTX, o: CTX_LIMIT})?;
+        self.emit(Jcc(x64::JAE), Operand::Rel32(0))?;
+        let at = self.cb.position();
+        self.exits.push((at, ip, Some(STATUS_POLL)));
+        Ok(())
+    }
+
+    //Leaves the native code when the pointer could move off the tape before
+    //the next check, the host then runs the ops one at a time
+    fn check_bounds(&mut self, ip: usize, lo: isize, hi: isize) -> Result<(), ExecError> {
+        use emitter::x64::Opcode::*;
+        self.flush_steps()?;
+        for &(offset, field, jcc) in &[(lo, CTX_TAPE_START, x64::Jmp::JB), (hi, CTX_TAPE_END, x64::JAE)] {
+            if offset == 0 {
+                continue;
+            }
+            if offset.abs() > (i32::max_value() / 4) as isize {
+                return Err(ExecError::Jit("Pointer moves too far for native code"));
+            }
+            self.emit(Mov, Operand::Reg64Reg64{d: Reg64::Rax, s: TAPE})?;
+            self.emit(Add, Operand::Reg64Imm32{r: Reg64::Rax, i: (offset * 4) as i32 as u32})?;
+            self.emit(Cmp, Operand::Reg64Mem64{d: Reg64::Rax, s: CTX, o: field})?;
+            self.emit(Jcc(jcc), Operand::Rel32(0))?;
+            let at = self.cb.position();
+            self.exits.push((at, ip, Some(STATUS_BOUNDS)));
+        }
+        Ok(())
+    }
+
+    //Records the next instruction as where running off a guarded tape
+    //faults, if the pointer moved since the last one
+    fn site(&mut self) {
+        if let Some((ip, x, steps)) = self.moved.take() {
+            let at = self.cb.position();
+            self.sites.push(Site {offset: at as u32, ip: ip as u32, moved: x, steps: (steps as i64 - self.flushed as i64) as i32});
+        }
+    }
+
+    //Touches the cell before the host gets a pointer to it
+    fn touch(&mut self) -> Result<(), ExecError> {
+        if self.moved.is_some() {
+            self.site();
+            self.emit(x64::Opcode::Cmp, Operand::DwordPtrImm32{d: TAPE, o: 0, i: 0})?;
+        }
+        Ok(())
+    }
+
+    fn compile_op(&mut self, ip: usize) -> Result<(), ExecError> {
+        use emitter::x64::Opcode::*;
+        match self.ops[ip] {
+            Opcode::Ptr(x) => {
+                if self.guarded {
+                    if (x as i64).abs() * 4 >= guard::GUARD_SIZE as i64 {
+                        return Err(ExecError::Jit("Pointer moves too far for the guard pages"));
+                    }
+                    self.moved = Some((ip, x, self.flushed + self.pending));
+                }
+                let steps = self.pending;
+                self.pending += 1;
+                self.emit(Add, Operand::Reg64Imm32{r: TAPE, i: (x * 4) as u32})?;
+                if self.check_low && x < 0 {
+                    self.emit(Cmp, Operand::Reg64Mem64{d: TAPE, s: CTX, o: CTX_TAPE_START})?;
+                    self.emit(Jcc(x64::Jmp::JB), Operand::Rel32(0))?;
+                    let at = self.cb.position();
+                    self.lows.push((at, ip, x, steps));
+                }
+            },
+            Opcode::Byte(x) => {
+                self.pending += 1;
+                self.site();
+                self.emit(Add, Operand::DwordPtrImm32{d: TAPE, o: 0, i: x as u32})?;
+                if self.cell_mask != !0 {
+                    let mask = self.cell_mask;
+                    self.emit(And, Operand::DwordPtrImm32{d: TAPE, o: 0, i: mask})?;
+                }
+            },
+            Opcode::LoopEnter(x) => {
+                self.pending += 1;
+                self.flush_steps()?;
+                self.site();
+                self.emit(Cmp, Operand::DwordPtrImm32{d: TAPE, o: 0, i: 0})?;
+                self.emit(Jcc(x64::JE), Operand::Rel32(0))?;
+                let at = self.cb.position();
+                self.fixups.push((at, x + 1));
+            },
+            Opcode::LoopExit(x) => {
+                self.pending += 1;
+                self.flush_steps()?;
+                self.site();
+                self.emit(Cmp, Operand::DwordPtrImm32{d: TAPE, o: 0, i: 0})?;
+                self.emit(Jcc(x64::JNE), Operand::Rel32(0))?;
+                let at = self.cb.position();
+                self.fixups.push((at, x + 1));
+            },
+            Opcode::Out => {
+                self.flush_steps()?;
+                self.mark_entry(ip);
+                self.touch()?;
+                self.call_io(ip, jit_out as IoFn as usize)?;
+                self.pending += 1;
+            },
+            Opcode::In => {
+                //the entry here doubles as the resume point after the host ran out of input
+                self.flush_steps()?;
+                self.mark_entry(ip);
+                self.touch()?;
+                self.call_io(ip, jit_in as IoFn as usize)?;
+                self.pending += 1;
+            },
+            Opcode::Debug => {
+                self.flush_steps()?;
+                self.mark_entry(ip);
+                self.touch()?;
+                self.call_io(ip, jit_dump as IoFn as usize)?;
+                self.pending += 1;
+            },
+        }
+        Ok(())
+    }
+
+    //Compiles ops start..end, which must not branch out of the range.
+    //Reaching `end` leaves the native code with STATUS_DONE.
+    fn compile(mut self, start: usize, end: usize) -> Result<(CodeBuff, Vec<Option<isize>>, Vec<Site>, Option<isize>), ExecError> {
+        use emitter::x64::Opcode::*;
+        self.prologue()?;
+
+        for ip in start..end {
+            let (block_start, loop_body) = match if ip > start { Some(self.ops[ip - 1]) } else { None } {
+                None => (true, false),
+                Some(Opcode::LoopEnter(_)) => (true, true),
+                Some(Opcode::LoopExit(_)) => (true, false),
+                _ => (false, false),
+            };
+            if block_start {
+                self.mark_entry(ip);
+            }
+            if loop_body {
+                self.check_limit(ip)?;
+            }
+            //the host checks wherever it enters the code
+            if let Some((lo, hi)) = if ip > start && !self.guarded { self.bounds.reach(ip) } else { None } {
+                self.check_bounds(ip, lo, hi)?;
+            }
+            self.compile_op(ip)?;
+        }
+
+        self.flush_steps()?;
+        self.touch()?;
+        self.mark_entry(end);
+        self.emit(Mov, Operand::Mem64Imm32{d: CTX, o: CTX_IP, i: end as u32})?;
+        self.emit(Mov, Operand::Reg64Imm32{r: Reg64::Rax, i: STATUS_DONE as u32})?;
+        let exit = self.cb.position();
+        self.epilogue()?;
+
+        //out of line stubs record where execution stopped and leave with the status in rax
+        let exits = mem::replace(&mut self.exits, vec![]);
+        for (at, ip, status) in exits {
+            let stub = self.cb.position();
+            self.patch(at, stub);
+            self.emit(Mov, Operand::Mem64Imm32{d: CTX, o: CTX_IP, i: ip as u32})?;
+            if let Some(status) = status {
+                self.emit(Mov, Operand::Reg64Imm32{r: Reg64::Rax, i: status as u32})?;
+            }
+            self.emit(Jmp, Operand::Rel32(0))?;
+            let pos = self.cb.position();
+            self.patch(pos, exit);
+        }
+
+        //the fault handler sends guarded code here, with the Ptr op to blame in rax
+        let fault = if self.guarded {
+            let stub = self.cb.position();
+            self.emit(Mov, Operand::Mem64Reg64{d: CTX, o: CTX_IP, s: Reg64::Rax})?;
+            self.emit(Mov, Operand::Reg64Imm32{r: Reg64::Rax, i: STATUS_FAULT as u32})?;
+            self.emit(Jmp, Operand::Rel32(0))?;
+            let pos = self.cb.position();
+            self.patch(pos, exit);
+
+            //moves below the first cell undo themselves and stop the same way
+            let lows = mem::replace(&mut self.lows, vec![]);
+            for (at, ip, x, steps) in lows {
+                let pos = self.cb.position();
+                self.patch(at, pos);
+                self.emit(Add, Operand::Reg64Imm32{r: TAPE, i: (-x * 4) as u32})?;
+                if steps > 0 {
+                    self.emit(Add, Operand::Reg64Imm32{r: STEPS, i: steps as u32})?;
+                }
+                self.emit(Mov, Operand::Reg64Imm32{r: Reg64::Rax, i: ip as u32})?;
+                self.emit(Jmp, Operand::Rel32(0))?;
+                let pos = self.cb.position();
+                self.patch(pos, stub);
+            }
+            Some(stub)
+        }else{
+            None
+        };
+
+        let fixups = mem::replace(&mut self.fixups, vec![]);
+        for (at, ip) in fixups {
+            match self.entries[ip] {
+                Some(target) => self.patch(at, target),
+                None => return Err(ExecError::Jit("Branch to an op without an entry")),
+            }
+        }
+
+        if self.cb.position() > self.cb.get_size() as isize {
+            return Err(ExecError::Jit("Ran out of code buffer room"));
+        }
+
+        Ok((self.cb, self.entries, self.sites, fault))
+    }
+}
+
+
+//Where native code picks up and leaves off; the tape itself is borrowed
+//from whoever runs the code
+#[derive(Debug, Copy, Clone)]
+pub struct NativeState {
+    pub mem_ptr: usize,
+    pub ip: usize,
+    pub steps: u64,
+    pub io: CellIo,
+}
+
+//Native code for the ops start..end of a program, the whole program or a single loop
+pub struct Region {
+    code: CodeBuff,
+    entries: Vec<Option<isize>>,
+    //the ops start..end, run one at a time where native code could leave the tape
+    ops: Vec<Opcode>,
+    bounds: Bounds,
+    start: usize,
+    end: usize,
+    cell_mask: u32,
+    //for code without bounds checks, where it can fault and the stub the
+    //fault handler sends it to
+    sites: Vec<Site>,
+    fault: Option<isize>,
+    check_low: bool,
+}
+
+impl Region {
+    pub fn compile(ops: &[Opcode], start: usize, end: usize, config: &Config) -> Result<Region, ExecError> {
+        Region::build(ops, start, end, config, false, false)
+    }
+
+    //Code without bounds checks, it only runs on a GuardedTape. A padded
+    //tape needs code that checks moves to the left.
+    pub fn compile_guarded(ops: &[Opcode], start: usize, end: usize, config: &Config, padded: bool) -> Result<Region, ExecError> {
+        Region::build(ops, start, end, config, true, padded)
+    }
+
+    fn build(ops: &[Opcode], start: usize, end: usize, config: &Config, guarded: bool, check_low: bool) -> Result<Region, ExecError> {
+        let bounds = Bounds::analyze(ops, start, end);
+        let (mut code, entries, sites, fault) = Compiler::new(ops, &bounds, end - start, config, guarded, check_low)?.compile(start, end)?;
+
+        if code.protect(true, false).is_err() {
+            return Err(ExecError::Jit("Could not make code executable"));
+        }
+        Ok(Region {code: code, entries: entries, ops: ops[start..end].to_vec(), bounds: bounds, start: start, end: end,
+                   cell_mask: config.cell_width.mask(), sites: sites, fault: fault, check_low: check_low})
+    }
+
+    pub fn can_enter(&self, ip: usize) -> bool {
+        self.entries.get(ip).map_or(false, |e| e.is_some())
+    }
+
+    //Runs the op at state.ip like OptimizedInterpreter does
+    fn step(&self, mem: &mut [u32], state: &mut NativeState, input: &mut dyn Read, output: &mut dyn Write) -> Result<(), ExecError> {
+        let ptr = state.mem_ptr;
+        match self.ops[state.ip - self.start] {
+            Opcode::Ptr(x) => {
+                let p = ptr.wrapping_add(x as usize);
+                if p >= mem.len() {
+                    return Err(ExecError::PointerOutOfRange{ip: state.ip});
+                }
+                state.mem_ptr = p;
+            },
+            Opcode::Byte(x) => mem[ptr] = mem[ptr].wrapping_add(x as u32) & self.cell_mask,
+            Opcode::LoopEnter(x) => if mem[ptr] == 0 {
+                state.ip = x;
+            },
+            Opcode::LoopExit(x) => if mem[ptr] != 0 {
+                state.ip = x;
+            },
+            Opcode::Out => state.io.write(output, mem[ptr])?,
+            Opcode::In => mem[ptr] = state.io.read(input, mem[ptr])?,
+            Opcode::Debug => {
+                let _ = bf::dump_tape(&mut io::stderr(), mem, ptr);
+            },
+        }
+        state.ip += 1;
+        state.steps += 1;
+        Ok(())
+    }
+
+    //Runs from state.ip until execution reaches the end of the region
+    pub fn run(&self, mem: &mut [u32], state: &mut NativeState, input: &mut dyn Read, output: &mut dyn Write,
+               limiter: &Limiter) -> Result<(), ExecError> {
+        if self.fault.is_some() {
+            return Err(ExecError::Jit("Code without bounds checks needs a guarded tape"));
+        }
+        let base = mem.as_mut_ptr();
+        let mut ctx = JitContext {
+            ptr: 0,
+            steps: 0,
+            ip: 0,
+            limit: 0,
+            tape_start: base as u64,
+            tape_end: base as u64 + mem.len() as u64 * 4,
+            input: input,
+            output: output,
+            io: state.io,
+            error: None,
+            base: base,
+            len: mem.len(),
+            end: ::std::ptr::null(),
+        };
+
+        let func: EntryFn = unsafe { mem::transmute(self.code.get_address(0)) };
+
+        loop {
+            ctx.limit = match limiter.poll(state.steps) {
+                Ok(n) => n,
+                Err(int) => return Err(int.to_error(state.ip, state.mem_ptr, mem)),
+            };
+
+            //near the ends of the tape ops run one at a time, until the code
+            //up to the next check can't leave it
+            while state.ip != self.end && !(self.can_enter(state.ip) && self.bounds.fits(state.ip, state.mem_ptr, mem.len())) {
+                if state.steps >= ctx.limit {
+                    ctx.limit = match limiter.poll(state.steps) {
+                        Ok(n) => n,
+                        Err(int) => return Err(int.to_error(state.ip, state.mem_ptr, mem)),
+                    };
+                }
+                self.step(mem, state, &mut *ctx.input, &mut *ctx.output)?;
+            }
+            if state.ip == self.end {
+                return Ok(());
+            }
+
+            let entry = self.code.get_address(self.entries[state.ip].unwrap());
+            ctx.io = state.io;
+            let status = func(&mut ctx, unsafe { base.offset(state.mem_ptr as isize) }, entry, state.steps);
+
+            state.ip = ctx.ip as usize;
+            state.steps = ctx.steps;
+            state.io = ctx.io;
+            state.mem_ptr = (ctx.ptr as usize).wrapping_sub(base as usize) / 4;
+
+            match status {
+                STATUS_DONE => {
+                    debug_assert!(state.ip == self.end);
+                    return Ok(());
+                },
+                STATUS_POLL | STATUS_BOUNDS => {},
+                _ => return Err(ctx.error.take().unwrap_or(ExecError::Jit("Native code failed"))),
+            }
+        }
+    }
+
+    //Runs guarded code from state.ip until execution reaches the end of the region
+    pub fn run_guarded(&self, tape: &mut GuardedTape, state: &mut NativeState, input: &mut dyn Read, output: &mut dyn Write,
+                       limiter: &Limiter) -> Result<(), ExecError> {
+        let stub = match self.fault {
+            Some(stub) => self.code.get_address(stub),
+            None => return Err(ExecError::Jit("Code with bounds checks can't use a guarded tape")),
+        };
+        if tape.padded() && !self.check_low {
+            return Err(ExecError::Jit("A padded tape needs code that checks moves to the left"));
+        }
+        let watch = match tape.watch(&self.code, &self.sites, stub) {
+            Ok(watch) => watch,
+            Err(_) => return Err(ExecError::Jit("Could not install the guard page handler")),
+        };
+        let base = tape.as_mut_ptr();
+        let mut ctx = JitContext {
+            ptr: 0,
+            steps: 0,
+            ip: 0,
+            limit: 0,
+            //only moves to the left are checked
+            tape_start: base as u64,
+            tape_end: 0,
+            input: input,
+            output: output,
+            io: state.io,
+            error: None,
+            base: base,
+            len: tape.len(),
+            end: watch.end(),
+        };
+
+        let func: EntryFn = unsafe { mem::transmute(self.code.get_address(0)) };
+
+        loop {
+            ctx.limit = match limiter.poll(state.steps) {
+                Ok(n) => n,
+                Err(int) => return Err(int.to_error(state.ip, state.mem_ptr, tape)),
+            };
+            if state.ip == self.end {
+                return Ok(());
+            }
+            let entry = match self.entries[state.ip] {
+                Some(entry) => self.code.get_address(entry),
+                None => return Err(ExecError::Jit("Native code can't start at this op")),
+            };
+
+            ctx.io = state.io;
+            let status = func(&mut ctx, unsafe { base.offset(state.mem_ptr as isize) }, entry, state.steps);
+            tape.sync(&watch);
+
+            state.ip = ctx.ip as usize;
+            state.steps = ctx.steps;
+            state.io = ctx.io;
+            state.mem_ptr = (ctx.ptr as usize).wrapping_sub(base as usize) / 4;
+
+            match status {
+                STATUS_DONE => return Ok(()),
+                STATUS_POLL => {},
+                STATUS_FAULT => return Err(ExecError::PointerOutOfRange{ip: state.ip}),
+                _ => return Err(ctx.error.take().unwrap_or(ExecError::Jit("Native code failed"))),
+            }
+        }
+    }
+}
+
+
+//The JIT's tape, the guarded one taking the place of bounds checks
+enum Memory {
+    Heap(Vec<u32>),
+    Guarded(GuardedTape),
+}
+
+impl Memory {
+    fn cells(&self) -> &[u32] {
+        match *self {
+            Memory::Heap(ref mem) => mem,
+            Memory::Guarded(ref tape) => tape,
+        }
+    }
+}
+
+
+pub struct Jit {
+    region: Region,
+    program_hash: u64,
+    mem: Memory,
+    mem_ptr: usize,
+    ip: usize,
+    steps: u64,
+    io: CellIo,
+    limits: Limits,
+}
+
+impl Jit {
+    pub fn compile(prog: &Program, config: &Config) -> Result<Jit, ExecError> {
+        let (region, mem) = match config.guard {
+            None => (Region::compile(&prog.ops, 0, prog.ops.len(), config)?, Memory::Heap(vec![0u32; config.tape_size])),
+            Some(policy) => {
+                let tape = match GuardedTape::new(config.tape_size, policy) {
+                    Ok(tape) => tape,
+                    Err(_) => return Err(ExecError::Jit("Could not map a tape between guard pages")),
+                };
+                (Region::compile_guarded(&prog.ops, 0, prog.ops.len(), config, tape.padded())?, Memory::Guarded(tape))
+            },
+        };
+        Ok(Jit {
+            region: region,
+            program_hash: bf::hash_ops(&prog.ops),
+            mem: mem,
+            mem_ptr: 0,
+            ip: 0,
+            steps: 0,
+            io: CellIo::new(config),
+            limits: config.limits.clone(),
+        })
+    }
+}
+
+impl Engine for Jit {
+    fn run_io(&mut self, input: &mut dyn Read, output: &mut dyn Write) -> Result<(), ExecError> {
+        let mut state = NativeState {mem_ptr: self.mem_ptr, ip: self.ip, steps: self.steps, io: self.io};
+        let res = match self.mem {
+            Memory::Heap(ref mut mem) => self.region.run(mem, &mut state, input, output, &self.limits.start()),
+            Memory::Guarded(ref mut tape) => self.region.run_guarded(tape, &mut state, input, output, &self.limits.start()),
+        };
+        self.mem_ptr = state.mem_ptr;
+        self.ip = state.ip;
+        self.steps = state.steps;
+        self.io = state.io;
+        res
+    }
+
+    fn tape(&self) -> &[u32] {
+        self.mem.cells()
+    }
+
+    fn mem_ptr(&self) -> usize {
+        self.mem_ptr
+    }
+
+    fn ip(&self) -> usize {
+        self.ip
+    }
+
+    fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    fn program_hash(&self) -> u64 {
+        self.program_hash
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            program_hash: self.program_hash,
+            tape: self.mem.cells().to_vec(),
+            mem_ptr: self.mem_ptr,
+            ip: self.ip,
+            steps: self.steps,
+            input_pos: self.io.input_pos,
+            output_pos: self.io.output_pos,
+        }
+    }
+
+    //Native code can only pick up at block starts and I/O ops, which is
+    //everywhere the JIT itself stops
+    fn restore(&mut self, snap: &Snapshot) -> Result<(), ExecError> {
+        snap.check(self.program_hash, self.region.end)?;
+        if !self.region.can_enter(snap.ip) {
+            return Err(ExecError::Snapshot("The JIT cannot resume at this op"));
+        }
+        match self.mem {
+            Memory::Heap(ref mut mem) => *mem = snap.tape.clone(),
+            Memory::Guarded(ref mut tape) => if !tape.load(&snap.tape) {
+                return Err(ExecError::Snapshot("The tape doesn't fit between the guard pages"));
+            },
+        }
+        self.mem_ptr = snap.mem_ptr;
+        self.ip = snap.ip;
+        self.steps = snap.steps;
+        self.io.input_pos = snap.input_pos;
+        self.io.output_pos = snap.output_pos;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use bf;
+    use exec::{self, Config, EngineKind};
+
+    //Loops patch their jumps at odd offsets, which debug builds check
+    #[test]
+    fn runs_loops() {
+        let prog = bf::Program::parse("++++++++[>++++[>++>+++<<-]>+<<-]>>.>+.").unwrap();
+        let mut config = Config::new();
+        config.engine = EngineKind::Jit;
+        let r = exec::run(&prog, b"", &config);
+        assert!(r.error.is_none(), "{:?}", r.error);
+        assert_eq!(r.output, b"Ha");
+    }
+}
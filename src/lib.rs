@@ -0,0 +1,303 @@
+
+
+extern crate libc;
+
+#[cfg(windows)] extern crate kernel32;
+#[cfg(windows)] extern crate winapi;
+
+use std::io::{Error, ErrorKind};
+use std::ops::{Index, IndexMut};
+
+
+pub struct CodeBuff {
+    buff : *mut u8,
+    size: u32,
+    
+    pos: isize,
+}
+
+impl CodeBuff {
+    pub fn new(num_pages: u32) -> Result<CodeBuff, Error> {
+        let _buff: *mut u8;
+        
+        match CodeBuff::alloc(num_pages) {
+            Ok(page) => _buff = page,
+            Err(err) => return Err(err)
+        }
+        
+        let _size = CodeBuff::get_page_size() * num_pages;
+        Ok(CodeBuff{buff: _buff, size: _size, pos: 0})
+    }
+
+    #[cfg(windows)]
+    pub fn get_page_size() -> u32 {
+        use winapi::sysinfoapi::SYSTEM_INFO;
+        use kernel32::GetSystemInfo;
+        
+        let mut sys_info: SYSTEM_INFO;
+        let ret = unsafe { 
+            sys_info = std::mem::uninitialized();
+            GetSystemInfo(&mut sys_info as *mut SYSTEM_INFO)
+        };
+
+        sys_info.dwPageSize
+    }
+
+    #[cfg(windows)]
+    fn alloc(num_pages: u32) -> Result<(*mut u8), Error> {
+        use kernel32::VirtualAlloc;
+        use std::ptr::null_mut;
+        use std::os::raw::c_void;
+        use winapi::winnt::{MEM_COMMIT, PAGE_READWRITE};
+        
+        
+        let page_size = CodeBuff::get_page_size() * num_pages;
+        let page: *mut c_void;
+        let lp_address: * mut c_void = null_mut();
+        unsafe {
+            page = VirtualAlloc(lp_address, page_size as u64, MEM_COMMIT, PAGE_READWRITE);   
+        }
+        
+        if page.is_null(){
+            Err(Error::last_os_error())
+        }else{
+            Ok(page as *mut u8)
+        }   
+    }
+    
+    #[cfg(windows)]
+    pub fn protect(&mut self, exec_en:bool, write_en:bool) -> Result<(), Error> {
+        use kernel32::VirtualProtect;
+        use winapi::winnt::{PAGE_READWRITE, PAGE_EXECUTE_READ, PAGE_EXECUTE_READWRITE, PAGE_READONLY};
+        use std::os::raw::c_void;
+        let prot: u32;
+        
+        match (exec_en, write_en){
+            ( true,  true) => prot = PAGE_EXECUTE_READWRITE,
+            ( true, false) => prot = PAGE_EXECUTE_READ,
+            (false,  true) => prot = PAGE_READWRITE,
+            (false, false) => prot = PAGE_READONLY,
+        };
+        let mut old:u32 = 0;
+        let ret = unsafe {
+            VirtualProtect(self.buff as *mut c_void, self.size as u64,  prot, &mut old)
+        };
+        
+        if ret == 0 {
+            Err(Error::last_os_error())
+        }else{
+            Ok(())
+        }   
+    }
+
+    #[cfg(unix)]
+    pub fn get_page_size() -> u32 {
+        unsafe { libc::sysconf(libc::_SC_PAGESIZE) as u32 }
+    }
+
+    #[cfg(unix)]
+    fn alloc(num_pages: u32) -> Result<*mut u8, Error> {
+        use std::ptr::null_mut;
+
+        let page_size = CodeBuff::get_page_size() * num_pages;
+        let page = unsafe {
+            libc::mmap(null_mut(), page_size as usize, libc::PROT_READ | libc::PROT_WRITE,
+                       libc::MAP_PRIVATE | libc::MAP_ANON, -1, 0)
+        };
+
+        if page == libc::MAP_FAILED {
+            Err(Error::last_os_error())
+        }else{
+            Ok(page as *mut u8)
+        }
+    }
+
+    #[cfg(unix)]
+    pub fn protect(&mut self, exec_en:bool, write_en:bool) -> Result<(), Error> {
+        let mut prot = libc::PROT_READ;
+        if exec_en {
+            prot |= libc::PROT_EXEC;
+        }
+        if write_en {
+            prot |= libc::PROT_WRITE;
+        }
+        let ret = unsafe {
+            libc::mprotect(self.buff as *mut libc::c_void, self.size as usize, prot)
+        };
+
+        if ret != 0 {
+            Err(Error::last_os_error())
+        }else{
+            Ok(())
+        }
+    }
+    
+    /// # Safety
+    /// Nothing checks there is a function at `offset` or that the buffer is
+    /// executable
+    pub unsafe fn get_function(&self, offset: isize) -> (fn() -> i64) {
+        std::mem::transmute(self.at(offset, 1))
+    }
+    
+    
+    
+    /// # Safety
+    /// As for `get_function`
+    pub unsafe fn get_function1<RT, T1>(&self, offset: isize) -> fn(T1) -> RT {
+        std::mem::transmute(self.at(offset, 1))
+    }
+    
+    
+    pub fn get_address(&self, offset:isize) -> usize {
+        (self.buff as usize).wrapping_add(offset as usize)
+    }
+    
+    pub fn get_size(&self) -> u32 {
+        self.size
+    }
+    
+    pub fn position(&self) -> isize {
+        self.pos
+    }
+    
+    pub fn set_position(&mut self, pos:isize) {
+        self.pos = pos
+    }
+    
+    //Pointer to `len` bytes at `pos`, panics unless they are all in the buffer
+    fn at(&self, pos: isize, len: usize) -> *mut u8 {
+        assert!(pos >= 0 && pos as usize + len <= self.size as usize, "Code buffer access out of range");
+        unsafe { self.buff.offset(pos) }
+    }
+    
+    pub fn write_u8(&mut self, x:u8) {
+        unsafe { 
+            *self.at(self.pos, 1) = x;
+        }
+        self.pos = self.pos + 1;
+    }
+    
+    pub fn write_u16(&mut self, x:u16) {
+        unsafe { 
+            std::ptr::write_unaligned(self.at(self.pos, 2) as *mut u16, x);
+        }
+        self.pos = self.pos + 2;
+    }
+    
+    pub fn write_u32(&mut self, x:u32) {
+        unsafe { 
+            std::ptr::write_unaligned(self.at(self.pos, 4) as *mut u32, x);
+        }
+        self.pos = self.pos + 4;
+    }
+    
+    pub fn write_u64(&mut self, x:u64) {
+        unsafe { 
+            std::ptr::write_unaligned(self.at(self.pos, 8) as *mut u64, x);
+        }
+        self.pos = self.pos + 8;
+    }
+    
+    pub fn write<T>(&mut self, x:T) {
+        unsafe { 
+            std::ptr::write_unaligned(self.at(self.pos, std::mem::size_of::<T>()) as *mut T, x);
+        }
+        self.pos = self.pos + std::mem::size_of::<T>() as isize;
+    }
+    
+    pub fn write_bytes(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        
+        for b in buf {
+            if self.pos >= self.size as isize {
+                return Err(Error::new(ErrorKind::AddrNotAvailable, "Ran out of buffer room."));
+            }
+            unsafe { *self.buff.offset(self.pos) = *b; }
+            self.pos += 1;
+            
+        }
+        Ok(buf.len())
+    }
+    
+}
+
+
+
+
+impl Index<usize> for CodeBuff {
+    type Output = u8;
+
+    fn index(&self, _index: usize) -> &u8 {
+        unsafe {&*self.at(_index as isize, 1) }
+    }
+}
+
+impl IndexMut<usize> for CodeBuff {
+    fn index_mut(&mut self, _index: usize) -> &mut u8 {
+        unsafe {&mut *self.at(_index as isize, 1) }
+    }
+}
+
+impl Drop for CodeBuff {
+    #[cfg(windows)]
+    fn drop(&mut self){
+        use kernel32::VirtualFree;
+        use std::os::raw::c_void;
+        use winapi::winnt::{MEM_RELEASE};
+        if !self.buff.is_null() {
+            let ret = unsafe {
+                VirtualFree(self.buff as *mut c_void, 0, MEM_RELEASE)
+            };
+            if ret == 0{
+                println!("VirtualFree failed: {}", Error::last_os_error())
+            }
+        }
+    }
+    #[cfg(unix)]
+    fn drop(&mut self){
+        if !self.buff.is_null() {
+            let ret = unsafe {
+                libc::munmap(self.buff as *mut libc::c_void, self.size as usize)
+            };
+            if ret != 0 {
+                println!("munmap failed: {}", Error::last_os_error())
+            }
+        }
+    }
+}
+
+
+pub mod bf;
+pub mod emitter;
+pub mod exec;
+pub mod jit;
+pub mod bounds;
+pub mod guard;
+pub mod snapshot;
+pub mod profile;
+pub mod debugger;
+pub mod tiered;
+pub mod bytecode;
+pub mod threaded;
+pub mod aot;
+pub mod cgen;
+pub mod wasm;
+pub mod difftest;
+pub mod fuzz;
+pub mod bench;
+pub mod repl;
+pub mod cli;
+
+
+#[cfg(test)]
+mod tests {
+    use super::CodeBuff;
+
+    #[test]
+    #[should_panic(expected = "out of range")]
+    fn writes_stay_in_the_buffer() {
+        let mut cb = CodeBuff::new(1).unwrap();
+        let end = cb.get_size() as isize;
+        cb.set_position(end - 2);
+        cb.write_u32(0);
+    }
+}
@@ -0,0 +1,125 @@
+use std::io::{self, Write};
+
+use bf::{Opcode, Program};
+
+
+//How many of the hottest ops and loops the report lists
+const REPORT_ROWS: usize = 20;
+
+
+//Execution counts per op. Everything else in the report is derived from them:
+//a loop is entered as often as its LoopEnter runs and iterates as often as the
+//first op of its body runs.
+pub struct Profile {
+    pub counts: Vec<u64>,
+}
+
+struct LoopStats {
+    enter: usize,
+    entries: u64,
+    iterations: u64,
+    inside: u64,
+}
+
+fn kind(op: &Opcode) -> usize {
+    match *op {
+        Opcode::Ptr(_)       => 0,
+        Opcode::Byte(_)      => 1,
+        Opcode::LoopEnter(_) => 2,
+        Opcode::LoopExit(_)  => 3,
+        Opcode::Out          => 4,
+        Opcode::In           => 5,
+        Opcode::Debug        => 6,
+    }
+}
+
+const KIND_NAMES: [&'static str; 7] = ["Ptr", "Byte", "LoopEnter", "LoopExit", "Out", "In", "Debug"];
+
+
+fn percent(x: u64, total: u64) -> f64 {
+    if total == 0 { 0.0 } else { x as f64 * 100.0 / total as f64 }
+}
+
+impl Profile {
+    pub fn new(len: usize) -> Profile {
+        Profile {counts: vec![0; len]}
+    }
+
+    fn loops(&self, prog: &Program) -> Vec<LoopStats> {
+        let mut loops = vec![];
+        for (i, op) in prog.ops.iter().enumerate() {
+            if let Opcode::LoopEnter(x) = *op {
+                loops.push(LoopStats {
+                    enter: i,
+                    entries: self.counts[i],
+                    iterations: self.counts[i + 1],
+                    inside: self.counts[i..x + 1].iter().sum(),
+                });
+            }
+        }
+        loops
+    }
+
+    pub fn report(&self, prog: &Program, w: &mut dyn Write) -> io::Result<()> {
+        let total: u64 = self.counts.iter().sum();
+        writeln!(w, "Profile: {} ops executed", total)?;
+
+        let mut kinds = [0u64; 7];
+        for (op, count) in prog.ops.iter().zip(self.counts.iter()) {
+            kinds[kind(op)] += *count;
+        }
+        writeln!(w, "")?;
+        writeln!(w, "Ops by kind:")?;
+        for (name, count) in KIND_NAMES.iter().zip(kinds.iter()) {
+            writeln!(w, "  {:<10} {:>14} {:>7.2}%", name, count, percent(*count, total))?;
+        }
+
+        let mut hot: Vec<usize> = (0..prog.ops.len()).filter(|&i| self.counts[i] > 0).collect();
+        hot.sort_by(|&a, &b| self.counts[b].cmp(&self.counts[a]));
+        writeln!(w, "")?;
+        writeln!(w, "Hot ops:")?;
+        writeln!(w, "  {:>4} {:>10} {:>8} {:>14} {:>8}", "rank", "line:col", "op", "count", "share")?;
+        for (rank, &i) in hot.iter().take(REPORT_ROWS).enumerate() {
+            let (line, col) = prog.line_col(i);
+            writeln!(w, "  {:>4} {:>10} {:>8} {:>14} {:>7.2}%", rank + 1, format!("{}:{}", line, col),
+                     format!("{}", prog.ops[i]), self.counts[i], percent(self.counts[i], total))?;
+        }
+
+        let mut loops = self.loops(prog);
+        loops.retain(|l| l.entries > 0);
+        loops.sort_by(|a, b| b.inside.cmp(&a.inside));
+        writeln!(w, "")?;
+        writeln!(w, "Hot loops (ops executed inside, nested loops included):")?;
+        writeln!(w, "  {:>4} {:>10} {:>12} {:>14} {:>10} {:>14} {:>8}", "rank", "line:col", "entries", "iterations", "iter/entry", "ops inside", "share")?;
+        for (rank, l) in loops.iter().take(REPORT_ROWS).enumerate() {
+            let (line, col) = prog.line_col(l.enter);
+            writeln!(w, "  {:>4} {:>10} {:>12} {:>14} {:>10.1} {:>14} {:>7.2}%", rank + 1, format!("{}:{}", line, col),
+                     l.entries, l.iterations, l.iterations as f64 / l.entries as f64, l.inside, percent(l.inside, total))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bf::{OptimizedInterpreter, Program};
+    use exec::Engine;
+
+    #[test]
+    fn counts_loop_entries_and_iterations() {
+        let prog = Program::parse("++[>+++[>+<-]<-]").unwrap();
+        let mut interp = OptimizedInterpreter::new();
+        interp.load_program(&prog);
+        interp.enable_profiling();
+        interp.run_io(&mut &b""[..], &mut Vec::new()).unwrap();
+
+        let loops = interp.profile().unwrap().loops(&prog);
+        assert_eq!(loops.len(), 2);
+        assert_eq!((loops[0].enter, loops[0].entries, loops[0].iterations), (1, 1, 2));
+        assert_eq!((loops[1].enter, loops[1].entries, loops[1].iterations), (4, 2, 6));
+
+        let mut report = Vec::new();
+        interp.profile().unwrap().report(&prog, &mut report).unwrap();
+        assert!(String::from_utf8(report).unwrap().starts_with("Profile: 44 ops executed\n"));
+    }
+}
@@ -0,0 +1,339 @@
+use std::fs::File;
+use std::io::{self, BufRead, Read, Write};
+
+use bf::{Opcode, Program};
+use exec::{self, Config, EngineKind, ExecError};
+use snapshot::Snapshot;
+
+
+const HELP: &'static str = "\
+Lines of BF run against the same tape, lines with open brackets continue on the next one.
+Commands:
+  :reset            clear the tape, the pointer and queued input
+  :load FILE        run FILE against the tape
+  :ir [CODE]        show the ops CODE parses to (default the last line run)
+  :engine [E]       show or switch the engine: naive, optimized, bytecode, threaded,
+                    jit or tiered
+  :input TEXT       queue TEXT (plus a newline) for `,`
+  :tape [RADIUS]    show the cells around the pointer (default 8)
+  :help             print this
+  :quit             leave the REPL
+`,` reads queued input and gets EOF once it runs dry.";
+
+//Cells shown after every line, at most this far either side of the pointer
+const RADIUS: usize = 8;
+
+
+fn engine_name(kind: EngineKind) -> &'static str {
+    match kind {
+        EngineKind::Naive     => "naive",
+        EngineKind::Optimized => "optimized",
+        EngineKind::Jit       => "jit",
+        EngineKind::Tiered    => "tiered",
+        EngineKind::Bytecode  => "bytecode",
+        EngineKind::Threaded  => "threaded",
+    }
+}
+
+//How many brackets are still open at the end of src, negative when a `]`
+//has nothing to close
+fn open_brackets(src: &str) -> i32 {
+    let mut depth = 0;
+    for c in src.chars() {
+        match c {
+            '[' => depth += 1,
+            ']' => {
+                depth -= 1;
+                if depth < 0 {
+                    return depth;
+                }
+            },
+            _ => {},
+        }
+    }
+    depth
+}
+
+//Remembers whether the program's output ended a line, so the cells are
+//printed on their own line
+struct TrackedOutput<'a> {
+    out: &'a mut dyn Write,
+    last: Option<u8>,
+}
+
+impl<'a> Write for TrackedOutput<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.out.write(buf)?;
+        if n > 0 {
+            self.last = Some(buf[n - 1]);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+
+pub struct Repl {
+    config: Config,
+    tape: Vec<u32>,
+    mem_ptr: usize,
+    steps: u64,
+    //lines read while brackets are still open
+    pending: String,
+    last: Option<Program>,
+    input: Vec<u8>,
+}
+
+impl Repl {
+    pub fn new(config: &Config) -> Repl {
+        Repl {config: config.clone(), tape: vec![0; config.tape_size], mem_ptr: 0, steps: 0,
+              pending: String::new(), last: None, input: vec![]}
+    }
+
+    pub fn reset(&mut self) {
+        self.tape = vec![0; self.config.tape_size];
+        self.mem_ptr = 0;
+        self.steps = 0;
+        self.pending.clear();
+        self.input.clear();
+    }
+
+    pub fn tape(&self) -> &[u32] {
+        &self.tape
+    }
+
+    pub fn mem_ptr(&self) -> usize {
+        self.mem_ptr
+    }
+
+    pub fn set_engine(&mut self, kind: EngineKind) {
+        self.config.engine = kind;
+    }
+
+    //Runs src against the tape. The tape keeps whatever the program did
+    //before an error, like it would in a longer program.
+    pub fn eval(&mut self, src: &str, out: &mut dyn Write) -> Result<(), ExecError> {
+        let prog = Program::parse_dialect(src, self.config.dialect).map_err(ExecError::Parse)?;
+        let mut engine = exec::create_engine(&prog, &self.config)?;
+        engine.restore(&Snapshot {
+            program_hash: engine.program_hash(),
+            tape: self.tape.clone(),
+            mem_ptr: self.mem_ptr,
+            ip: 0,
+            steps: 0,
+            input_pos: 0,
+            output_pos: 0,
+        })?;
+
+        let result = match prog.input {
+            Some(ref input) => engine.run_io(&mut &input[..], out),
+            None => {
+                let mut input = &self.input[..];
+                let result = engine.run_io(&mut input, out);
+                self.input = input.to_vec();
+                result
+            },
+        };
+        self.tape = engine.tape().to_vec();
+        self.mem_ptr = engine.mem_ptr();
+        self.steps = engine.steps();
+        self.last = Some(prog);
+        result
+    }
+
+    //The cells between the first and last nonzero ones, always including the
+    //pointer, clipped to radius cells either side of it
+    pub fn show_cells(&self, radius: usize, out: &mut dyn Write) -> io::Result<()> {
+        let ptr = self.mem_ptr;
+        let first = self.tape.iter().position(|&c| c != 0).map_or(ptr, |i| if i < ptr { i } else { ptr });
+        let last = self.tape.iter().rposition(|&c| c != 0).map_or(ptr, |i| if i > ptr { i } else { ptr });
+        let first = if first + radius < ptr { ptr - radius } else { first };
+        let last = if last > ptr + radius { ptr + radius } else { last };
+
+        if self.tape[..first].iter().any(|&c| c != 0) {
+            write!(out, "... ")?;
+        }
+        for i in first..last + 1 {
+            if i == ptr {
+                write!(out, "[{}]:{} ", i, self.tape[i])?;
+            }else{
+                write!(out, "{}:{} ", i, self.tape[i])?;
+            }
+        }
+        if self.tape[last + 1..].iter().any(|&c| c != 0) {
+            write!(out, "... ")?;
+        }
+        writeln!(out, "({} ops)", self.steps)
+    }
+
+    fn show_ir(&self, prog: &Program, out: &mut dyn Write) -> io::Result<()> {
+        let mut depth = 0;
+        for (ip, op) in prog.ops.iter().enumerate() {
+            if let Opcode::LoopExit(_) = *op {
+                depth -= 1;
+            }
+            writeln!(out, "{:>6}  {}{}", ip, "  ".repeat(depth), op)?;
+            if let Opcode::LoopEnter(_) = *op {
+                depth += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn run_and_show(&mut self, src: &str, out: &mut dyn Write) -> io::Result<()> {
+        let (result, last) = {
+            let mut tracked = TrackedOutput {out: out, last: None};
+            let result = self.eval(src, &mut tracked);
+            (result, tracked.last)
+        };
+        if last.map_or(false, |c| c != b'\n') {
+            writeln!(out, "")?;
+        }
+        if let Err(err) = result {
+            writeln!(out, "Error: {}", err)?;
+        }
+        self.show_cells(RADIUS, out)
+    }
+
+    fn command(&mut self, line: &str, out: &mut dyn Write) -> io::Result<bool> {
+        let mut args = line[1..].split_whitespace();
+        let cmd = match args.next() {
+            Some(cmd) => cmd,
+            None => return Ok(true),
+        };
+
+        match cmd {
+            "reset" => {
+                self.reset();
+                self.show_cells(RADIUS, out)?;
+            },
+            "load" => {
+                match args.next() {
+                    Some(path) => {
+                        let mut src = String::new();
+                        match File::open(path).and_then(|mut f| f.read_to_string(&mut src)) {
+                            Ok(_) => self.run_and_show(&src, out)?,
+                            Err(err) => writeln!(out, "Could not read {}: {}", path, err)?,
+                        }
+                    },
+                    None => writeln!(out, "Expected a file name")?,
+                }
+            },
+            "ir" => {
+                let code = line.trim_start()[1 + "ir".len()..].trim();
+                if code.is_empty() {
+                    match self.last.clone() {
+                        Some(prog) => self.show_ir(&prog, out)?,
+                        None => writeln!(out, "Nothing has run yet")?,
+                    }
+                }else{
+                    match Program::parse_dialect(code, self.config.dialect) {
+                        Ok(prog) => self.show_ir(&prog, out)?,
+                        Err(err) => writeln!(out, "Error: {}", err)?,
+                    }
+                }
+            },
+            "engine" => {
+                let kind = match args.next() {
+                    Some("naive")     => EngineKind::Naive,
+                    Some("optimized") => EngineKind::Optimized,
+                    Some("jit")       => EngineKind::Jit,
+                    Some("tiered")    => EngineKind::Tiered,
+                    Some("bytecode")  => EngineKind::Bytecode,
+                    Some("threaded")  => EngineKind::Threaded,
+                    Some(e) => {
+                        writeln!(out, "Unknown engine `{}`", e)?;
+                        return Ok(true);
+                    },
+                    None => self.config.engine,
+                };
+                self.set_engine(kind);
+                writeln!(out, "Engine: {}", engine_name(kind))?;
+            },
+            "input" => {
+                let text = line.trim_start()[1 + "input".len()..].trim_start().trim_end_matches(&['\r', '\n'][..]);
+                self.input.extend_from_slice(text.as_bytes());
+                self.input.push(b'\n');
+            },
+            "tape" => {
+                let radius = args.next().and_then(|a| a.parse::<usize>().ok()).unwrap_or(RADIUS);
+                self.show_cells(radius, out)?;
+            },
+            "h" | "help" => writeln!(out, "{}", HELP)?,
+            "q" | "quit" => return Ok(false),
+            _ => writeln!(out, "Unknown command `:{}`, try `:help`", cmd)?,
+        }
+        Ok(true)
+    }
+
+    //Returns false once the user quits
+    pub fn line(&mut self, line: &str, out: &mut dyn Write) -> io::Result<bool> {
+        if self.pending.is_empty() && line.trim_start().starts_with(':') {
+            return self.command(line.trim_start(), out);
+        }
+
+        self.pending.push_str(line);
+        if !self.pending.ends_with('\n') {
+            self.pending.push('\n');
+        }
+        if open_brackets(&self.pending) > 0 {
+            return Ok(true);
+        }
+        let src = ::std::mem::replace(&mut self.pending, String::new());
+        self.run_and_show(&src, out)?;
+        Ok(true)
+    }
+
+    pub fn run_commands(&mut self, commands: &mut dyn BufRead, out: &mut dyn Write) -> io::Result<()> {
+        loop {
+            write!(out, "{}", if self.pending.is_empty() { "bf> " } else { "... " })?;
+            out.flush()?;
+            let mut line = String::new();
+            if commands.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            if !self.line(&line, out)? {
+                return Ok(());
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use exec::{Config, EngineKind};
+    use super::Repl;
+
+    //The tape carries over from line to line, whichever engine runs them
+    #[test]
+    fn keeps_the_tape() {
+        let mut r = Repl::new(&Config::new());
+        let mut out = vec![];
+        r.eval("++++++++[>+++++++++<-]>.", &mut out).unwrap();
+        r.set_engine(EngineKind::Jit);
+        r.eval(">+++[-<+>]<.", &mut out).unwrap();
+        assert_eq!(out, b"HK");
+        assert_eq!((r.mem_ptr(), r.tape()[1], r.tape()[2]), (1, 75, 0));
+
+        r.reset();
+        assert_eq!(r.mem_ptr(), 0);
+        assert!(r.tape().iter().all(|&c| c == 0));
+    }
+
+    #[test]
+    fn runs_commands() {
+        let mut r = Repl::new(&Config::new());
+        let mut out = vec![];
+        let script = "++++++++[>+++++++++\n<-]>.\n:engine bytecode\n:input hi\n,.,.\n";
+        r.run_commands(&mut script.as_bytes(), &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("bf> ... H\n"), "{}", out);
+        assert!(out.contains("Engine: bytecode\n"), "{}", out);
+        assert!(out.contains("hi\n"), "{}", out);
+        assert_eq!((r.mem_ptr(), r.tape()[1]), (1, b'i' as u32));
+    }
+}
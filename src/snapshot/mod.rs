@@ -0,0 +1,213 @@
+extern crate byteorder;
+
+use std::fs::File;
+use std::io::{self, Read, Write, Error, ErrorKind};
+use std::path::Path;
+
+use self::byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use exec::ExecError;
+
+
+const MAGIC: &'static [u8; 4] = b"BFSN";
+const VERSION: u8 = 1;
+//Longest tape a snapshot file may claim, 1GiB of cells
+const MAX_TAPE_LEN: usize = 1 << 28;
+
+
+//Everything needed to continue a run later, possibly in another process.
+//input_pos/output_pos tell the caller how much of the I/O streams the run
+//had already consumed/produced when the snapshot was taken.
+#[derive(Eq, PartialEq, Debug, Clone)]
+pub struct Snapshot {
+    pub program_hash: u64,
+    pub tape: Vec<u32>,
+    pub mem_ptr: usize,
+    pub ip: usize,
+    pub steps: u64,
+    pub input_pos: u64,
+    pub output_pos: u64,
+}
+
+
+//FNV-1a, stable across processes and builds unlike the std hashers
+pub fn hash_bytes(hash: u64, bytes: &[u8]) -> u64 {
+    let mut h = hash;
+    for b in bytes {
+        h ^= *b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h
+}
+
+pub const HASH_INIT: u64 = 0xcbf29ce484222325;
+
+
+fn write_varint(w: &mut dyn Write, x: u64) -> io::Result<()> {
+    let mut x = x;
+    loop {
+        if x < 0x80 {
+            return w.write_u8(x as u8);
+        }
+        w.write_u8((x as u8 & 0x7f) | 0x80)?;
+        x >>= 7;
+    }
+}
+
+fn read_varint(r: &mut dyn Read) -> io::Result<u64> {
+    let mut x = 0u64;
+    let mut shift = 0;
+    loop {
+        let b = r.read_u8()?;
+        if shift > 63 {
+            return Err(Error::new(ErrorKind::InvalidData, "Varint too long"));
+        }
+        x |= ((b & 0x7f) as u64) << shift;
+        if b & 0x80 == 0 {
+            return Ok(x);
+        }
+        shift += 7;
+    }
+}
+
+
+impl Snapshot {
+    //Header, then varint fields, then the tape as (zero run, literal run) pairs
+    pub fn write_to(&self, w: &mut dyn Write) -> io::Result<()> {
+        w.write_all(MAGIC)?;
+        w.write_u8(VERSION)?;
+        w.write_u64::<LittleEndian>(self.program_hash)?;
+        write_varint(w, self.mem_ptr as u64)?;
+        write_varint(w, self.ip as u64)?;
+        write_varint(w, self.steps)?;
+        write_varint(w, self.input_pos)?;
+        write_varint(w, self.output_pos)?;
+        write_varint(w, self.tape.len() as u64)?;
+
+        let mut i = 0;
+        while i < self.tape.len() {
+            let zeros = self.tape[i..].iter().take_while(|&&c| c == 0).count();
+            i += zeros;
+            let literals = self.tape[i..].iter().take_while(|&&c| c != 0).count();
+            write_varint(w, zeros as u64)?;
+            write_varint(w, literals as u64)?;
+            for c in &self.tape[i..i + literals] {
+                write_varint(w, *c as u64)?;
+            }
+            i += literals;
+        }
+        Ok(())
+    }
+
+    pub fn read_from(r: &mut dyn Read) -> io::Result<Snapshot> {
+        let mut magic = [0u8; 4];
+        r.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(Error::new(ErrorKind::InvalidData, "Not a snapshot file"));
+        }
+        if r.read_u8()? != VERSION {
+            return Err(Error::new(ErrorKind::InvalidData, "Unsupported snapshot version"));
+        }
+
+        let program_hash = r.read_u64::<LittleEndian>()?;
+        let mem_ptr = read_varint(r)? as usize;
+        let ip = read_varint(r)? as usize;
+        let steps = read_varint(r)?;
+        let input_pos = read_varint(r)?;
+        let output_pos = read_varint(r)?;
+        let tape_len = read_varint(r)?;
+        if tape_len > MAX_TAPE_LEN as u64 {
+            return Err(Error::new(ErrorKind::InvalidData, "Tape is too long"));
+        }
+        let tape_len = tape_len as usize;
+
+        //Grown as runs are read instead of trusting the length up front
+        let mut tape = Vec::<u32>::new();
+        while tape.len() < tape_len {
+            let room = (tape_len - tape.len()) as u64;
+            let zeros = read_varint(r)?;
+            let literals = read_varint(r)?;
+            if zeros > room || literals > room - zeros {
+                return Err(Error::new(ErrorKind::InvalidData, "Tape runs past the tape length"));
+            }
+            let len = tape.len() + zeros as usize;
+            tape.resize(len, 0);
+            for _ in 0..literals {
+                tape.push(read_varint(r)? as u32);
+            }
+        }
+
+        Ok(Snapshot {
+            program_hash: program_hash,
+            tape: tape,
+            mem_ptr: mem_ptr,
+            ip: ip,
+            steps: steps,
+            input_pos: input_pos,
+            output_pos: output_pos,
+        })
+    }
+
+    //Validates the snapshot against the program an engine has loaded
+    pub fn check(&self, program_hash: u64, prog_len: usize) -> Result<(), ExecError> {
+        if self.program_hash != program_hash {
+            Err(ExecError::Snapshot("Snapshot was taken from a different program"))
+        }else if self.ip > prog_len {
+            Err(ExecError::Snapshot("Instruction pointer is past the end of the program"))
+        }else if self.mem_ptr >= self.tape.len() {
+            Err(ExecError::Snapshot("Pointer is outside of the tape"))
+        }else{
+            Ok(())
+        }
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut f = File::create(path)?;
+        let mut buf = vec![];
+        self.write_to(&mut buf)?;
+        f.write_all(&buf)
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Snapshot> {
+        let mut f = File::open(path)?;
+        let mut buf = vec![];
+        f.read_to_end(&mut buf)?;
+        Snapshot::read_from(&mut &buf[..])
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips() {
+        let mut tape = vec![0; 1000];
+        tape[3] = 7;
+        tape[500] = 0xffff_ffff;
+        let snap = Snapshot {program_hash: 42, tape: tape, mem_ptr: 500, ip: 9, steps: 1 << 40, input_pos: 3, output_pos: 5};
+        let mut buf = vec![];
+        snap.write_to(&mut buf).unwrap();
+        assert_eq!(Snapshot::read_from(&mut &buf[..]).unwrap(), snap);
+    }
+
+    //A crafted length must fail to read, not abort the allocation
+    #[test]
+    fn rejects_huge_tapes() {
+        let mut buf = vec![];
+        buf.extend_from_slice(MAGIC);
+        buf.push(VERSION);
+        buf.extend_from_slice(&[0; 8]);
+        buf.extend_from_slice(&[0, 0, 0, 0, 0]);
+        buf.extend_from_slice(&[0xfc, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x3f]);
+        assert!(Snapshot::read_from(&mut &buf[..]).is_err());
+
+        let mut buf = vec![];
+        Snapshot {program_hash: 0, tape: vec![1; 4], mem_ptr: 0, ip: 0, steps: 0, input_pos: 0, output_pos: 0}.write_to(&mut buf).unwrap();
+        let len = buf.len();
+        buf.truncate(len - 6);
+        buf.extend_from_slice(&[0xff, 0xff, 0xff, 0x7f, 0]);
+        assert!(Snapshot::read_from(&mut &buf[..]).is_err());
+    }
+}
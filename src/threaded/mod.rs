@@ -0,0 +1,585 @@
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+
+use bf::{self, dump_tape, CellIo, Opcode, Program};
+use bytecode::{iterations, linear};
+use exec::{Config, Engine, ExecError, Limiter, Limits};
+use snapshot::Snapshot;
+
+
+//Running a loop takes a few nested calls on the native stack, so programs
+//nesting deeper than this are turned down instead of overflowing it
+pub const MAX_NESTING: usize = 1000;
+
+
+//What the compiled code runs on, borrowed from the engine and run_io's
+//arguments for the length of a run. The pointer is passed from call to call
+//instead, so it stays in a register.
+struct Machine<'a> {
+    tape: &'a mut [u32],
+    steps: u64,
+    mask: u32,
+    io: CellIo,
+    input: &'a mut dyn Read,
+    output: &'a mut dyn Write,
+    limiter: &'a Limiter,
+    next_poll: u64,
+    //why, at which op and where the pointer was, once code returns Stop
+    error: Option<ExecError>,
+    ip: usize,
+    ptr: usize,
+}
+
+//Returned all the way up once the program can't go on. Keeping the error in
+//the Machine makes every call return just the pointer or this.
+struct Stop;
+
+impl<'a> Machine<'a> {
+    fn stop(&mut self, ip: usize, ptr: usize, err: ExecError) -> Stop {
+        self.ip = ip;
+        self.ptr = ptr;
+        self.error = Some(err);
+        Stop
+    }
+
+    //Budget and cancellation check, ip being where to pick up again
+    fn poll(&mut self, ip: usize, ptr: usize) -> Result<(), Stop> {
+        if self.steps >= self.next_poll {
+            match self.limiter.poll(self.steps) {
+                Ok(n) => self.next_poll = n,
+                Err(int) => {
+                    let err = int.to_error(ip, ptr, self.tape);
+                    return Err(self.stop(ip, ptr, err));
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+//Takes the pointer and returns where it ends up
+type Code = Box<dyn Fn(&mut Machine, usize) -> Result<usize, Stop>>;
+
+//A compiled op or run of ops, with the ops start..=end it covers
+struct Node {
+    start: usize,
+    end: usize,
+    //the body, for a loop
+    body: Option<Rc<Block>>,
+}
+
+//Code for a sequence of ops. Running it is a call per node, the nodes
+//are only needed to find where to resume.
+struct Block {
+    code: Vec<Code>,
+    nodes: Vec<Node>,
+}
+
+
+fn run_from(block: &Block, first: usize, m: &mut Machine, mut ptr: usize) -> Result<usize, Stop> {
+    for code in &block.code[first..] {
+        ptr = code(m, ptr)?;
+    }
+    Ok(ptr)
+}
+
+//Runs a loop whose head already found the cell nonzero
+fn iterate(body: &Block, body_ip: usize, m: &mut Machine, mut ptr: usize) -> Result<usize, Stop> {
+    loop {
+        for code in &body.code {
+            ptr = code(m, ptr)?;
+        }
+        m.steps += 1;
+        if m.tape[ptr] == 0 {
+            return Ok(ptr);
+        }
+        m.poll(body_ip, ptr)?;
+    }
+}
+
+//Picks up at ip, which has to start a node in block or lie inside one of its loops
+fn resume(block: &Block, ip: usize, m: &mut Machine, mut ptr: usize) -> Result<usize, Stop> {
+    for (i, node) in block.nodes.iter().enumerate() {
+        if node.start == ip {
+            return run_from(block, i, m, ptr);
+        }
+        if let Some(ref body) = node.body {
+            if node.start < ip && ip <= node.end {
+                //the loop's exit is where an empty body starts
+                if ip < node.end {
+                    ptr = resume(body, ip, m, ptr)?;
+                }
+                m.steps += 1;
+                if m.tape[ptr] != 0 {
+                    m.poll(node.start + 1, ptr)?;
+                    ptr = iterate(body, node.start + 1, m, ptr)?;
+                }
+                return run_from(block, i + 1, m, ptr);
+            }
+        }
+    }
+    Err(m.stop(ip, ptr, ExecError::Snapshot("The threaded interpreter cannot resume at this op")))
+}
+
+fn can_resume(block: &Block, ip: usize) -> bool {
+    block.nodes.iter().any(|node| match node.body {
+        _ if node.start == ip => true,
+        Some(ref body) => node.start < ip && (ip == node.end || ip < node.end && can_resume(body, ip)),
+        None => false,
+    })
+}
+
+
+//A run of Byte and Ptr ops starting at ip. When the pointer stays on the
+//tape throughout, the adds go straight to their cells, otherwise the ops
+//run one by one to stop at the one leaving it.
+fn compile_run(ops: &[Opcode], ip: usize) -> Code {
+    let mut offset = 0isize;
+    let mut min = 0;
+    let mut max = 0;
+    let mut adds: Vec<(isize, u32)> = vec![];
+    for op in ops {
+        match *op {
+            Opcode::Ptr(x) => {
+                offset += x as isize;
+                min = if offset < min { offset } else { min };
+                max = if offset > max { offset } else { max };
+            },
+            Opcode::Byte(x) => match adds.iter().position(|a| a.0 == offset) {
+                Some(i) => adds[i].1 = adds[i].1.wrapping_add(x as u32),
+                None => adds.push((offset, x as u32)),
+            },
+            _ => unreachable!(),
+        }
+    }
+    let shift = offset;
+    let n = ops.len() as u64;
+
+    match (ops, &adds[..]) {
+        (&[Opcode::Byte(_)], &[(_, x)]) => Box::new(move |m, ptr| {
+            m.tape[ptr] = m.tape[ptr].wrapping_add(x) & m.mask;
+            m.steps += 1;
+            Ok(ptr)
+        }),
+        (&[Opcode::Ptr(x)], _) => Box::new(move |m, ptr| {
+            let p = ptr.wrapping_add(x as usize);
+            if p >= m.tape.len() {
+                return Err(m.stop(ip, ptr, ExecError::PointerOutOfRange{ip: ip}));
+            }
+            m.steps += 1;
+            Ok(p)
+        }),
+        //the common >+< and +> shapes, without going through the list
+        (_, &[(at, x)]) => {
+            let ops = ops.to_vec();
+            Box::new(move |m, ptr| {
+                let p = ptr as isize;
+                if p + min < 0 || p + max >= m.tape.len() as isize {
+                    return run_ops(&ops, ip, m, ptr);
+                }
+                let cell = &mut m.tape[(p + at) as usize];
+                *cell = cell.wrapping_add(x) & m.mask;
+                m.steps += n;
+                Ok((p + shift) as usize)
+            })
+        },
+        _ => {
+            let ops = ops.to_vec();
+            Box::new(move |m, ptr| {
+                let p = ptr as isize;
+                if p + min < 0 || p + max >= m.tape.len() as isize {
+                    return run_ops(&ops, ip, m, ptr);
+                }
+                for &(at, x) in &adds {
+                    let cell = &mut m.tape[(p + at) as usize];
+                    *cell = cell.wrapping_add(x) & m.mask;
+                }
+                m.steps += n;
+                Ok((p + shift) as usize)
+            })
+        },
+    }
+}
+
+//A run of Byte and Ptr ops one at a time, for when the pointer leaves the tape
+fn run_ops(ops: &[Opcode], ip: usize, m: &mut Machine, mut ptr: usize) -> Result<usize, Stop> {
+    for (i, op) in ops.iter().enumerate() {
+        match *op {
+            Opcode::Ptr(x) => {
+                let p = ptr.wrapping_add(x as usize);
+                if p >= m.tape.len() {
+                    return Err(m.stop(ip + i, ptr, ExecError::PointerOutOfRange{ip: ip + i}));
+                }
+                ptr = p;
+            },
+            Opcode::Byte(x) => m.tape[ptr] = m.tape[ptr].wrapping_add(x as u32) & m.mask,
+            _ => {},
+        }
+        m.steps += 1;
+    }
+    Ok(ptr)
+}
+
+fn compile_op(op: Opcode, ip: usize) -> Code {
+    match op {
+        Opcode::Out => Box::new(move |m, ptr| {
+            let c = m.tape[ptr];
+            match m.io.write(&mut *m.output, c) {
+                Ok(_) => {
+                    m.steps += 1;
+                    Ok(ptr)
+                },
+                Err(err) => Err(m.stop(ip, ptr, err)),
+            }
+        }),
+        Opcode::In => Box::new(move |m, ptr| {
+            let c = m.tape[ptr];
+            match m.io.read(&mut *m.input, c) {
+                Ok(c) => {
+                    m.tape[ptr] = c;
+                    m.steps += 1;
+                    Ok(ptr)
+                },
+                Err(err) => Err(m.stop(ip, ptr, err)),
+            }
+        }),
+        Opcode::Debug => Box::new(move |m, ptr| {
+            let _ = dump_tape(&mut io::stderr(), m.tape, ptr);
+            m.steps += 1;
+            Ok(ptr)
+        }),
+        _ => unreachable!(),
+    }
+}
+
+//[-] and [+], by what one iteration adds. A budget running out halfway
+//stops in front of the loop.
+fn compile_clear(delta: u32, ip: usize) -> Code {
+    Box::new(move |m, ptr| {
+        let c = m.tape[ptr];
+        let cost = 1 + if c != 0 { 2 * iterations(c, delta, m.mask) } else { 0 };
+        if m.steps + cost > m.next_poll {
+            match m.limiter.poll(m.steps + cost - 1) {
+                Ok(n) => m.next_poll = n,
+                Err(int) => {
+                    let err = int.to_error(ip, ptr, m.tape);
+                    return Err(m.stop(ip, ptr, err));
+                },
+            }
+        }
+        m.tape[ptr] = 0;
+        m.steps += cost;
+        Ok(ptr)
+    })
+}
+
+//[>], [<<] and the like
+fn compile_scan(x: i32, body_ip: usize) -> Code {
+    Box::new(move |m, mut ptr| {
+        m.steps += 1;
+        while m.tape[ptr] != 0 {
+            let p = ptr.wrapping_add(x as usize);
+            if p >= m.tape.len() {
+                return Err(m.stop(body_ip, ptr, ExecError::PointerOutOfRange{ip: body_ip}));
+            }
+            ptr = p;
+            m.steps += 2;
+            if m.tape[ptr] != 0 {
+                m.poll(body_ip, ptr)?;
+            }
+        }
+        Ok(ptr)
+    })
+}
+
+fn compile_loop(ops: &[Opcode], ip: usize, exit: usize, mask: u32, body: &Rc<Block>) -> Code {
+    let body = body.clone();
+    let body_ip = ip + 1;
+    if let &[Opcode::Ptr(x)] = &ops[ip + 1..exit] {
+        return compile_scan(x, body_ip);
+    }
+
+    //a loop adding multiples of its head cell to other cells does it all at
+    //once, unless the pointer would leave the tape or the budget runs out
+    if let Some(l) = linear(&ops[ip + 1..exit], mask) {
+        return Box::new(move |m, ptr| {
+            m.steps += 1;
+            let c = m.tape[ptr];
+            if c == 0 {
+                return Ok(ptr);
+            }
+            let q = ptr as isize;
+            let mut fits = q + l.min >= 0 && q + l.max < m.tape.len() as isize;
+            let n = iterations(c, l.delta, m.mask);
+            let cost = n * (l.body + 1);
+            if fits && m.steps + cost > m.next_poll {
+                match m.limiter.poll(m.steps + cost - 1) {
+                    Ok(n) => m.next_poll = n,
+                    Err(_) => fits = false,
+                }
+            }
+            if !fits {
+                return iterate(&body, body_ip, m, ptr);
+            }
+            for &(at, x) in &l.terms {
+                let cell = &mut m.tape[(q + at) as usize];
+                *cell = cell.wrapping_add(x.wrapping_mul(n as u32)) & m.mask;
+            }
+            m.tape[ptr] = 0;
+            m.steps += cost;
+            Ok(ptr)
+        });
+    }
+
+    Box::new(move |m, ptr| {
+        m.steps += 1;
+        if m.tape[ptr] == 0 {
+            return Ok(ptr);
+        }
+        iterate(&body, body_ip, m, ptr)
+    })
+}
+
+fn compile_block(ops: &[Opcode], start: usize, end: usize, mask: u32) -> Block {
+    let mut block = Block {code: vec![], nodes: vec![]};
+    let mut ip = start;
+    while ip < end {
+        match ops[ip] {
+            Opcode::LoopEnter(exit) => {
+                let body = match &ops[ip + 1..exit] {
+                    &[Opcode::Byte(1)] => {
+                        block.code.push(compile_clear(1, ip));
+                        None
+                    },
+                    &[Opcode::Byte(-1)] => {
+                        block.code.push(compile_clear(!0, ip));
+                        None
+                    },
+                    _ => {
+                        let body = Rc::new(compile_block(ops, ip + 1, exit, mask));
+                        block.code.push(compile_loop(ops, ip, exit, mask, &body));
+                        Some(body)
+                    },
+                };
+                block.nodes.push(Node {start: ip, end: exit, body: body});
+                ip = exit + 1;
+            },
+            Opcode::Ptr(_) | Opcode::Byte(_) => {
+                let mut last = ip + 1;
+                while last < end {
+                    match ops[last] {
+                        Opcode::Ptr(_) | Opcode::Byte(_) => last += 1,
+                        _ => break,
+                    }
+                }
+                block.code.push(compile_run(&ops[ip..last], ip));
+                block.nodes.push(Node {start: ip, end: last - 1, body: None});
+                ip = last;
+            },
+            op => {
+                block.code.push(compile_op(op, ip));
+                block.nodes.push(Node {start: ip, end: ip, body: None});
+                ip += 1;
+            },
+        }
+    }
+    block
+}
+
+
+//Compiles the program into nested Rust closures, each loop calling the
+//code of its body directly. Runs everywhere, unlike the JIT, and gives the
+//same results as OptimizedInterpreter.
+pub struct Threaded {
+    root: Block,
+    len: usize,
+    program_hash: u64,
+    mem: Vec<u32>,
+    mem_ptr: usize,
+    ip: usize,
+    steps: u64,
+    cell_mask: u32,
+    io: CellIo,
+    limits: Limits,
+}
+
+fn nesting(ops: &[Opcode]) -> usize {
+    let mut depth = 0;
+    let mut max = 0;
+    for op in ops {
+        match *op {
+            Opcode::LoopEnter(_) => {
+                depth += 1;
+                max = if depth > max { depth } else { max };
+            },
+            Opcode::LoopExit(_) => depth -= 1,
+            _ => {},
+        }
+    }
+    max
+}
+
+impl Threaded {
+    pub fn new(prog: &Program, config: &Config) -> Result<Threaded, ExecError> {
+        if nesting(&prog.ops) > MAX_NESTING {
+            return Err(ExecError::Config("Loops nest too deep for the threaded interpreter"));
+        }
+        Ok(Threaded {
+            root: compile_block(&prog.ops, 0, prog.ops.len(), config.cell_width.mask()),
+            len: prog.ops.len(),
+            program_hash: bf::hash_ops(&prog.ops),
+            mem: vec![0u32; config.tape_size],
+            mem_ptr: 0,
+            ip: 0,
+            steps: 0,
+            cell_mask: config.cell_width.mask(),
+            io: CellIo::new(config),
+            limits: config.limits.clone(),
+        })
+    }
+}
+
+impl Engine for Threaded {
+    fn run_io(&mut self, input: &mut dyn Read, output: &mut dyn Write) -> Result<(), ExecError> {
+        if self.ip == self.len {
+            return Ok(());
+        }
+        let limiter = self.limits.start();
+        let next_poll = match limiter.poll(self.steps) {
+            Ok(n) => n,
+            Err(int) => return Err(int.to_error(self.ip, self.mem_ptr, &self.mem)),
+        };
+
+        let mut m = Machine {
+            tape: &mut self.mem,
+            steps: self.steps,
+            mask: self.cell_mask,
+            io: self.io,
+            input: input,
+            output: output,
+            limiter: &limiter,
+            next_poll: next_poll,
+            error: None,
+            ip: self.len,
+            ptr: self.mem_ptr,
+        };
+        let res = if self.ip == 0 {
+            run_from(&self.root, 0, &mut m, self.mem_ptr)
+        }else{
+            resume(&self.root, self.ip, &mut m, self.mem_ptr)
+        };
+
+        self.ip = m.ip;
+        self.steps = m.steps;
+        self.io = m.io;
+        match res {
+            Ok(ptr) => {
+                self.mem_ptr = ptr;
+                Ok(())
+            },
+            Err(Stop) => {
+                self.mem_ptr = m.ptr;
+                Err(m.error.take().unwrap())
+            },
+        }
+    }
+
+    fn tape(&self) -> &[u32] {
+        &self.mem
+    }
+
+    fn mem_ptr(&self) -> usize {
+        self.mem_ptr
+    }
+
+    fn ip(&self) -> usize {
+        self.ip
+    }
+
+    fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    fn program_hash(&self) -> u64 {
+        self.program_hash
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            program_hash: self.program_hash,
+            tape: self.mem.clone(),
+            mem_ptr: self.mem_ptr,
+            ip: self.ip,
+            steps: self.steps,
+            input_pos: self.io.input_pos,
+            output_pos: self.io.output_pos,
+        }
+    }
+
+    //Runs of ops compile to a single closure, only their first op can be resumed at
+    fn restore(&mut self, snap: &Snapshot) -> Result<(), ExecError> {
+        snap.check(self.program_hash, self.len)?;
+        if snap.ip != self.len && !can_resume(&self.root, snap.ip) {
+            return Err(ExecError::Snapshot("The threaded interpreter cannot resume at this op"));
+        }
+        self.mem = snap.tape.clone();
+        self.mem_ptr = snap.mem_ptr;
+        self.ip = snap.ip;
+        self.steps = snap.steps;
+        self.io.input_pos = snap.input_pos;
+        self.io.output_pos = snap.output_pos;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use bf::Program;
+    use difftest;
+    use exec::{self, Config, EngineKind, ExecError};
+    use super::MAX_NESTING;
+
+    //Compiled to closures, the program still ends up where the optimized
+    //interpreter does, cell widths and tape ends included
+    #[test]
+    fn matches_the_optimized_interpreter() {
+        for case in difftest::builtin_corpus() {
+            let prog = Program::parse(&case.source).unwrap();
+            for base in difftest::configs() {
+                let mut config = base.clone();
+                config.engine = EngineKind::Optimized;
+                let want = exec::run(&prog, &case.input, &config);
+                if let Some(ExecError::BudgetExhausted{..}) = want.error {
+                    continue;
+                }
+                config.engine = EngineKind::Threaded;
+                let got = exec::run(&prog, &case.input, &config);
+                assert_eq!((&got.output, &got.tape, got.mem_ptr, &got.error),
+                           (&want.output, &want.tape, want.mem_ptr, &want.error), "{}", case.name);
+            }
+        }
+    }
+
+    fn nested(depth: usize) -> Program {
+        let source = format!("+{}-{}++++++++[>++++++++<-]>+.", "[".repeat(depth), "]".repeat(depth));
+        Program::parse(&source).unwrap()
+    }
+
+    //Each loop level is a few calls deep, the deepest allowed must still fit
+    //the 2MB stack test threads get
+    #[test]
+    fn turns_down_deep_nesting() {
+        let mut config = Config::new();
+        config.engine = EngineKind::Threaded;
+        let r = exec::run(&nested(MAX_NESTING), b"", &config);
+        assert_eq!((r.output, r.error), (b"A".to_vec(), None));
+
+        for &depth in &[MAX_NESTING + 1, 50000] {
+            match exec::run(&nested(depth), b"", &config).error {
+                Some(ExecError::Config(_)) => {},
+                err => panic!("{} loops deep: {:?}", depth, err),
+            }
+        }
+    }
+}
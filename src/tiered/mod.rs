@@ -0,0 +1,151 @@
+use std::io::{Read, Write};
+
+use bf::{Opcode, OptimizedInterpreter, Program};
+use exec::{Config, Engine, ExecError, Limits};
+use jit::Region;
+use snapshot::Snapshot;
+
+
+//How often the interpreter has to reach a loop before it gets compiled
+pub const HOT_LOOP_THRESHOLD: u32 = 1000;
+
+
+//Starts out interpreting and compiles loops once they turn hot. Native code
+//runs on the interpreter's own tape: it is entered at the head of the loop
+//(or of its body, when the loop warmed up while iterating) and hands back to
+//the interpreter when the loop exits.
+pub struct Tiered {
+    interp: OptimizedInterpreter,
+    ops: Vec<Opcode>,
+    config: Config,
+    limits: Limits,
+    //per LoopEnter, how often the interpreter entered or iterated the loop
+    heat: Vec<u32>,
+    //per LoopEnter, the compiled loop
+    regions: Vec<Option<Region>>,
+}
+
+impl Tiered {
+    pub fn new(prog: &Program, config: &Config) -> Tiered {
+        let mut interp = OptimizedInterpreter::with_config(config);
+        interp.load_program(prog);
+        Tiered {
+            interp: interp,
+            ops: prog.ops.clone(),
+            config: config.clone(),
+            limits: config.limits.clone(),
+            heat: vec![0; prog.ops.len()],
+            regions: (0..prog.ops.len()).map(|_| None).collect(),
+        }
+    }
+
+    pub fn compiled_loops(&self) -> usize {
+        self.regions.iter().filter(|r| r.is_some()).count()
+    }
+
+    //The loop ip is the head or the first body op of
+    fn loop_at(&self, ip: usize) -> Option<usize> {
+        match self.ops[ip] {
+            Opcode::LoopEnter(_) => Some(ip),
+            _ if ip > 0 => match self.ops[ip - 1] {
+                Opcode::LoopEnter(_) => Some(ip - 1),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    //Counts a visit to the loop, true once native code for it is ready.
+    //A loop that fails to compile stays interpreted.
+    fn warm_up(&mut self, enter: usize) -> bool {
+        if self.regions[enter].is_some() {
+            return true;
+        }
+        self.heat[enter] = self.heat[enter].saturating_add(1);
+        if self.heat[enter] != HOT_LOOP_THRESHOLD {
+            return false;
+        }
+        if let Opcode::LoopEnter(exit) = self.ops[enter] {
+            self.regions[enter] = Region::compile(&self.ops, enter, exit + 1, &self.config).ok();
+        }
+        self.regions[enter].is_some()
+    }
+}
+
+impl Engine for Tiered {
+    fn run_io(&mut self, input: &mut dyn Read, output: &mut dyn Write) -> Result<(), ExecError> {
+        let limiter = self.limits.start();
+        let mut next_poll = self.interp.steps();
+
+        while !self.interp.is_finished() {
+            if self.interp.steps() >= next_poll {
+                next_poll = match limiter.poll(self.interp.steps()) {
+                    Ok(n) => n,
+                    Err(int) => return Err(int.to_error(self.interp.ip(), self.interp.mem_ptr(), self.interp.tape())),
+                };
+            }
+
+            let ip = self.interp.ip();
+            if let Some(enter) = self.loop_at(ip) {
+                if self.warm_up(enter) {
+                    if let Some(ref region) = self.regions[enter] {
+                        self.interp.run_region(region, input, output, &limiter)?;
+                        continue;
+                    }
+                }
+            }
+            self.interp.step(input, output)?;
+        }
+        Ok(())
+    }
+
+    fn tape(&self) -> &[u32] {
+        self.interp.tape()
+    }
+
+    fn mem_ptr(&self) -> usize {
+        self.interp.mem_ptr()
+    }
+
+    fn ip(&self) -> usize {
+        self.interp.ip()
+    }
+
+    fn steps(&self) -> u64 {
+        self.interp.steps()
+    }
+
+    fn program_hash(&self) -> u64 {
+        self.interp.program_hash()
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        self.interp.snapshot()
+    }
+
+    fn restore(&mut self, snap: &Snapshot) -> Result<(), ExecError> {
+        self.interp.restore(snap)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use exec::{self, EngineKind};
+
+    #[test]
+    fn compiles_hot_loops_and_matches_the_interpreter() {
+        let prog = Program::parse("++++++++++++++++[>++++++++[>++++++++[>+>++<<-]<-]<-]>>>.>.<<[-]<[>++<-]>.").unwrap();
+        let mut tiered = Tiered::new(&prog, &Config::new());
+        let mut output = Vec::new();
+        tiered.run_io(&mut &b""[..], &mut output).unwrap();
+        assert!(tiered.compiled_loops() > 0);
+
+        let mut config = Config::new();
+        config.engine = EngineKind::Optimized;
+        let expected = exec::run(&prog, b"", &config);
+        assert_eq!(output, expected.output);
+        assert_eq!(tiered.tape(), &expected.tape[..]);
+        assert_eq!(tiered.mem_ptr(), expected.mem_ptr);
+    }
+}
@@ -0,0 +1,473 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+use bf::{Opcode, Program};
+use exec::{CellWidth, Config, EofPolicy};
+
+mod validate;
+mod wat;
+pub use self::validate::{decode, validate, Module};
+pub use self::wat::to_wat;
+
+
+//A module built from a program imports
+//
+//    env.put: (i32) -> ()     gets the cell of every `.`
+//    env.get: () -> i32       a byte for `,`, or a negative value at EOF
+//
+//and exports its tape as "memory" and "run", which executes the program and
+//returns the final pointer. Cells are stored at the configured width, so
+//narrow cells wrap by themselves. Moving the pointer off the tape traps, and
+//input embedded after a `!` is stored after the tape and read before `get`.
+
+pub const I32: u8 = 0x7f;
+
+const MAGIC: &'static [u8] = b"\0asm";
+const VERSION: &'static [u8] = &[1, 0, 0, 0];
+const PAGE_SIZE: usize = 65536;
+
+//Function indices, the imports come first
+const PUT: u32 = 0;
+const GET: u32 = 1;
+const RUN: u32 = 2;
+
+//Locals of run: the tape pointer as a byte address, the last read and the
+//read position in the embedded input
+const PTR: u32 = 0;
+const CHAR: u32 = 1;
+const INPUT_POS: u32 = 2;
+
+
+#[derive(Eq, PartialEq, Debug, Copy, Clone)]
+pub enum BlockType {
+    Empty,
+    I32,
+}
+
+//The instructions the backend uses, all on i32 values
+#[derive(Eq, PartialEq, Debug, Copy, Clone)]
+pub enum Instr {
+    Unreachable,
+    Block(BlockType),
+    Loop(BlockType),
+    If(BlockType),
+    Else,
+    End,
+    Br(u32),
+    BrIf(u32),
+    Call(u32),
+    LocalGet(u32),
+    LocalSet(u32),
+    LocalTee(u32),
+    //width and offset, always naturally aligned
+    Load(CellWidth, u32),
+    Store(CellWidth, u32),
+    Const(i32),
+    Eqz,
+    LtS,
+    LtU,
+    GeU,
+    Add,
+    Sub,
+    ShrU,
+}
+
+fn write_uleb(out: &mut Vec<u8>, mut x: u32) {
+    loop {
+        let byte = (x & 0x7f) as u8;
+        x >>= 7;
+        if x == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn write_sleb(out: &mut Vec<u8>, mut x: i32) {
+    loop {
+        let byte = (x & 0x7f) as u8;
+        x >>= 7;
+        if (x == 0 && byte & 0x40 == 0) || (x == -1 && byte & 0x40 != 0) {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn write_name(out: &mut Vec<u8>, name: &str) {
+    write_uleb(out, name.len() as u32);
+    out.extend_from_slice(name.as_bytes());
+}
+
+fn width_bytes(width: CellWidth) -> u32 {
+    match width {
+        CellWidth::U8  => 1,
+        CellWidth::U16 => 2,
+        CellWidth::U32 => 4,
+    }
+}
+
+//log2 of the width, the natural alignment
+fn width_shift(width: CellWidth) -> u32 {
+    match width {
+        CellWidth::U8  => 0,
+        CellWidth::U16 => 1,
+        CellWidth::U32 => 2,
+    }
+}
+
+impl BlockType {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.push(match *self {
+            BlockType::Empty => 0x40,
+            BlockType::I32   => I32,
+        });
+    }
+}
+
+impl Instr {
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        match *self {
+            Instr::Unreachable => out.push(0x00),
+            Instr::Block(t) => { out.push(0x02); t.encode(out); },
+            Instr::Loop(t)  => { out.push(0x03); t.encode(out); },
+            Instr::If(t)    => { out.push(0x04); t.encode(out); },
+            Instr::Else     => out.push(0x05),
+            Instr::End      => out.push(0x0b),
+            Instr::Br(l)    => { out.push(0x0c); write_uleb(out, l); },
+            Instr::BrIf(l)  => { out.push(0x0d); write_uleb(out, l); },
+            Instr::Call(f)  => { out.push(0x10); write_uleb(out, f); },
+            Instr::LocalGet(i) => { out.push(0x20); write_uleb(out, i); },
+            Instr::LocalSet(i) => { out.push(0x21); write_uleb(out, i); },
+            Instr::LocalTee(i) => { out.push(0x22); write_uleb(out, i); },
+            Instr::Load(w, offset) => {
+                out.push(match w { CellWidth::U8 => 0x2d, CellWidth::U16 => 0x2f, CellWidth::U32 => 0x28 });
+                write_uleb(out, width_shift(w));
+                write_uleb(out, offset);
+            },
+            Instr::Store(w, offset) => {
+                out.push(match w { CellWidth::U8 => 0x3a, CellWidth::U16 => 0x3b, CellWidth::U32 => 0x36 });
+                write_uleb(out, width_shift(w));
+                write_uleb(out, offset);
+            },
+            Instr::Const(x) => { out.push(0x41); write_sleb(out, x); },
+            Instr::Eqz  => out.push(0x45),
+            Instr::LtS  => out.push(0x48),
+            Instr::LtU  => out.push(0x49),
+            Instr::GeU  => out.push(0x4f),
+            Instr::Add  => out.push(0x6a),
+            Instr::Sub  => out.push(0x6b),
+            Instr::ShrU => out.push(0x76),
+        }
+    }
+}
+
+
+struct Compiler<'a> {
+    prog: &'a Program,
+    config: &'a Config,
+    body: Vec<Instr>,
+    tape_bytes: u32,
+}
+
+impl<'a> Compiler<'a> {
+    fn emit(&mut self, instrs: &[Instr]) {
+        self.body.extend_from_slice(instrs);
+    }
+
+    fn cell(&self) -> Instr {
+        Instr::Load(self.config.cell_width, 0)
+    }
+
+    //Reads into CHAR, from the embedded input while it lasts
+    fn read(&mut self) {
+        let prog = self.prog;
+        match prog.input {
+            Some(ref input) => {
+                let base = self.tape_bytes;
+                self.emit(&[Instr::LocalGet(INPUT_POS), Instr::Const(input.len() as i32), Instr::LtU,
+                            Instr::If(BlockType::I32),
+                            Instr::LocalGet(INPUT_POS), Instr::Load(CellWidth::U8, base),
+                            Instr::LocalGet(INPUT_POS), Instr::Const(1), Instr::Add, Instr::LocalSet(INPUT_POS),
+                            Instr::Else,
+                            Instr::Const(-1),
+                            Instr::End,
+                            Instr::LocalSet(CHAR)]);
+            },
+            None => self.emit(&[Instr::Call(GET), Instr::LocalSet(CHAR)]),
+        }
+    }
+
+    fn compile(&mut self) -> Result<(), &'static str> {
+        let prog = self.prog;
+        let ops = &prog.ops;
+        let width = self.config.cell_width;
+        let cell = self.cell();
+        //LoopEnter indices of the open blocks
+        let mut open: Vec<usize> = vec![];
+
+        for (ip, op) in ops.iter().enumerate() {
+            match *op {
+                Opcode::Ptr(x) => {
+                    //a pointer below zero wraps to a huge address, one unsigned compare catches both ends
+                    let step = x.wrapping_mul(width_bytes(width) as i32);
+                    self.emit(&[Instr::LocalGet(PTR), Instr::Const(step), Instr::Add, Instr::LocalTee(PTR),
+                                Instr::Const(self.tape_bytes as i32), Instr::GeU,
+                                Instr::If(BlockType::Empty), Instr::Unreachable, Instr::End]);
+                },
+                Opcode::Byte(x) => {
+                    self.emit(&[Instr::LocalGet(PTR), Instr::LocalGet(PTR), cell, Instr::Const(x), Instr::Add,
+                                Instr::Store(width, 0)]);
+                },
+                Opcode::LoopEnter(x) => {
+                    if ops.get(x) != Some(&Opcode::LoopExit(ip)) {
+                        return Err("Unmatched loop");
+                    }
+                    open.push(ip);
+                    self.emit(&[Instr::Block(BlockType::Empty),
+                                Instr::LocalGet(PTR), cell, Instr::Eqz, Instr::BrIf(0),
+                                Instr::Loop(BlockType::Empty)]);
+                },
+                Opcode::LoopExit(x) => {
+                    if open.pop() != Some(x) {
+                        return Err("Unmatched loop");
+                    }
+                    self.emit(&[Instr::LocalGet(PTR), cell, Instr::BrIf(0), Instr::End, Instr::End]);
+                },
+                Opcode::Out => {
+                    self.emit(&[Instr::LocalGet(PTR), cell, Instr::Call(PUT)]);
+                },
+                Opcode::In => {
+                    self.read();
+                    self.emit(&[Instr::LocalGet(CHAR), Instr::Const(0), Instr::LtS, Instr::If(BlockType::Empty)]);
+                    match self.config.eof {
+                        EofPolicy::Zero      => self.emit(&[Instr::LocalGet(PTR), Instr::Const(0), Instr::Store(width, 0)]),
+                        EofPolicy::MinusOne  => self.emit(&[Instr::LocalGet(PTR), Instr::Const(width.mask() as i32), Instr::Store(width, 0)]),
+                        EofPolicy::Unchanged => {},
+                    }
+                    self.emit(&[Instr::Else, Instr::LocalGet(PTR), Instr::LocalGet(CHAR), Instr::Store(width, 0), Instr::End]);
+                },
+                //there is nothing to dump to, like in the native backends
+                Opcode::Debug => {},
+            }
+        }
+        if !open.is_empty() {
+            return Err("Unmatched loop");
+        }
+
+        self.emit(&[Instr::LocalGet(PTR), Instr::Const(width_shift(width) as i32), Instr::ShrU, Instr::End]);
+        Ok(())
+    }
+}
+
+fn section(module: &mut Vec<u8>, id: u8, contents: &[u8]) {
+    module.push(id);
+    write_uleb(module, contents.len() as u32);
+    module.extend_from_slice(contents);
+}
+
+fn func_type(out: &mut Vec<u8>, params: &[u8], results: &[u8]) {
+    out.push(0x60);
+    write_uleb(out, params.len() as u32);
+    out.extend_from_slice(params);
+    write_uleb(out, results.len() as u32);
+    out.extend_from_slice(results);
+}
+
+pub fn compile(prog: &Program, config: &Config) -> Result<Vec<u8>, &'static str> {
+    let tape_bytes = config.tape_size * width_bytes(config.cell_width) as usize;
+    let input_len = prog.input.as_ref().map_or(0, |i| i.len());
+    if tape_bytes + input_len > i32::max_value() as usize {
+        return Err("Tape too large");
+    }
+
+    let mut c = Compiler {prog: prog, config: config, body: vec![], tape_bytes: tape_bytes as u32};
+    c.compile()?;
+
+    let mut module = vec![];
+    module.extend_from_slice(MAGIC);
+    module.extend_from_slice(VERSION);
+
+    let mut types = vec![];
+    //put, then get and run
+    write_uleb(&mut types, 2);
+    func_type(&mut types, &[I32], &[]);
+    func_type(&mut types, &[], &[I32]);
+    section(&mut module, 1, &types);
+
+    let mut imports = vec![];
+    write_uleb(&mut imports, 2);
+    for &(name, ty) in &[("put", 0), ("get", 1)] {
+        write_name(&mut imports, "env");
+        write_name(&mut imports, name);
+        imports.push(0x00);
+        write_uleb(&mut imports, ty);
+    }
+    section(&mut module, 2, &imports);
+
+    let mut funcs = vec![];
+    write_uleb(&mut funcs, 1);
+    write_uleb(&mut funcs, 1);
+    section(&mut module, 3, &funcs);
+
+    let pages = (tape_bytes + input_len + PAGE_SIZE - 1) / PAGE_SIZE;
+    let mut memory = vec![];
+    write_uleb(&mut memory, 1);
+    memory.push(0x00);
+    write_uleb(&mut memory, pages.max(1) as u32);
+    section(&mut module, 5, &memory);
+
+    let mut exports = vec![];
+    write_uleb(&mut exports, 2);
+    write_name(&mut exports, "run");
+    exports.push(0x00);
+    write_uleb(&mut exports, RUN);
+    write_name(&mut exports, "memory");
+    exports.push(0x02);
+    write_uleb(&mut exports, 0);
+    section(&mut module, 7, &exports);
+
+    let mut body = vec![];
+    write_uleb(&mut body, 1);
+    write_uleb(&mut body, 3);
+    body.push(I32);
+    for instr in &c.body {
+        instr.encode(&mut body);
+    }
+    let mut code = vec![];
+    write_uleb(&mut code, 1);
+    write_uleb(&mut code, body.len() as u32);
+    code.extend_from_slice(&body);
+    section(&mut module, 10, &code);
+
+    if let Some(ref input) = prog.input {
+        let mut data = vec![];
+        write_uleb(&mut data, 1);
+        data.push(0x00);
+        Instr::Const(tape_bytes as i32).encode(&mut data);
+        Instr::End.encode(&mut data);
+        write_uleb(&mut data, input.len() as u32);
+        data.extend_from_slice(input);
+        section(&mut module, 11, &data);
+    }
+    Ok(module)
+}
+
+pub fn write_wasm<P: AsRef<Path>>(prog: &Program, config: &Config, path: P) -> io::Result<()> {
+    let module = match compile(prog, config) {
+        Ok(module) => module,
+        Err(err) => return Err(io::Error::new(io::ErrorKind::Other, err)),
+    };
+    let mut f = File::create(path)?;
+    f.write_all(&module)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use bf::Program;
+    use exec::{CellWidth, Config, Dialect, EofPolicy};
+    use super::{compile, to_wat, validate};
+
+    #[test]
+    fn compiles_valid_modules() {
+        let sources = ["++++++++[>++++[>++>+++<<-]>+<<-]>>.>+.", "+[[->>+<<]>[>]<<-[<]+>+]", ",[.,]",
+                       "<+>", "+[>+]", ",.,.!hi"];
+        let mut dialect = Dialect::new();
+        dialect.input_separator = true;
+        for source in sources.iter() {
+            let prog = Program::parse_dialect(source, dialect).unwrap();
+            for &width in &[CellWidth::U8, CellWidth::U16, CellWidth::U32] {
+                for &eof in &[EofPolicy::Zero, EofPolicy::MinusOne, EofPolicy::Unchanged] {
+                    let mut config = Config::new();
+                    config.cell_width = width;
+                    config.eof = eof;
+                    config.tape_size = 16;
+                    let module = compile(&prog, &config).unwrap();
+                    if let Err(err) = validate(&module) {
+                        panic!("{} with {:?} cells and {:?} at EOF: {}", source, width, eof, err);
+                    }
+                }
+            }
+        }
+    }
+
+    //`+[-<],.` on a four cell tape
+    const WAT: &'static str = "\
+(module
+  (type (;0;) (func (param i32)))
+  (type (;1;) (func (result i32)))
+  (import \"env\" \"put\" (func (;0;) (type 0)))
+  (import \"env\" \"get\" (func (;1;) (type 1)))
+  (func (;2;) (type 1) (result i32)
+    (local i32 i32 i32)
+    local.get 0
+    local.get 0
+    i32.load
+    i32.const 1
+    i32.add
+    i32.store
+    block
+      local.get 0
+      i32.load
+      i32.eqz
+      br_if 0
+      loop
+        local.get 0
+        local.get 0
+        i32.load
+        i32.const -1
+        i32.add
+        i32.store
+        local.get 0
+        i32.const -4
+        i32.add
+        local.tee 0
+        i32.const 16
+        i32.ge_u
+        if
+          unreachable
+        end
+        local.get 0
+        i32.load
+        br_if 0
+      end
+    end
+    call 1
+    local.set 1
+    local.get 1
+    i32.const 0
+    i32.lt_s
+    if
+      local.get 0
+      i32.const 0
+      i32.store
+    else
+      local.get 0
+      local.get 1
+      i32.store
+    end
+    local.get 0
+    i32.load
+    call 0
+    local.get 0
+    i32.const 2
+    i32.shr_u
+  )
+  (memory (;0;) 1)
+  (export \"run\" (func 2))
+  (export \"memory\" (memory 0))
+)
+";
+
+    #[test]
+    fn prints_the_text_format() {
+        let mut config = Config::new();
+        config.tape_size = 4;
+        let module = compile(&Program::parse("+[-<],.").unwrap(), &config).unwrap();
+        assert_eq!(to_wat(&module).unwrap(), WAT);
+    }
+}
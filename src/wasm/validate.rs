@@ -0,0 +1,558 @@
+use std::str;
+
+use exec::CellWidth;
+
+use super::{BlockType, Instr, I32, MAGIC, PAGE_SIZE, VERSION};
+
+
+const MAX_LOCALS: usize = 50000;
+const MAX_PAGES: u32 = 65536;
+
+//A decoded module, limited to the sections and instructions the backend
+//writes. Everything else is rejected instead of being skipped.
+#[derive(Debug, Clone)]
+pub struct Module {
+    //(params, results) of every function type
+    pub types: Vec<(Vec<u8>, Vec<u8>)>,
+    //(module, name, type index) of the imported functions
+    pub imports: Vec<(String, String, u32)>,
+    //type index of every defined function
+    pub funcs: Vec<u32>,
+    //initial and maximum pages
+    pub memory: Option<(u32, Option<u32>)>,
+    //(name, kind, index)
+    pub exports: Vec<(String, u8, u32)>,
+    //local types and body of every defined function
+    pub code: Vec<(Vec<u8>, Vec<Instr>)>,
+    //(offset, bytes) of the active data segments
+    pub data: Vec<(u32, Vec<u8>)>,
+}
+
+impl Module {
+    //Type of a function by index, imports first
+    pub fn func_type(&self, f: u32) -> Option<&(Vec<u8>, Vec<u8>)> {
+        let f = f as usize;
+        let ty = if f < self.imports.len() {
+            self.imports[f].2
+        }else{
+            *self.funcs.get(f - self.imports.len())?
+        };
+        self.types.get(ty as usize)
+    }
+}
+
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn err<T>(&self, msg: &str) -> Result<T, String> {
+        Err(format!("{} at byte {}", msg, self.pos))
+    }
+
+    fn done(&self) -> bool {
+        self.pos == self.bytes.len()
+    }
+
+    fn byte(&mut self) -> Result<u8, String> {
+        match self.bytes.get(self.pos) {
+            Some(&b) => { self.pos += 1; Ok(b) },
+            None => self.err("Unexpected end"),
+        }
+    }
+
+    fn bytes(&mut self, n: usize) -> Result<&'a [u8], String> {
+        if self.bytes.len() - self.pos < n {
+            return self.err("Unexpected end");
+        }
+        self.pos += n;
+        Ok(&self.bytes[self.pos - n..self.pos])
+    }
+
+    fn uleb(&mut self) -> Result<u32, String> {
+        let mut x = 0u64;
+        let mut shift = 0;
+        loop {
+            let b = self.byte()?;
+            x |= ((b & 0x7f) as u64) << shift;
+            if b & 0x80 == 0 {
+                break;
+            }
+            shift += 7;
+            if shift >= 35 {
+                return self.err("LEB128 too long");
+            }
+        }
+        if x > u32::max_value() as u64 {
+            return self.err("LEB128 out of range");
+        }
+        Ok(x as u32)
+    }
+
+    fn sleb(&mut self) -> Result<i32, String> {
+        let mut x = 0i64;
+        let mut shift = 0;
+        loop {
+            let b = self.byte()?;
+            x |= ((b & 0x7f) as i64) << shift;
+            shift += 7;
+            if b & 0x80 == 0 {
+                if b & 0x40 != 0 {
+                    x |= -1i64 << shift;
+                }
+                break;
+            }
+            if shift >= 35 {
+                return self.err("LEB128 too long");
+            }
+        }
+        if x < i32::min_value() as i64 || x > i32::max_value() as i64 {
+            return self.err("LEB128 out of range");
+        }
+        Ok(x as i32)
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        let len = self.uleb()? as usize;
+        let bytes = self.bytes(len)?;
+        match str::from_utf8(bytes) {
+            Ok(s) => Ok(s.to_string()),
+            Err(_) => self.err("Name is not UTF-8"),
+        }
+    }
+
+    fn valtype(&mut self) -> Result<u8, String> {
+        match self.byte()? {
+            t @ 0x7c..=0x7f => Ok(t),
+            _ => self.err("Invalid value type"),
+        }
+    }
+
+    fn valtypes(&mut self) -> Result<Vec<u8>, String> {
+        let n = self.uleb()?;
+        (0..n).map(|_| self.valtype()).collect()
+    }
+
+    fn blocktype(&mut self) -> Result<BlockType, String> {
+        match self.byte()? {
+            0x40 => Ok(BlockType::Empty),
+            I32 => Ok(BlockType::I32),
+            _ => self.err("Unsupported block type"),
+        }
+    }
+
+    //Alignment and offset, the alignment can't be over the natural one
+    fn memarg(&mut self, width: CellWidth) -> Result<(CellWidth, u32), String> {
+        let natural = match width {
+            CellWidth::U8  => 0,
+            CellWidth::U16 => 1,
+            CellWidth::U32 => 2,
+        };
+        if self.uleb()? > natural {
+            return self.err("Alignment over the natural one");
+        }
+        Ok((width, self.uleb()?))
+    }
+
+    fn instr(&mut self) -> Result<Instr, String> {
+        let op = self.byte()?;
+        Ok(match op {
+            0x00 => Instr::Unreachable,
+            0x02 => Instr::Block(self.blocktype()?),
+            0x03 => Instr::Loop(self.blocktype()?),
+            0x04 => Instr::If(self.blocktype()?),
+            0x05 => Instr::Else,
+            0x0b => Instr::End,
+            0x0c => Instr::Br(self.uleb()?),
+            0x0d => Instr::BrIf(self.uleb()?),
+            0x10 => Instr::Call(self.uleb()?),
+            0x20 => Instr::LocalGet(self.uleb()?),
+            0x21 => Instr::LocalSet(self.uleb()?),
+            0x22 => Instr::LocalTee(self.uleb()?),
+            0x28 => { let (w, o) = self.memarg(CellWidth::U32)?; Instr::Load(w, o) },
+            0x2d => { let (w, o) = self.memarg(CellWidth::U8)?; Instr::Load(w, o) },
+            0x2f => { let (w, o) = self.memarg(CellWidth::U16)?; Instr::Load(w, o) },
+            0x36 => { let (w, o) = self.memarg(CellWidth::U32)?; Instr::Store(w, o) },
+            0x3a => { let (w, o) = self.memarg(CellWidth::U8)?; Instr::Store(w, o) },
+            0x3b => { let (w, o) = self.memarg(CellWidth::U16)?; Instr::Store(w, o) },
+            0x41 => Instr::Const(self.sleb()?),
+            0x45 => Instr::Eqz,
+            0x48 => Instr::LtS,
+            0x49 => Instr::LtU,
+            0x4f => Instr::GeU,
+            0x6a => Instr::Add,
+            0x6b => Instr::Sub,
+            0x76 => Instr::ShrU,
+            _ => {
+                self.pos -= 1;
+                return self.err(&format!("Unsupported opcode 0x{:02x}", op));
+            },
+        })
+    }
+
+    fn body(&mut self) -> Result<(Vec<u8>, Vec<Instr>), String> {
+        let size = self.uleb()? as usize;
+        let start = self.pos;
+        self.bytes(size)?;
+        let mut r = Reader {bytes: &self.bytes[..start + size], pos: start};
+
+        let mut locals = vec![];
+        for _ in 0..r.uleb()? {
+            let n = r.uleb()? as usize;
+            let t = r.valtype()?;
+            if locals.len() + n > MAX_LOCALS {
+                return r.err("Too many locals");
+            }
+            locals.extend(::std::iter::repeat(t).take(n));
+        }
+        let mut instrs = vec![];
+        while !r.done() {
+            instrs.push(r.instr()?);
+        }
+        if instrs.last() != Some(&Instr::End) {
+            return self.err("Function body without an end");
+        }
+        Ok((locals, instrs))
+    }
+}
+
+//Parses a binary module into its sections without checking they fit together
+pub fn decode(bytes: &[u8]) -> Result<Module, String> {
+    let mut r = Reader {bytes: bytes, pos: 0};
+    if r.bytes(4)? != MAGIC {
+        return r.err("Not a wasm module");
+    }
+    if r.bytes(4)? != VERSION {
+        return r.err("Unsupported version");
+    }
+
+    let mut m = Module {types: vec![], imports: vec![], funcs: vec![], memory: None, exports: vec![], code: vec![], data: vec![]};
+    let mut last_id = 0;
+    while !r.done() {
+        let id = r.byte()?;
+        let size = r.uleb()? as usize;
+        let start = r.pos;
+        let mut s = Reader {bytes: &bytes[..start + size.min(bytes.len() - start)], pos: start};
+        r.bytes(size)?;
+
+        //custom sections can go anywhere, the others in order and once
+        if id == 0 {
+            continue;
+        }
+        if id <= last_id {
+            return s.err("Section out of order");
+        }
+        last_id = id;
+
+        match id {
+            1 => {
+                for _ in 0..s.uleb()? {
+                    if s.byte()? != 0x60 {
+                        return s.err("Expected a function type");
+                    }
+                    let params = s.valtypes()?;
+                    let results = s.valtypes()?;
+                    m.types.push((params, results));
+                }
+            },
+            2 => {
+                for _ in 0..s.uleb()? {
+                    let module = s.name()?;
+                    let name = s.name()?;
+                    if s.byte()? != 0x00 {
+                        return s.err("Only function imports are supported");
+                    }
+                    let ty = s.uleb()?;
+                    m.imports.push((module, name, ty));
+                }
+            },
+            3 => {
+                for _ in 0..s.uleb()? {
+                    let ty = s.uleb()?;
+                    m.funcs.push(ty);
+                }
+            },
+            5 => {
+                let n = s.uleb()?;
+                if n > 1 {
+                    return s.err("More than one memory");
+                }
+                if n == 1 {
+                    m.memory = match s.byte()? {
+                        0x00 => Some((s.uleb()?, None)),
+                        0x01 => { let min = s.uleb()?; Some((min, Some(s.uleb()?))) },
+                        _ => return s.err("Invalid limits"),
+                    };
+                }
+            },
+            7 => {
+                for _ in 0..s.uleb()? {
+                    let name = s.name()?;
+                    let kind = s.byte()?;
+                    let index = s.uleb()?;
+                    m.exports.push((name, kind, index));
+                }
+            },
+            10 => {
+                for _ in 0..s.uleb()? {
+                    let body = s.body()?;
+                    m.code.push(body);
+                }
+            },
+            11 => {
+                for _ in 0..s.uleb()? {
+                    if s.uleb()? != 0 {
+                        return s.err("Only active segments of memory 0 are supported");
+                    }
+                    let offset = match (s.instr()?, s.instr()?) {
+                        (Instr::Const(x), Instr::End) => x as u32,
+                        _ => return s.err("Data offset must be a constant"),
+                    };
+                    let len = s.uleb()? as usize;
+                    let data = s.bytes(len)?.to_vec();
+                    m.data.push((offset, data));
+                }
+            },
+            _ => return s.err(&format!("Unsupported section {}", id)),
+        }
+        if s.pos != start + size {
+            return s.err("Section size mismatch");
+        }
+    }
+    Ok(m)
+}
+
+
+#[derive(Eq, PartialEq, Debug, Copy, Clone)]
+enum FrameKind {
+    Func,
+    Block,
+    Loop,
+    If,
+    Else,
+}
+
+struct Frame {
+    kind: FrameKind,
+    //what a branch to the frame takes, the results except for loops
+    label: Vec<u8>,
+    results: Vec<u8>,
+    height: usize,
+    unreachable: bool,
+}
+
+//Operand stack typing of one function body
+struct Checker {
+    stack: Vec<u8>,
+    frames: Vec<Frame>,
+}
+
+impl Checker {
+    fn push(&mut self, t: u8) {
+        self.stack.push(t);
+    }
+
+    fn pop(&mut self, expect: u8) -> Result<(), &'static str> {
+        let frame = self.frames.last().unwrap();
+        if self.stack.len() == frame.height {
+            //after a branch anything can be popped
+            return if frame.unreachable { Ok(()) } else { Err("Stack underflow") };
+        }
+        if self.stack.pop() != Some(expect) {
+            return Err("Type mismatch");
+        }
+        Ok(())
+    }
+
+    fn pop_all(&mut self, types: &[u8]) -> Result<(), &'static str> {
+        for &t in types.iter().rev() {
+            self.pop(t)?;
+        }
+        Ok(())
+    }
+
+    fn set_unreachable(&mut self) {
+        let frame = self.frames.last_mut().unwrap();
+        self.stack.truncate(frame.height);
+        frame.unreachable = true;
+    }
+
+    fn enter(&mut self, kind: FrameKind, bt: BlockType) {
+        let results = match bt {
+            BlockType::Empty => vec![],
+            BlockType::I32 => vec![I32],
+        };
+        let label = if kind == FrameKind::Loop { vec![] } else { results.clone() };
+        let height = self.stack.len();
+        self.frames.push(Frame {kind: kind, label: label, results: results, height: height, unreachable: false});
+    }
+
+    //Checks the frame's results are exactly what is left above it
+    fn leave(&mut self) -> Result<Frame, &'static str> {
+        let results = self.frames.last().unwrap().results.clone();
+        self.pop_all(&results)?;
+        if self.stack.len() != self.frames.last().unwrap().height {
+            return Err("Values left on the stack");
+        }
+        Ok(self.frames.pop().unwrap())
+    }
+
+    fn label(&self, depth: u32) -> Result<Vec<u8>, &'static str> {
+        let depth = depth as usize;
+        if depth >= self.frames.len() {
+            return Err("Branch depth out of range");
+        }
+        Ok(self.frames[self.frames.len() - 1 - depth].label.clone())
+    }
+
+    fn instr(&mut self, m: &Module, locals: &[u8], instr: Instr) -> Result<(), &'static str> {
+        match instr {
+            Instr::Unreachable => self.set_unreachable(),
+            Instr::Block(bt) => self.enter(FrameKind::Block, bt),
+            Instr::Loop(bt) => self.enter(FrameKind::Loop, bt),
+            Instr::If(bt) => {
+                self.pop(I32)?;
+                self.enter(FrameKind::If, bt);
+            },
+            Instr::Else => {
+                if self.frames.last().unwrap().kind != FrameKind::If {
+                    return Err("Else outside of an if");
+                }
+                let frame = self.leave()?;
+                self.frames.push(Frame {kind: FrameKind::Else, unreachable: false, ..frame});
+            },
+            Instr::End => {
+                let frame = self.leave()?;
+                if frame.kind == FrameKind::If && !frame.results.is_empty() {
+                    return Err("If with a result but no else");
+                }
+                if !self.frames.is_empty() {
+                    self.stack.extend_from_slice(&frame.results);
+                }
+            },
+            Instr::Br(depth) => {
+                let label = self.label(depth)?;
+                self.pop_all(&label)?;
+                self.set_unreachable();
+            },
+            Instr::BrIf(depth) => {
+                self.pop(I32)?;
+                let label = self.label(depth)?;
+                self.pop_all(&label)?;
+                self.stack.extend_from_slice(&label);
+            },
+            Instr::Call(f) => {
+                let (params, results) = match m.func_type(f) {
+                    Some(ty) => ty.clone(),
+                    None => return Err("Call of an unknown function"),
+                };
+                self.pop_all(&params)?;
+                self.stack.extend_from_slice(&results);
+            },
+            Instr::LocalGet(i) | Instr::LocalSet(i) | Instr::LocalTee(i) => {
+                let t = match locals.get(i as usize) {
+                    Some(&t) => t,
+                    None => return Err("Unknown local"),
+                };
+                if instr != Instr::LocalGet(i) {
+                    self.pop(t)?;
+                }
+                if instr != Instr::LocalSet(i) {
+                    self.push(t);
+                }
+            },
+            Instr::Load(..) | Instr::Store(..) if m.memory.is_none() => return Err("Memory access without a memory"),
+            Instr::Load(..) => {
+                self.pop(I32)?;
+                self.push(I32);
+            },
+            Instr::Store(..) => {
+                self.pop(I32)?;
+                self.pop(I32)?;
+            },
+            Instr::Const(_) => self.push(I32),
+            Instr::Eqz => {
+                self.pop(I32)?;
+                self.push(I32);
+            },
+            Instr::LtS | Instr::LtU | Instr::GeU | Instr::Add | Instr::Sub | Instr::ShrU => {
+                self.pop(I32)?;
+                self.pop(I32)?;
+                self.push(I32);
+            },
+        }
+        Ok(())
+    }
+}
+
+fn check_body(m: &Module, f: usize) -> Result<(), String> {
+    let &(ref params, ref results) = match m.types.get(m.funcs[f] as usize) {
+        Some(ty) => ty,
+        None => return Err("Unknown type".to_string()),
+    };
+    let (ref local_types, ref body) = m.code[f];
+    let mut locals = params.clone();
+    locals.extend_from_slice(local_types);
+
+    let mut c = Checker {stack: vec![], frames: vec![]};
+    c.frames.push(Frame {kind: FrameKind::Func, label: results.clone(), results: results.clone(), height: 0, unreachable: false});
+    for (i, &instr) in body.iter().enumerate() {
+        if c.frames.is_empty() {
+            return Err(format!("Instruction {} after the end of the body", i));
+        }
+        c.instr(m, &locals, instr).map_err(|err| format!("{} at instruction {} ({:?})", err, i, instr))?;
+    }
+    if !c.frames.is_empty() {
+        return Err("Unclosed block".to_string());
+    }
+    Ok(())
+}
+
+//Decodes a module and checks it is valid: indices in range, exports unique,
+//data inside the memory and every function body well typed
+pub fn validate(bytes: &[u8]) -> Result<Module, String> {
+    let m = decode(bytes)?;
+
+    for &(ref module, ref name, ty) in &m.imports {
+        if ty as usize >= m.types.len() {
+            return Err(format!("Import {}.{} has an unknown type", module, name));
+        }
+    }
+    if m.funcs.len() != m.code.len() {
+        return Err(format!("{} functions but {} bodies", m.funcs.len(), m.code.len()));
+    }
+    if let Some((min, max)) = m.memory {
+        if min > MAX_PAGES || max.map_or(false, |max| max < min || max > MAX_PAGES) {
+            return Err("Invalid memory limits".to_string());
+        }
+    }
+
+    let func_count = (m.imports.len() + m.funcs.len()) as u32;
+    for (i, &(ref name, kind, index)) in m.exports.iter().enumerate() {
+        if m.exports[..i].iter().any(|e| e.0 == *name) {
+            return Err(format!("Duplicate export {}", name));
+        }
+        let valid = match kind {
+            0x00 => index < func_count,
+            0x02 => index == 0 && m.memory.is_some(),
+            _ => false,
+        };
+        if !valid {
+            return Err(format!("Export {} refers to nothing", name));
+        }
+    }
+
+    for &(offset, ref data) in &m.data {
+        let size = m.memory.map_or(0, |mem| mem.0 as u64 * PAGE_SIZE as u64);
+        if offset as u64 + data.len() as u64 > size {
+            return Err(format!("Data segment at {} is outside the memory", offset));
+        }
+    }
+
+    for f in 0..m.funcs.len() {
+        check_body(&m, f).map_err(|err| format!("Function {}: {}", m.imports.len() + f, err))?;
+    }
+    Ok(m)
+}
@@ -0,0 +1,147 @@
+use std::fmt;
+
+use exec::CellWidth;
+
+use super::{decode, BlockType, Instr, I32};
+
+
+fn valtype(t: u8) -> &'static str {
+    match t {
+        I32  => "i32",
+        0x7e => "i64",
+        0x7d => "f32",
+        _    => "f64",
+    }
+}
+
+fn valtypes(ts: &[u8]) -> String {
+    ts.iter().map(|&t| valtype(t)).collect::<Vec<_>>().join(" ")
+}
+
+impl fmt::Display for BlockType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BlockType::Empty => Ok(()),
+            BlockType::I32   => write!(f, " (result i32)"),
+        }
+    }
+}
+
+impl fmt::Display for Instr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let offset = |o: u32| if o == 0 { String::new() } else { format!(" offset={}", o) };
+        match *self {
+            Instr::Unreachable => write!(f, "unreachable"),
+            Instr::Block(t)    => write!(f, "block{}", t),
+            Instr::Loop(t)     => write!(f, "loop{}", t),
+            Instr::If(t)       => write!(f, "if{}", t),
+            Instr::Else        => write!(f, "else"),
+            Instr::End         => write!(f, "end"),
+            Instr::Br(l)       => write!(f, "br {}", l),
+            Instr::BrIf(l)     => write!(f, "br_if {}", l),
+            Instr::Call(x)     => write!(f, "call {}", x),
+            Instr::LocalGet(i) => write!(f, "local.get {}", i),
+            Instr::LocalSet(i) => write!(f, "local.set {}", i),
+            Instr::LocalTee(i) => write!(f, "local.tee {}", i),
+            Instr::Load(CellWidth::U8, o)   => write!(f, "i32.load8_u{}", offset(o)),
+            Instr::Load(CellWidth::U16, o)  => write!(f, "i32.load16_u{}", offset(o)),
+            Instr::Load(CellWidth::U32, o)  => write!(f, "i32.load{}", offset(o)),
+            Instr::Store(CellWidth::U8, o)  => write!(f, "i32.store8{}", offset(o)),
+            Instr::Store(CellWidth::U16, o) => write!(f, "i32.store16{}", offset(o)),
+            Instr::Store(CellWidth::U32, o) => write!(f, "i32.store{}", offset(o)),
+            Instr::Const(x)    => write!(f, "i32.const {}", x),
+            Instr::Eqz         => write!(f, "i32.eqz"),
+            Instr::LtS         => write!(f, "i32.lt_s"),
+            Instr::LtU         => write!(f, "i32.lt_u"),
+            Instr::GeU         => write!(f, "i32.ge_u"),
+            Instr::Add         => write!(f, "i32.add"),
+            Instr::Sub         => write!(f, "i32.sub"),
+            Instr::ShrU        => write!(f, "i32.shr_u"),
+        }
+    }
+}
+
+fn string(bytes: &[u8]) -> String {
+    let mut s = String::new();
+    for &b in bytes {
+        if b >= 0x20 && b < 0x7f && b != b'"' && b != b'\\' {
+            s.push(b as char);
+        }else{
+            s.push_str(&format!("\\{:02x}", b));
+        }
+    }
+    s
+}
+
+//The text format of a binary module, with flat instructions indented by
+//nesting and numeric indices
+pub fn to_wat(bytes: &[u8]) -> Result<String, String> {
+    let m = decode(bytes)?;
+    let mut out = String::from("(module\n");
+
+    for (i, &(ref params, ref results)) in m.types.iter().enumerate() {
+        out.push_str(&format!("  (type (;{};) (func", i));
+        if !params.is_empty() {
+            out.push_str(&format!(" (param {})", valtypes(params)));
+        }
+        if !results.is_empty() {
+            out.push_str(&format!(" (result {})", valtypes(results)));
+        }
+        out.push_str("))\n");
+    }
+    for (i, &(ref module, ref name, ty)) in m.imports.iter().enumerate() {
+        out.push_str(&format!("  (import \"{}\" \"{}\" (func (;{};) (type {})))\n", module, name, i, ty));
+    }
+
+    for (f, &(ref locals, ref body)) in m.code.iter().enumerate() {
+        let index = m.imports.len() + f;
+        out.push_str(&format!("  (func (;{};) (type {})", index, m.funcs[f]));
+        if let Some(&(ref params, ref results)) = m.types.get(m.funcs[f] as usize) {
+            if !params.is_empty() {
+                out.push_str(&format!(" (param {})", valtypes(params)));
+            }
+            if !results.is_empty() {
+                out.push_str(&format!(" (result {})", valtypes(results)));
+            }
+        }
+        out.push('\n');
+        if !locals.is_empty() {
+            out.push_str(&format!("    (local {})\n", valtypes(locals)));
+        }
+
+        //the last end is the closing paren of the func
+        let mut depth = 2;
+        for instr in &body[..body.len() - 1] {
+            match *instr {
+                Instr::End => depth -= 1,
+                Instr::Else => depth -= 1,
+                _ => {},
+            }
+            for _ in 0..depth {
+                out.push_str("  ");
+            }
+            out.push_str(&format!("{}\n", instr));
+            match *instr {
+                Instr::Block(_) | Instr::Loop(_) | Instr::If(_) | Instr::Else => depth += 1,
+                _ => {},
+            }
+        }
+        out.push_str("  )\n");
+    }
+
+    if let Some((min, max)) = m.memory {
+        match max {
+            Some(max) => out.push_str(&format!("  (memory (;0;) {} {})\n", min, max)),
+            None => out.push_str(&format!("  (memory (;0;) {})\n", min)),
+        }
+    }
+    for &(ref name, kind, index) in &m.exports {
+        let kind = if kind == 0x02 { "memory" } else { "func" };
+        out.push_str(&format!("  (export \"{}\" ({} {}))\n", name, kind, index));
+    }
+    for (i, &(offset, ref data)) in m.data.iter().enumerate() {
+        out.push_str(&format!("  (data (;{};) (i32.const {}) \"{}\")\n", i, offset as i32, string(data)));
+    }
+    out.push_str(")\n");
+    Ok(out)
+}